@@ -8,9 +8,9 @@ pub fn parse_args(args: &[String]) -> (Vec<String>, HashMap<String, Option<Strin
     while i < args.len() {
         let arg: &String = &args[i];
         
-        if arg.starts_with("--") {
+        if let Some(long) = arg.strip_prefix("--") {
             // Long option
-            let option: String = arg[2..].to_string();
+            let option: String = long.to_string();
             // Don't accept any arguments past a blank long option
             if arg == "--"
             {
@@ -25,9 +25,9 @@ pub fn parse_args(args: &[String]) -> (Vec<String>, HashMap<String, Option<Strin
                 parsed_args.insert(option, None);
                 i += 1;
             }
-        } else if arg.starts_with('-') {
+        } else if let Some(short) = arg.strip_prefix('-') {
             // Short option
-            let options: Vec<char> = arg[1..].chars().collect();
+            let options: Vec<char> = short.chars().collect();
             for (j, opt) in options.iter().enumerate() {
                 let option: String = opt.to_string();
                 if j == options.len() - 1 && i + 1 < args.len() && !args[i + 1].starts_with('-') && value_args.contains(&args[i + 1]) {
@@ -55,7 +55,7 @@ pub fn parse_arg_vec(args: &Vec<String>) -> (Vec<String>, HashMap<String, Option
 {
     let arg_array: &[String] = args.as_slice();
     
-    return parse_args(arg_array);
+    parse_args(arg_array)
 }
 
 pub fn parse_summon_args(args: &[String]) -> (Vec<String>, HashMap<String, Option<String>>) {
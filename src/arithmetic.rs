@@ -0,0 +1,599 @@
+// Integer arithmetic for $(( )) and (( )), with C operators and precedence as in bash.
+// Expressions are parsed into a small tree first so that &&, || and ?: only evaluate the branch they need.
+
+use crate::globals::ShellState;
+
+/// How deeply variables may refer to other expressions before we give up.
+const MAX_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+enum ArithToken {
+    Number(i64),
+    Name(Lvalue),
+    Op(&'static str),
+}
+
+/// A variable, or an array element with name[subscript]. The subscript is kept as text until evaluation,
+/// since it is an expression for an indexed array but a key for an associative one.
+#[derive(Debug, Clone, PartialEq)]
+struct Lvalue {
+    name: String,
+    subscript: Option<String>,
+}
+
+impl std::fmt::Display for Lvalue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.subscript {
+            Some(subscript) => write!(f, "{}[{}]", self.name, subscript),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// Longest operators first so that e.g. "<<=" is not read as "<" "<=".
+const OPERATORS: [&str; 39] = [
+    "<<=", ">>=",
+    "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "+=", "-=", "*=", "/=", "%=", "&=", "^=", "|=",
+    "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&", "^", "|", "?", ":", ",", "(", ")",
+];
+
+const ASSIGNMENT_OPERATORS: [&str; 11] = ["=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|="];
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i64),
+    Variable(Lvalue),
+    Unary(&'static str, Box<Expr>),
+    /// ++x and --x, holding the amount added.
+    PreIncrement(Lvalue, i64),
+    /// x++ and x--
+    PostIncrement(Lvalue, i64),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    /// name op= value, with op empty for plain '='.
+    Assign(Lvalue, &'static str, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+/// Evaluates an already expanded arithmetic expression. An empty expression is 0.
+pub fn evaluate(state: &mut ShellState, expression: &str) -> Result<i64, String> {
+    let mut evaluator: Evaluator<'_> = Evaluator { state, depth: 0 };
+    evaluator.evaluate_text(expression)
+}
+
+fn tokenize(expression: &str) -> Result<Vec<ArithToken>, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens: Vec<ArithToken> = Vec::new();
+    let mut pos: usize = 0;
+
+    'outer: while pos < chars.len() {
+        let c: char = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+            continue;
+        }
+        if c.is_ascii_digit() {
+            let start: usize = pos;
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || matches!(chars[pos], '#' | '@' | '_')) {
+                pos += 1;
+            }
+            let literal: String = chars[start..pos].iter().collect();
+            tokens.push(ArithToken::Number(parse_number(&literal)?));
+            continue;
+        }
+        if c.is_alphabetic() || c == '_' {
+            let start: usize = pos;
+            while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            let name: String = chars[start..pos].iter().collect();
+            let mut subscript: Option<String> = None;
+            if chars.get(pos) == Some(&'[') {
+                // The subscript runs to the matching ']', and may itself contain elements
+                let open: usize = pos;
+                let mut depth: usize = 0;
+                while pos < chars.len() {
+                    match chars[pos] {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        _ => {}
+                    }
+                    pos += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                if depth != 0 {
+                    let rest: String = chars[start..].iter().collect();
+                    return Err(format!("syntax error: missing ']' (error token is \"{}\")", rest));
+                }
+                subscript = Some(chars[open + 1..pos - 1].iter().collect());
+            }
+            tokens.push(ArithToken::Name(Lvalue { name, subscript }));
+            continue;
+        }
+        for op in OPERATORS {
+            let len: usize = op.len();
+            if pos + len <= chars.len() && chars[pos..pos + len].iter().copied().eq(op.chars()) {
+                tokens.push(ArithToken::Op(op));
+                pos += len;
+                continue 'outer;
+            }
+        }
+        let rest: String = chars[pos..].iter().collect();
+        return Err(format!("syntax error: invalid arithmetic operator (error token is \"{}\")", rest));
+    }
+
+    Ok(tokens)
+}
+
+/// Parses decimal, 0x hex, leading-zero octal and base#digits literals.
+fn parse_number(literal: &str) -> Result<i64, String> {
+    let (base, digits): (u32, &str) = if let Some((base, digits)) = literal.split_once('#') {
+        match base.parse::<u32>() {
+            Ok(base) if (2..=64).contains(&base) => (base, digits),
+            _ => return Err(format!("invalid arithmetic base (error token is \"{}\")", literal)),
+        }
+    } else if let Some(hex) = literal.strip_prefix("0x").or_else(|| literal.strip_prefix("0X")) {
+        (16, hex)
+    } else if literal.len() > 1 && literal.starts_with('0') {
+        (8, &literal[1..])
+    } else {
+        (10, literal)
+    };
+
+    if digits.is_empty() {
+        return Err(format!("invalid number (error token is \"{}\")", literal));
+    }
+
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit: u32 = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            // Up to base 36 letters are case-insensitive; above that upper case continues after z.
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => u32::MAX,
+        };
+        if digit >= base {
+            return Err(format!("value too great for base (error token is \"{}\")", literal));
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+fn binary_precedence(op: &str) -> Option<(u8, bool)> {
+    let precedence: (u8, bool) = match op {
+        "," => (1, false),
+        "||" => (3, false),
+        "&&" => (4, false),
+        "|" => (5, false),
+        "^" => (6, false),
+        "&" => (7, false),
+        "==" | "!=" => (8, false),
+        "<" | ">" | "<=" | ">=" => (9, false),
+        "<<" | ">>" => (10, false),
+        "+" | "-" => (11, false),
+        "*" | "/" | "%" => (12, false),
+        "**" => (13, true),
+        _ => return None,
+    };
+    Some(precedence)
+}
+
+struct ArithParser {
+    tokens: Vec<ArithToken>,
+    pos: usize,
+}
+
+impl ArithParser {
+    fn peek(&self) -> Option<&ArithToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(ArithToken::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", op)))
+        }
+    }
+
+    fn error(&self, message: &str) -> String {
+        let token: String = match self.peek() {
+            Some(ArithToken::Number(n)) => n.to_string(),
+            Some(ArithToken::Name(lvalue)) => lvalue.to_string(),
+            Some(ArithToken::Op(op)) => op.to_string(),
+            None => String::new(),
+        };
+        format!("syntax error: {} (error token is \"{}\")", message, token)
+    }
+
+    fn parse_comma(&mut self) -> Result<Expr, String> {
+        let mut expr: Expr = self.parse_assignment()?;
+        while self.peek_op() == Some(",") {
+            self.pos += 1;
+            let rhs: Expr = self.parse_assignment()?;
+            expr = Expr::Binary(",", Box::new(expr), Box::new(rhs));
+        }
+        Ok(expr)
+    }
+
+    fn parse_assignment(&mut self) -> Result<Expr, String> {
+        if let (Some(ArithToken::Name(lvalue)), Some(ArithToken::Op(op))) = (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            if ASSIGNMENT_OPERATORS.contains(op) {
+                let lvalue: Lvalue = lvalue.clone();
+                let op: &'static str = op;
+                self.pos += 2;
+                let value: Expr = self.parse_assignment()?;
+                return Ok(Expr::Assign(lvalue, op.trim_end_matches('='), Box::new(value)));
+            }
+        }
+        self.parse_conditional()
+    }
+
+    fn parse_conditional(&mut self) -> Result<Expr, String> {
+        let condition: Expr = self.parse_binary(3)?;
+        if self.peek_op() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let then: Expr = self.parse_comma()?;
+        self.expect(":")?;
+        let otherwise: Expr = self.parse_assignment()?;
+        Ok(Expr::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)))
+    }
+
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs: Expr = self.parse_unary()?;
+        while let Some(op) = self.peek_op() {
+            let (precedence, right_assoc): (u8, bool) = match binary_precedence(op) {
+                Some(p) if p.0 >= min_precedence && op != "," => p,
+                _ => break,
+            };
+            self.pos += 1;
+            let next: u8 = if right_assoc { precedence } else { precedence + 1 };
+            let rhs: Expr = self.parse_binary(next)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.peek_op() {
+            Some(op @ ("!" | "~" | "-" | "+")) => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+            }
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                match self.peek() {
+                    Some(ArithToken::Name(lvalue)) => {
+                        let lvalue: Lvalue = lvalue.clone();
+                        self.pos += 1;
+                        Ok(Expr::PreIncrement(lvalue, if op == "++" { 1 } else { -1 }))
+                    }
+                    _ => Err(self.error("identifier expected after pre-increment or pre-decrement")),
+                }
+            }
+            _ => self.parse_postfix(),
+        }
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, String> {
+        let token: ArithToken = match self.peek() {
+            Some(token) => token.clone(),
+            None => return Err(self.error("operand expected")),
+        };
+        self.pos += 1;
+        match token {
+            ArithToken::Number(n) => Ok(Expr::Number(n)),
+            ArithToken::Name(lvalue) => match self.peek_op() {
+                Some(op @ ("++" | "--")) => {
+                    self.pos += 1;
+                    Ok(Expr::PostIncrement(lvalue, if op == "++" { 1 } else { -1 }))
+                }
+                _ => Ok(Expr::Variable(lvalue)),
+            },
+            ArithToken::Op("(") => {
+                let expr: Expr = self.parse_comma()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            ArithToken::Op(_) => {
+                self.pos -= 1;
+                Err(self.error("operand expected"))
+            }
+        }
+    }
+}
+
+struct Evaluator<'a> {
+    state: &'a mut ShellState,
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    fn evaluate_text(&mut self, expression: &str) -> Result<i64, String> {
+        let tokens: Vec<ArithToken> = tokenize(expression)?;
+        if tokens.is_empty() {
+            return Ok(0);
+        }
+        let mut parser: ArithParser = ArithParser { tokens, pos: 0 };
+        let expr: Expr = parser.parse_comma()?;
+        if parser.peek().is_some() {
+            return Err(parser.error("syntax error in expression"));
+        }
+        self.eval(&expr)
+    }
+
+    /// The key of an element: an associative array's subscript as it is, an indexed array's evaluated,
+    /// with negative indices counting back from the end. None for a plain variable.
+    fn key(&mut self, lvalue: &Lvalue) -> Result<Option<String>, String> {
+        let subscript: &str = match &lvalue.subscript {
+            Some(subscript) => subscript,
+            None => return Ok(None),
+        };
+        if self.state.is_associative(&lvalue.name) {
+            return Ok(Some(subscript.to_owned()));
+        }
+        let mut index: i64 = self.evaluate_text(subscript)?;
+        if index < 0 {
+            index += self.state.next_index(&lvalue.name) as i64;
+        }
+        if index < 0 {
+            return Err(format!("{}: bad array subscript", lvalue.name));
+        }
+        Ok(Some(index.to_string()))
+    }
+
+    /// A variable's or element's value, itself evaluated as an expression. Unset and empty ones are 0.
+    fn variable(&mut self, lvalue: &Lvalue) -> Result<i64, String> {
+        let value: Option<String> = match self.key(lvalue)? {
+            Some(key) => self.state.get_element(&lvalue.name, &key),
+            None => self.state.get_var(&lvalue.name),
+        };
+        let value: String = match value {
+            Some(value) if !value.trim().is_empty() => value,
+            _ => return Ok(0),
+        };
+        if let Ok(n) = value.trim().parse::<i64>() {
+            return Ok(n);
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(format!("{}: expression recursion level exceeded", lvalue));
+        }
+        self.depth += 1;
+        let result: Result<i64, String> = self.evaluate_text(&value);
+        self.depth -= 1;
+        result
+    }
+
+    fn assign(&mut self, lvalue: &Lvalue, value: i64) -> Result<(), String> {
+        match self.key(lvalue)? {
+            Some(key) => self.state.set_element(&lvalue.name, &key, &value.to_string()),
+            None => self.state.set_var(&lvalue.name, &value.to_string()),
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<i64, String> {
+        match expr {
+            Expr::Number(n) => Ok(*n),
+            Expr::Variable(name) => self.variable(name),
+            Expr::Unary(op, operand) => {
+                let value: i64 = self.eval(operand)?;
+                Ok(match *op {
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    "-" => value.wrapping_neg(),
+                    _ => value,
+                })
+            }
+            Expr::PreIncrement(name, delta) => {
+                let value: i64 = self.variable(name)?.wrapping_add(*delta);
+                self.assign(name, value)?;
+                Ok(value)
+            }
+            Expr::PostIncrement(name, delta) => {
+                let value: i64 = self.variable(name)?;
+                self.assign(name, value.wrapping_add(*delta))?;
+                Ok(value)
+            }
+            Expr::Binary("&&", lhs, rhs) => Ok((self.eval(lhs)? != 0 && self.eval(rhs)? != 0) as i64),
+            Expr::Binary("||", lhs, rhs) => Ok((self.eval(lhs)? != 0 || self.eval(rhs)? != 0) as i64),
+            Expr::Binary(",", lhs, rhs) => {
+                self.eval(lhs)?;
+                self.eval(rhs)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs: i64 = self.eval(lhs)?;
+                let rhs: i64 = self.eval(rhs)?;
+                apply_binary(op, lhs, rhs)
+            }
+            Expr::Assign(name, op, value) => {
+                let mut value: i64 = self.eval(value)?;
+                if !op.is_empty() {
+                    let current: i64 = self.variable(name)?;
+                    value = apply_binary(op, current, value)?;
+                }
+                self.assign(name, value)?;
+                Ok(value)
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if self.eval(condition)? != 0 {
+                    self.eval(then)
+                } else {
+                    self.eval(otherwise)
+                }
+            }
+        }
+    }
+}
+
+fn apply_binary(op: &str, lhs: i64, rhs: i64) -> Result<i64, String> {
+    let value: i64 = match op {
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" | "%" if rhs == 0 => return Err("division by 0".to_owned()),
+        "/" => lhs.wrapping_div(rhs),
+        "%" => lhs.wrapping_rem(rhs),
+        "**" => {
+            if rhs < 0 {
+                return Err("exponent less than 0".to_owned());
+            }
+            let (mut result, mut base, mut exponent): (i64, i64, i64) = (1, lhs, rhs);
+            while exponent > 0 {
+                if exponent & 1 == 1 {
+                    result = result.wrapping_mul(base);
+                }
+                base = base.wrapping_mul(base);
+                exponent >>= 1;
+            }
+            result
+        }
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "&" => lhs & rhs,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "<" => (lhs < rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        _ => return Err(format!("syntax error: unknown operator '{}'", op)),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> Result<i64, String> {
+        evaluate(&mut ShellState::default(), expression)
+    }
+
+    #[test]
+    fn precedence_follows_c() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(eval("-2 ** 2"), Ok(4));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("6 | 1 ^ 3 & 2"), Ok(7));
+        assert_eq!(eval("1 < 2 == 1"), Ok(1));
+        assert_eq!(eval("!0 + ~0"), Ok(0));
+        assert_eq!(eval("7 % 3 * 2"), Ok(2));
+        assert_eq!(eval("1, 2, 3"), Ok(3));
+        assert_eq!(eval(""), Ok(0));
+    }
+
+    #[test]
+    fn conditional_and_logic_short_circuit() {
+        let mut state: ShellState = ShellState::default();
+        assert_eq!(evaluate(&mut state, "0 && (x = 1)"), Ok(0));
+        assert_eq!(evaluate(&mut state, "1 || (x = 1)"), Ok(1));
+        assert_eq!(evaluate(&mut state, "1 ? 2 : (x = 1)"), Ok(2));
+        assert_eq!(state.get_var("x"), None);
+        assert_eq!(evaluate(&mut state, "0 ? 2 : 1 ? 3 : 4"), Ok(3));
+        assert_eq!(evaluate(&mut state, "2 && 3"), Ok(1));
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(eval("16#ff"), Ok(255));
+        assert_eq!(eval("2#1010"), Ok(10));
+        assert_eq!(eval("36#Z"), Ok(35));
+        assert_eq!(eval("64#_"), Ok(63));
+        assert_eq!(eval("0x1F"), Ok(31));
+        assert_eq!(eval("017"), Ok(15));
+        assert!(eval("08").is_err());
+        assert!(eval("2#12").is_err());
+        assert!(eval("65#1").is_err());
+    }
+
+    #[test]
+    fn assignment_and_increments() {
+        let mut state: ShellState = ShellState::default();
+        assert_eq!(evaluate(&mut state, "x = 5"), Ok(5));
+        assert_eq!(evaluate(&mut state, "x += 3"), Ok(8));
+        assert_eq!(evaluate(&mut state, "x <<= 1"), Ok(16));
+        assert_eq!(evaluate(&mut state, "x++"), Ok(16));
+        assert_eq!(evaluate(&mut state, "++x"), Ok(18));
+        assert_eq!(evaluate(&mut state, "x--, --x"), Ok(16));
+        assert_eq!(evaluate(&mut state, "y = z = 2"), Ok(2));
+        assert_eq!(state.get_var("x").as_deref(), Some("16"));
+        assert_eq!(state.get_var("z").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn variables_hold_expressions() {
+        let mut state: ShellState = ShellState::default();
+        state.set_var("a", "3").unwrap();
+        state.set_var("b", "a * 2").unwrap();
+        state.set_var("empty", "").unwrap();
+        assert_eq!(evaluate(&mut state, "b + 1"), Ok(7));
+        assert_eq!(evaluate(&mut state, "unset + empty"), Ok(0));
+    }
+
+    #[test]
+    fn array_elements() {
+        let mut state: ShellState = ShellState::default();
+        state.set_element("a", "0", "5").unwrap();
+        state.set_element("a", "1", "i + 1").unwrap();
+        state.set_value("m", crate::globals::Value::Associative(Default::default())).unwrap();
+        state.set_var("i", "1").unwrap();
+        assert_eq!(evaluate(&mut state, "a[0] * 2"), Ok(10));
+        assert_eq!(evaluate(&mut state, "a[i]"), Ok(2));
+        assert_eq!(evaluate(&mut state, "a[a[0] - 4]"), Ok(2));
+        assert_eq!(evaluate(&mut state, "a[-1] + a[9]"), Ok(2));
+        assert_eq!(evaluate(&mut state, "a[2] = 7, a[2]++, a[2]"), Ok(8));
+        assert_eq!(evaluate(&mut state, "m[x y] += 3"), Ok(3));
+        assert_eq!(state.get_element("m", "x y").as_deref(), Some("3"));
+        assert_eq!(evaluate(&mut state, "a[-9]"), Err("a: bad array subscript".to_owned()));
+        assert!(evaluate(&mut state, "a[1").is_err());
+    }
+
+    #[test]
+    fn self_reference_hits_the_recursion_limit() {
+        // Each nested evaluation needs more stack than a test thread gets by default.
+        let result: Result<i64, String> = std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(|| {
+                let mut state: ShellState = ShellState::default();
+                state.set_var("loop", "loop").unwrap();
+                evaluate(&mut state, "loop")
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(result, Err("loop: expression recursion level exceeded".to_owned()));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval("1 / 0"), Err("division by 0".to_owned()));
+        assert!(eval("2 ** -1").is_err());
+        assert!(eval("1 +").is_err());
+        assert!(eval("(1").is_err());
+        assert!(eval("1 2").is_err());
+        assert!(eval("1 $ 2").is_err());
+        assert!(eval("++1").is_err());
+    }
+}
@@ -0,0 +1,617 @@
+// Syntax tree produced by the parser and consumed by evaluation.rs.
+// Words keep track of how they were quoted so that expansion can decide per part what to touch.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WordPart {
+    /// Unquoted text.
+    Literal(String),
+    /// Escaped text that must be taken verbatim.
+    Quoted(String),
+    /// Contents of a "..." string. Parameters inside still expand.
+    DoubleQuoted(Vec<WordPart>),
+    /// $NAME or ${NAME}
+    Parameter(String),
+    /// ${NAME<operator>...}
+    ParameterExpansion(Box<ParameterExpansion>),
+    /// $(...) or `...`
+    CommandSubstitution(List),
+    /// $((...)), whose text is expanded before it is evaluated.
+    Arithmetic(Vec<WordPart>),
+    /// (x y z) right after NAME= or NAME+=.
+    Array(Vec<ArrayElement>),
+}
+
+/// An element of an array assignment: value, or [index]=value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArrayElement {
+    pub index: Option<Word>,
+    pub value: Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaceMode {
+    /// ${name/pattern/string}
+    First,
+    /// ${name//pattern/string}
+    All,
+    /// ${name/#pattern/string}
+    Prefix,
+    /// ${name/%pattern/string}
+    Suffix,
+}
+
+/// What to do with a parameter inside ${...}. `colon` means the operator also treats an empty value as unset.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParameterOp {
+    /// ${#name}
+    Length,
+    /// ${name:-word}
+    Default { colon: bool, word: Word },
+    /// ${name:=word}
+    Assign { colon: bool, word: Word },
+    /// ${name:?word}
+    Error { colon: bool, word: Word },
+    /// ${name:+word}
+    Alternative { colon: bool, word: Word },
+    /// ${name#pattern} and ${name##pattern}
+    RemovePrefix { longest: bool, pattern: Word },
+    /// ${name%pattern} and ${name%%pattern}
+    RemoveSuffix { longest: bool, pattern: Word },
+    Replace { mode: ReplaceMode, pattern: Word, replacement: Word },
+    /// ${name:offset} and ${name:offset:length}, both arithmetic expressions.
+    Substring { offset: Word, length: Option<Word> },
+    /// ${name^pattern}, ${name^^pattern}, ${name,pattern} and ${name,,pattern}
+    Case { upper: bool, all: bool, pattern: Word },
+    /// ${name[index]} with no operator.
+    Value,
+    /// ${!name[@]} and ${!name[*]}
+    Keys,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterExpansion {
+    pub name: String,
+    /// The subscript in ${name[index]...}; @ and * stand for every element.
+    pub index: Option<Word>,
+    pub op: ParameterOp,
+}
+
+impl ParameterExpansion {
+    /// The words nested inside the operator.
+    pub fn words(&self) -> Vec<&Word> {
+        let mut words: Vec<&Word> = self.index.iter().collect();
+        words.extend(self.op_words());
+        words
+    }
+
+    fn op_words(&self) -> Vec<&Word> {
+        match &self.op {
+            ParameterOp::Length | ParameterOp::Value | ParameterOp::Keys => Vec::new(),
+            ParameterOp::Default { word, .. } | ParameterOp::Assign { word, .. }
+            | ParameterOp::Error { word, .. } | ParameterOp::Alternative { word, .. } => vec![word],
+            ParameterOp::RemovePrefix { pattern, .. } | ParameterOp::RemoveSuffix { pattern, .. }
+            | ParameterOp::Case { pattern, .. } => vec![pattern],
+            ParameterOp::Replace { pattern, replacement, .. } => vec![pattern, replacement],
+            ParameterOp::Substring { offset, length } => {
+                let mut words: Vec<&Word> = vec![offset];
+                words.extend(length);
+                words
+            }
+        }
+    }
+
+    /// Whether the expansion works on every element, as ${name[@]}, ${#name[*]} and ${@:2} do.
+    pub fn selects_all(&self) -> bool {
+        let subscript: Option<&str> = match &self.index {
+            Some(index) => index.as_literal(),
+            None => Some(self.name.as_str()),
+        };
+        matches!(subscript, Some("@") | Some("*"))
+    }
+
+    /// Whether the expansion stands for several values rather than one string.
+    pub fn is_list(&self) -> bool {
+        self.selects_all() && self.op != ParameterOp::Length
+    }
+
+    /// Whether the values are joined into one string inside double quotes, as for ${name[*]}.
+    pub fn is_star(&self) -> bool {
+        match &self.index {
+            Some(index) => index.as_literal() == Some("*"),
+            None => self.name == "*",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Word {
+    pub parts: Vec<WordPart>,
+}
+
+impl Word {
+    pub fn new(parts: Vec<WordPart>) -> Self {
+        Word { parts }
+    }
+
+    /// The text of the word if it consists of a single unquoted literal, e.g. a reserved word.
+    pub fn as_literal(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [WordPart::Literal(s)] => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Whether running the word's expansions can change $?.
+    pub fn has_command_substitution(&self) -> bool {
+        fn check(part: &WordPart) -> bool {
+            match part {
+                WordPart::CommandSubstitution(_) => true,
+                WordPart::DoubleQuoted(parts) | WordPart::Arithmetic(parts) => parts.iter().any(check),
+                WordPart::Array(elements) => elements.iter().any(|element| {
+                    element.index.iter().chain(std::iter::once(&element.value)).any(|word| word.parts.iter().any(check))
+                }),
+                WordPart::ParameterExpansion(expansion) => expansion.words().iter().any(|word| word.parts.iter().any(check)),
+                _ => false,
+            }
+        }
+        self.parts.iter().any(check)
+    }
+
+    /// Whether any part of the word was quoted or escaped.
+    pub fn is_quoted(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, WordPart::Quoted(_) | WordPart::DoubleQuoted(_)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    /// name[index]=value sets a single element.
+    pub index: Option<Word>,
+    /// name+=value appends to the current value.
+    pub append: bool,
+    /// A single WordPart::Array for name=(...).
+    pub value: Word,
+}
+
+impl Assignment {
+    /// The elements of name=(...).
+    pub fn array(&self) -> Option<&[ArrayElement]> {
+        match self.value.parts.as_slice() {
+            [WordPart::Array(elements)] => Some(elements),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectKind {
+    /// <
+    Input,
+    /// >
+    Output,
+    /// >|
+    Clobber,
+    /// >>
+    Append,
+    /// <>
+    ReadWrite,
+    /// <&n or <&-
+    DupInput,
+    /// >&n or >&-
+    DupOutput,
+    /// <<<
+    HereString,
+    /// << or <<-, with the body as the target
+    HereDoc,
+    /// &>
+    OutputBoth,
+    /// &>>
+    AppendBoth,
+}
+
+impl RedirectKind {
+    /// The descriptor the redirection applies to when none is written in front of it.
+    pub fn default_fd(&self) -> i32 {
+        match self {
+            RedirectKind::Input | RedirectKind::ReadWrite | RedirectKind::DupInput | RedirectKind::HereString | RedirectKind::HereDoc => 0,
+            _ => 1,
+        }
+    }
+
+    pub fn operator(&self) -> &'static str {
+        match self {
+            RedirectKind::Input => "<",
+            RedirectKind::Output => ">",
+            RedirectKind::Clobber => ">|",
+            RedirectKind::Append => ">>",
+            RedirectKind::ReadWrite => "<>",
+            RedirectKind::DupInput => "<&",
+            RedirectKind::DupOutput => ">&",
+            RedirectKind::HereString => "<<<",
+            // The delimiter is gone by now, so a here-document is shown as the equivalent here-string.
+            RedirectKind::HereDoc => "<<<",
+            RedirectKind::OutputBoth => "&>",
+            RedirectKind::AppendBoth => "&>>",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub fd: i32,
+    pub kind: RedirectKind,
+    pub target: Word,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SimpleCommand {
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompoundCommand {
+    /// (( expression ))
+    Arithmetic(Vec<WordPart>),
+    /// if ...; then ...; elif ...; then ...; else ...; fi
+    If { branches: Vec<(List, List)>, else_branch: Option<List> },
+    /// while ...; do ...; done, or until when `until` is set.
+    While { until: bool, condition: List, body: List },
+    /// for name [in words]; do ...; done. Without `in`, the words are "$@".
+    For { name: String, words: Option<Vec<Word>>, body: List },
+    /// for ((init; condition; update)); do ...; done
+    ArithmeticFor { init: Vec<WordPart>, condition: Vec<WordPart>, update: Vec<WordPart>, body: List },
+    /// case word in pattern) ...;; esac
+    Case { word: Word, items: Vec<CaseItem> },
+    /// { ...; }, run in the current shell.
+    BraceGroup(List),
+    /// ( ... ), run in a forked copy of the shell so nothing it changes leaks out.
+    Subshell(List),
+    /// [[ expression ]]
+    Conditional(CondExpr),
+}
+
+/// The expression inside [[ ]]. Its words are expanded without splitting or pathname expansion.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CondExpr {
+    /// -f file, -z string, ...
+    Unary(String, Word),
+    /// left op right, where op is a string, integer, file or =~ operator.
+    Binary(Word, String, Word),
+    /// A lone word, true when it expands to a non-empty string.
+    Word(Word),
+    Not(Box<CondExpr>),
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+    /// ( expression )
+    Group(Box<CondExpr>),
+}
+
+/// What happens after a case item's commands have run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaseTerminator {
+    /// ;; ends the case command.
+    Break,
+    /// ;& runs the next item's commands without testing its patterns.
+    FallThrough,
+    /// ;;& goes on testing the patterns of the following items.
+    Continue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseItem {
+    pub patterns: Vec<Word>,
+    pub body: List,
+    pub terminator: CaseTerminator,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    /// A compound command with the redirections written after it.
+    Compound(CompoundCommand, Vec<Redirect>),
+    /// name() compound-command
+    FunctionDefinition(FunctionDefinition),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDefinition {
+    pub name: String,
+    /// Always a compound command, with any redirections that apply to every call.
+    pub body: Box<Command>,
+}
+
+/// [!] cmd1 | cmd2 | ...
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pipeline {
+    pub negated: bool,
+    pub commands: Vec<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AndOrOp {
+    /// &&
+    And,
+    /// ||
+    Or,
+}
+
+/// Pipelines chained with && and ||, evaluated left to right.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(AndOrOp, Pipeline)>,
+    /// Ended by '&', so it runs as a background job.
+    pub background: bool,
+}
+
+/// And-or lists separated by ';', '&' or newlines.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct List {
+    pub items: Vec<AndOr>,
+}
+
+// Rendering back to shell source, used for job listings.
+
+impl fmt::Display for WordPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WordPart::Literal(text) => write!(f, "{}", text),
+            WordPart::Quoted(text) => write!(f, "'{}'", text.replace('\'', "'\\''")),
+            WordPart::DoubleQuoted(parts) => {
+                write!(f, "\"")?;
+                for part in parts {
+                    match part {
+                        WordPart::Quoted(text) => {
+                            for c in text.chars() {
+                                if matches!(c, '$' | '`' | '"' | '\\') {
+                                    write!(f, "\\")?;
+                                }
+                                write!(f, "{}", c)?;
+                            }
+                        }
+                        _ => write!(f, "{}", part)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            WordPart::Parameter(name) => write!(f, "${{{}}}", name),
+            WordPart::ParameterExpansion(expansion) => write!(f, "{}", expansion),
+            WordPart::CommandSubstitution(list) => write!(f, "$({})", list),
+            WordPart::Arithmetic(parts) => write!(f, "$(({}))", arithmetic_text(parts)),
+            WordPart::Array(elements) => {
+                let elements: Vec<String> = elements.iter().map(|element| match &element.index {
+                    Some(index) => format!("[{}]={}", index, element.value),
+                    None => element.value.to_string(),
+                }).collect();
+                write!(f, "({})", elements.join(" "))
+            }
+        }
+    }
+}
+
+/// Arithmetic text is shown as written, without the quoting Display adds for quoted parts.
+fn arithmetic_text(parts: &[WordPart]) -> String {
+    parts.iter().map(|part| match part {
+        WordPart::Literal(text) | WordPart::Quoted(text) => text.clone(),
+        _ => part.to_string(),
+    }).collect()
+}
+
+impl fmt::Display for ParameterExpansion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let colon = |colon: bool| if colon { ":" } else { "" };
+        let name: String = match &self.index {
+            Some(index) => format!("{}[{}]", self.name, index),
+            None => self.name.clone(),
+        };
+        match &self.op {
+            ParameterOp::Length => write!(f, "${{#{}}}", name),
+            ParameterOp::Default { colon: c, word } => write!(f, "${{{}{}-{}}}", name, colon(*c), word),
+            ParameterOp::Assign { colon: c, word } => write!(f, "${{{}{}={}}}", name, colon(*c), word),
+            ParameterOp::Error { colon: c, word } => write!(f, "${{{}{}?{}}}", name, colon(*c), word),
+            ParameterOp::Alternative { colon: c, word } => write!(f, "${{{}{}+{}}}", name, colon(*c), word),
+            ParameterOp::RemovePrefix { longest, pattern } => write!(f, "${{{}{}{}}}", name, if *longest { "##" } else { "#" }, pattern),
+            ParameterOp::RemoveSuffix { longest, pattern } => write!(f, "${{{}{}{}}}", name, if *longest { "%%" } else { "%" }, pattern),
+            ParameterOp::Replace { mode, pattern, replacement } => {
+                let op: &str = match mode {
+                    ReplaceMode::First => "/",
+                    ReplaceMode::All => "//",
+                    ReplaceMode::Prefix => "/#",
+                    ReplaceMode::Suffix => "/%",
+                };
+                write!(f, "${{{}{}{}/{}}}", name, op, pattern, replacement)
+            }
+            ParameterOp::Substring { offset, length } => match length {
+                Some(length) => write!(f, "${{{}:{}:{}}}", name, offset, length),
+                None => write!(f, "${{{}:{}}}", name, offset),
+            },
+            ParameterOp::Case { upper, all, pattern } => {
+                let op: &str = match (upper, all) {
+                    (true, true) => "^^",
+                    (true, false) => "^",
+                    (false, true) => ",,",
+                    (false, false) => ",",
+                };
+                write!(f, "${{{}{}{}}}", name, op, pattern)
+            }
+            ParameterOp::Value => write!(f, "${{{}}}", name),
+            ParameterOp::Keys => write!(f, "${{!{}}}", name),
+        }
+    }
+}
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in &self.parts {
+            write!(f, "{}", part)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.fd != self.kind.default_fd() {
+            write!(f, "{}", self.fd)?;
+        }
+        write!(f, "{}{}", self.kind.operator(), self.target)
+    }
+}
+
+impl fmt::Display for Assignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(index) = &self.index {
+            write!(f, "[{}]", index)?;
+        }
+        write!(f, "{}={}", if self.append { "+" } else { "" }, self.value)
+    }
+}
+
+impl fmt::Display for SimpleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut items: Vec<String> = Vec::new();
+        items.extend(self.assignments.iter().map(|a| a.to_string()));
+        items.extend(self.words.iter().map(|w| w.to_string()));
+        items.extend(self.redirects.iter().map(|r| r.to_string()));
+        write!(f, "{}", items.join(" "))
+    }
+}
+
+impl fmt::Display for CompoundCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompoundCommand::Arithmetic(parts) => write!(f, "(({}))", arithmetic_text(parts)),
+            CompoundCommand::If { branches, else_branch } => {
+                for (i, (condition, body)) in branches.iter().enumerate() {
+                    write!(f, "{} {}; then {}; ", if i == 0 { "if" } else { "elif" }, condition, body)?;
+                }
+                if let Some(body) = else_branch {
+                    write!(f, "else {}; ", body)?;
+                }
+                write!(f, "fi")
+            }
+            CompoundCommand::While { until, condition, body } => {
+                write!(f, "{} {}; do {}; done", if *until { "until" } else { "while" }, condition, body)
+            }
+            CompoundCommand::For { name, words, body } => {
+                write!(f, "for {}", name)?;
+                if let Some(words) = words {
+                    write!(f, " in")?;
+                    for word in words {
+                        write!(f, " {}", word)?;
+                    }
+                }
+                write!(f, "; do {}; done", body)
+            }
+            CompoundCommand::ArithmeticFor { init, condition, update, body } => write!(
+                f,
+                "for (({}; {}; {})); do {}; done",
+                arithmetic_text(init),
+                arithmetic_text(condition),
+                arithmetic_text(update),
+                body
+            ),
+            CompoundCommand::Case { word, items } => {
+                write!(f, "case {} in", word)?;
+                for item in items {
+                    let patterns: Vec<String> = item.patterns.iter().map(|p| p.to_string()).collect();
+                    write!(f, " {}) {} {}", patterns.join(" | "), item.body, item.terminator)?;
+                }
+                write!(f, " esac")
+            }
+            CompoundCommand::BraceGroup(list) => write!(f, "{{ {}; }}", list),
+            CompoundCommand::Subshell(list) => write!(f, "( {} )", list),
+            CompoundCommand::Conditional(expression) => write!(f, "[[ {} ]]", expression),
+        }
+    }
+}
+
+impl fmt::Display for CondExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CondExpr::Unary(op, word) => write!(f, "{} {}", op, word),
+            CondExpr::Binary(left, op, right) => write!(f, "{} {} {}", left, op, right),
+            CondExpr::Word(word) => write!(f, "{}", word),
+            CondExpr::Not(expression) => write!(f, "! {}", expression),
+            CondExpr::And(left, right) => write!(f, "{} && {}", left, right),
+            CondExpr::Or(left, right) => write!(f, "{} || {}", left, right),
+            CondExpr::Group(expression) => write!(f, "( {} )", expression),
+        }
+    }
+}
+
+impl fmt::Display for CaseTerminator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaseTerminator::Break => write!(f, ";;"),
+            CaseTerminator::FallThrough => write!(f, ";&"),
+            CaseTerminator::Continue => write!(f, ";;&"),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Simple(command) => write!(f, "{}", command),
+            Command::Compound(command, redirects) => {
+                write!(f, "{}", command)?;
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+            Command::FunctionDefinition(function) => write!(f, "{}", function),
+        }
+    }
+}
+
+impl fmt::Display for FunctionDefinition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} () {}", self.name, self.body)
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negated {
+            write!(f, "! ")?;
+        }
+        let commands: Vec<String> = self.commands.iter().map(|c| c.to_string()).collect();
+        write!(f, "{}", commands.join(" | "))
+    }
+}
+
+impl fmt::Display for AndOr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+        for (op, pipeline) in &self.rest {
+            match op {
+                AndOrOp::And => write!(f, " && {}", pipeline)?,
+                AndOrOp::Or => write!(f, " || {}", pipeline)?,
+            }
+        }
+        if self.background {
+            write!(f, " &")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for List {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, "{}", if self.items[i - 1].background { " " } else { "; " })?;
+            }
+            write!(f, "{}", item)?;
+        }
+        Ok(())
+    }
+}
@@ -1,156 +1,993 @@
-use std::{env, borrow::Cow, path::{Path, PathBuf}, collections::HashMap};
+use std::{env, ffi::{CStr, CString}, path::{Component, Path, PathBuf}, collections::HashMap};
 use crate::helpers::{load_aliases, get_alias_file_path};
-use crate::globals::ShellState;
-
-pub fn split_command(cmd: &str) -> Vec<String> {
-    let mut result: Vec<String> = Vec::new();
-    let mut current: String = String::new();
-    let mut in_quotes: bool = false;
-    let mut escaped: bool = false;
-
-    for c in cmd.trim().chars() {
-        match c {
-            '"' if !escaped => {
-                in_quotes = !in_quotes;
-                if !in_quotes && !current.is_empty() {
-                    result.push(std::mem::take(&mut current));
+use crate::globals::{ControlFlow, ShellState};
+use crate::config::Config;
+use crate::jobs::JobControl;
+use crate::evaluation::command_substitution;
+use crate::arithmetic::evaluate;
+use crate::ast::{ParameterExpansion, ParameterOp, ReplaceMode, Word, WordPart};
+use crate::parser::{is_valid_name, unexpected};
+use crate::pattern::{self, Pattern};
+use crate::lexer::{Lexer, Token};
+use crate::conditional::escape_regex;
+
+/// Expands a parsed word into the single string it stands for, without splitting it.
+/// Quoted parts are taken verbatim; tilde expansion only applies to unquoted text at the start of the word.
+/// Errors (e.g. a bad arithmetic expression) abort the command the word belongs to.
+pub fn expand_word(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, word: &Word) -> Result<String, String> {
+    let mut result: String = String::new();
+    for (i, part) in word.parts.iter().enumerate() {
+        if let (0, WordPart::Literal(text)) = (i, part) {
+            if let Some((home, rest)) = expand_tilde(state, text, word.parts.len() == 1) {
+                result.push_str(&home);
+                result.push_str(rest);
+                continue;
+            }
+        }
+        expand_part(state, conf, job_control, part, &mut result)?;
+    }
+    Ok(result)
+}
+
+/// A field being built up during expansion, along with the pattern it stands for.
+/// Quoted text is escaped in the pattern so that only unquoted metacharacters take part in globbing.
+#[derive(Default)]
+struct Field {
+    text: String,
+    pattern: String,
+    // Whether the field exists even when empty, as for "".
+    started: bool,
+}
+
+impl Field {
+    /// Unquoted text only makes a field if it isn't empty, so an unset $x disappears entirely.
+    fn push_unquoted(&mut self, text: &str) {
+        self.text.push_str(text);
+        self.pattern.push_str(text);
+        self.started |= !text.is_empty();
+    }
+
+    fn push_quoted(&mut self, text: &str) {
+        self.text.push_str(text);
+        self.pattern.push_str(&pattern::escape(text));
+        self.started = true;
+    }
+}
+
+/// Collects fields, splitting the results of unquoted expansions on IFS.
+struct FieldSplitter {
+    fields: Vec<Field>,
+    current: Field,
+    ifs: String,
+}
+
+impl FieldSplitter {
+    fn new(state: &ShellState) -> Self {
+        FieldSplitter {
+            fields: Vec::new(),
+            current: Field::default(),
+            ifs: state.get_var("IFS").unwrap_or_else(|| " \t\n".to_owned()),
+        }
+    }
+
+    /// Ends the current field, keeping it only if something has gone into it.
+    fn end_field(&mut self) {
+        if self.current.started {
+            self.fields.push(std::mem::take(&mut self.current));
+        }
+    }
+
+    /// Adds the result of an unquoted expansion. Runs of IFS whitespace separate fields,
+    /// and every other IFS character separates fields even when that leaves one empty.
+    fn push_split(&mut self, text: &str) {
+        let mut chars: std::iter::Peekable<std::str::Chars<'_>> = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if !self.ifs.contains(c) {
+                self.current.push_unquoted(&c.to_string());
+                continue;
+            }
+
+            let mut hard: bool = !c.is_whitespace();
+            while let Some(next) = chars.peek().copied().filter(|next| self.ifs.contains(*next)) {
+                // Whitespace around a single non-whitespace delimiter belongs to it.
+                if !next.is_whitespace() {
+                    if hard {
+                        break;
+                    }
+                    hard = true;
                 }
+                chars.next();
             }
-            ' ' if !in_quotes && !escaped => {
-                if !current.is_empty() {
-                    result.push(std::mem::take(&mut current));
+
+            if hard {
+                self.current.started = true;
+            }
+            self.end_field();
+        }
+    }
+
+    fn finish(mut self) -> Vec<Field> {
+        self.end_field();
+        self.fields
+    }
+}
+
+/// Expands a parsed word into fields. The results of unquoted expansions are split on IFS,
+/// "$@" becomes one field per positional parameter, and then each field with unquoted glob characters
+/// is replaced by the paths it matches.
+pub fn expand_word_fields(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, word: &Word) -> Result<Vec<String>, String> {
+    let mut splitter: FieldSplitter = FieldSplitter::new(state);
+
+    for (i, part) in word.parts.iter().enumerate() {
+        match part {
+            WordPart::Literal(text) if i == 0 => match expand_tilde(state, text, word.parts.len() == 1) {
+                Some((home, rest)) => {
+                    splitter.current.push_quoted(&home);
+                    splitter.current.push_unquoted(rest);
+                }
+                None => splitter.current.push_unquoted(text),
+            },
+            WordPart::Literal(text) => splitter.current.push_unquoted(text),
+            WordPart::Quoted(text) => splitter.current.push_quoted(text),
+            WordPart::DoubleQuoted(parts) => {
+                // "" is an empty field of its own.
+                if parts.is_empty() {
+                    splitter.current.push_quoted("");
+                }
+                for inner in parts {
+                    match expand_list(state, conf, job_control, inner)? {
+                        Some((values, false)) => {
+                            for (n, value) in values.iter().enumerate() {
+                                if n > 0 {
+                                    splitter.end_field();
+                                }
+                                splitter.current.push_quoted(value);
+                            }
+                        }
+                        Some((values, true)) => splitter.current.push_quoted(&values.join(&state.star_separator())),
+                        None => {
+                            let mut text: String = String::new();
+                            expand_part(state, conf, job_control, inner, &mut text)?;
+                            splitter.current.push_quoted(&text);
+                        }
+                    }
                 }
             }
-            '\\' if !escaped => {
-                escaped = true;
+            _ => match expand_list(state, conf, job_control, part)? {
+                // Unquoted, each value is split separately.
+                Some((values, _)) => {
+                    for (n, value) in values.iter().enumerate() {
+                        if n > 0 {
+                            splitter.end_field();
+                        }
+                        splitter.push_split(value);
+                    }
+                }
+                None => {
+                    let mut text: String = String::new();
+                    expand_part(state, conf, job_control, part, &mut text)?;
+                    splitter.push_split(&text);
+                }
+            },
+        }
+    }
+
+    let mut expanded: Vec<String> = Vec::new();
+    for field in splitter.finish() {
+        expanded.extend(expand_pathname(conf, field)?);
+    }
+    Ok(expanded)
+}
+
+/// The separate values of $@, $*, ${name[@]}, ${!name[*]} and the like, or None for a part that
+/// expands to a single string. The flag is set for the * forms, whose values are joined inside double quotes.
+fn expand_list(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, part: &WordPart) -> Result<Option<(Vec<String>, bool)>, String> {
+    match part {
+        WordPart::Parameter(name) if name == "@" || name == "*" => Ok(Some((state.positional.clone(), name == "*"))),
+        WordPart::ParameterExpansion(expansion) if expansion.is_list() => {
+            Ok(Some((expand_parameter(state, conf, job_control, expansion)?, expansion.is_star())))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Pathname expansion of one field, following the nullglob, failglob, dotglob and globstar options.
+fn expand_pathname(conf: &Config, field: Field) -> Result<Vec<String>, String> {
+    if !pattern::has_glob_chars(&field.pattern) {
+        return Ok(vec![field.text]);
+    }
+    let paths: Vec<String> = pattern::glob(&field.pattern, conf.is_enabled("dotglob"), conf.is_enabled("globstar"));
+    if !paths.is_empty() {
+        Ok(paths)
+    } else if conf.is_enabled("failglob") {
+        Err(format!("no match: {}", field.text))
+    } else if conf.is_enabled("nullglob") {
+        Ok(Vec::new())
+    } else {
+        Ok(vec![field.text])
+    }
+}
+
+fn expand_part(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, part: &WordPart, result: &mut String) -> Result<(), String> {
+    match part {
+        WordPart::Literal(text) => result.push_str(text),
+        WordPart::Quoted(text) => result.push_str(text),
+        WordPart::DoubleQuoted(parts) => {
+            for inner in parts {
+                expand_part(state, conf, job_control, inner, result)?;
             }
-            _ => {
-                if escaped {
-                    escaped = false;
+        }
+        WordPart::Parameter(name) => result.push_str(&lookup_parameter(state, conf, name)?),
+        WordPart::ParameterExpansion(expansion) => {
+            let values: Vec<String> = expand_parameter(state, conf, job_control, expansion)?;
+            let separator: String = if expansion.is_star() { state.star_separator() } else { " ".to_owned() };
+            result.push_str(&values.join(&separator));
+        }
+        WordPart::CommandSubstitution(list) => result.push_str(&command_substitution(state, conf, job_control, list)),
+        WordPart::Arithmetic(parts) => result.push_str(&expand_arithmetic(state, conf, job_control, parts)?.to_string()),
+        WordPart::Array(_) => return Err(format!("{}: arrays can only be assigned to variables", part)),
+    }
+    Ok(())
+}
+
+/// The value of a parameter; unset parameters are empty, or a fatal error under `set -u`.
+fn lookup_parameter(state: &mut ShellState, conf: &Config, name: &str) -> Result<String, String> {
+    match state.get_var(name) {
+        Some(value) => Ok(value),
+        None if conf.is_enabled("nounset") && name != "@" && name != "*" => Err(fatal(state, format!("{}: unbound variable", name))),
+        None => Ok(String::new()),
+    }
+}
+
+/// Marks an expansion error as one that ends a script or subshell, and passes its message on.
+fn fatal(state: &mut ShellState, message: String) -> String {
+    state.control_flow = Some(ControlFlow::Exit(1));
+    message
+}
+
+/// Expands the subscript of an array element: a key for an associative array, otherwise an arithmetic
+/// expression whose negative values count back from the end of the array.
+pub fn expand_subscript(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, name: &str, index: &Word) -> Result<String, String> {
+    if state.is_associative(name) {
+        let key: String = expand_word(state, conf, job_control, index)?;
+        if key.is_empty() {
+            return Err(format!("{}[{}]: bad array subscript", name, index));
+        }
+        return Ok(key);
+    }
+    let mut position: i64 = expand_arithmetic(state, conf, job_control, &index.parts)?;
+    if position < 0 {
+        position += state.next_index(name) as i64;
+    }
+    if position < 0 {
+        return Err(format!("{}[{}]: bad array subscript", name, index));
+    }
+    Ok(position.to_string())
+}
+
+/// What a parameter expansion works on: the value of the parameter or element, or every element
+/// for ${name[@]}, $@ and the like. None when the parameter or element is unset.
+fn parameter_values(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, expansion: &ParameterExpansion) -> Result<Option<Vec<String>>, String> {
+    let name: &str = &expansion.name;
+    if expansion.selects_all() {
+        return Ok(Some(match name {
+            "@" | "*" => state.positional.clone(),
+            _ => state.get_value(name).map(|value| value.values()).unwrap_or_default(),
+        }));
+    }
+    match &expansion.index {
+        Some(index) => {
+            let key: String = expand_subscript(state, conf, job_control, name, index)?;
+            Ok(state.get_element(name, &key).map(|value| vec![value]))
+        }
+        None => Ok(state.get_var(name).map(|value| vec![value])),
+    }
+}
+
+/// The values an operator applies to. An unset parameter is empty, or a fatal error under `set -u`.
+fn present_values(state: &mut ShellState, conf: &Config, target: &str, values: Option<Vec<String>>) -> Result<Vec<String>, String> {
+    match values {
+        Some(values) => Ok(values),
+        None if conf.is_enabled("nounset") => Err(fatal(state, format!("{}: unbound variable", target))),
+        None => Ok(vec![String::new()]),
+    }
+}
+
+/// Expands ${...}. The result has one value, except for the forms that work on every element,
+/// where each element gives one.
+fn expand_parameter(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, expansion: &ParameterExpansion) -> Result<Vec<String>, String> {
+    let name: &str = &expansion.name;
+    let target: String = match &expansion.index {
+        Some(index) => format!("{}[{}]", name, index),
+        None => name.to_owned(),
+    };
+    let values: Option<Vec<String>> = parameter_values(state, conf, job_control, expansion)?;
+    // Whether the parameter counts as set for the :-, :=, :? and :+ forms.
+    let is_set = |colon: bool| values.as_ref().is_some_and(|values| !values.is_empty() && (!colon || !values.join(" ").is_empty()));
+
+    match &expansion.op {
+        ParameterOp::Default { colon, word } => match is_set(*colon) {
+            true => Ok(values.unwrap_or_default()),
+            false => Ok(vec![expand_word(state, conf, job_control, word)?]),
+        },
+        ParameterOp::Assign { colon, word } => {
+            if is_set(*colon) {
+                return Ok(values.unwrap_or_default());
+            }
+            if expansion.is_list() || !is_valid_name(name) {
+                return Err(format!("${}: cannot assign in this way", target));
+            }
+            let new_value: String = expand_word(state, conf, job_control, word)?;
+            match &expansion.index {
+                Some(index) => {
+                    let key: String = expand_subscript(state, conf, job_control, name, index)?;
+                    state.set_element(name, &key, &new_value)?;
+                }
+                None => state.set_var(name, &new_value)?,
+            }
+            Ok(vec![new_value])
+        }
+        ParameterOp::Error { colon, word } => {
+            if is_set(*colon) {
+                return Ok(values.unwrap_or_default());
+            }
+            let message: String = expand_word(state, conf, job_control, word)?;
+            match message.is_empty() {
+                true => Err(fatal(state, format!("{}: parameter null or not set", target))),
+                false => Err(fatal(state, format!("{}: {}", target, message))),
+            }
+        }
+        ParameterOp::Alternative { colon, word } => match is_set(*colon) {
+            true => Ok(vec![expand_word(state, conf, job_control, word)?]),
+            false => Ok(Vec::new()),
+        },
+        ParameterOp::Length if expansion.selects_all() => Ok(vec![values.map_or(0, |values| values.len()).to_string()]),
+        ParameterOp::Length => Ok(vec![present_values(state, conf, &target, values)?[0].chars().count().to_string()]),
+        ParameterOp::RemovePrefix { longest, pattern } => {
+            let values: Vec<String> = present_values(state, conf, &target, values)?;
+            let pattern: Pattern = Pattern::new(&expand_pattern(state, conf, job_control, pattern)?);
+            Ok(values.iter().map(|value| remove_prefix(value, &pattern, *longest)).collect())
+        }
+        ParameterOp::RemoveSuffix { longest, pattern } => {
+            let values: Vec<String> = present_values(state, conf, &target, values)?;
+            let pattern: Pattern = Pattern::new(&expand_pattern(state, conf, job_control, pattern)?);
+            Ok(values.iter().map(|value| remove_suffix(value, &pattern, *longest)).collect())
+        }
+        ParameterOp::Replace { mode, pattern, replacement } => {
+            let values: Vec<String> = present_values(state, conf, &target, values)?;
+            let pattern: String = expand_pattern(state, conf, job_control, pattern)?;
+            let replacement: String = expand_word(state, conf, job_control, replacement)?;
+            let compiled: Pattern = Pattern::new(&pattern);
+            Ok(values.iter().map(|value| replace_pattern(value, &compiled, pattern.is_empty(), &replacement, mode)).collect())
+        }
+        ParameterOp::Substring { offset, length } => {
+            let mut values: Vec<String> = present_values(state, conf, &target, values)?;
+            let mut offset: i64 = expand_arithmetic(state, conf, job_control, &offset.parts)?;
+            let length: Option<i64> = match length {
+                Some(length) => Some(expand_arithmetic(state, conf, job_control, &length.parts)?),
+                None => None,
+            };
+            if !expansion.selects_all() {
+                let chars: Vec<char> = values[0].chars().collect();
+                return Ok(vec![slice(&chars, offset, length)?.iter().collect()]);
+            }
+            // ${@:1} starts at $1, and ${@:0} and offsets from the end reach back to $0.
+            if expansion.index.is_none() {
+                match offset > 0 {
+                    true => offset -= 1,
+                    false => values.insert(0, state.get_var("0").unwrap_or_default()),
                 }
-                current.push(c);
             }
+            slice(&values, offset, length)
         }
+        ParameterOp::Case { upper, all, pattern } => {
+            let values: Vec<String> = present_values(state, conf, &target, values)?;
+            let pattern: String = expand_pattern(state, conf, job_control, pattern)?;
+            // Without a pattern every character is converted.
+            let pattern: Pattern = Pattern::new(if pattern.is_empty() { "?" } else { &pattern });
+            Ok(values.iter().map(|value| change_case(value, &pattern, *upper, *all)).collect())
+        }
+        ParameterOp::Value => present_values(state, conf, &target, values),
+        ParameterOp::Keys => Ok(state.get_value(name).map(|value| value.keys()).unwrap_or_default()),
+    }
+}
+
+fn remove_prefix(value: &str, pattern: &Pattern, longest: bool) -> String {
+    let mut ends: Vec<usize> = char_boundaries(value);
+    if longest {
+        ends.reverse();
     }
+    match ends.into_iter().find(|end| pattern.matches(&value[..*end])) {
+        Some(end) => value[end..].to_owned(),
+        None => value.to_owned(),
+    }
+}
 
-    if !current.is_empty() {
-        result.push(current);
+fn remove_suffix(value: &str, pattern: &Pattern, longest: bool) -> String {
+    let mut starts: Vec<usize> = char_boundaries(value);
+    if !longest {
+        starts.reverse();
     }
+    match starts.into_iter().find(|start| pattern.matches(&value[*start..])) {
+        Some(start) => value[..start].to_owned(),
+        None => value.to_owned(),
+    }
+}
+
+/// ${name:offset:length} on the characters of a value or the elements of an array.
+/// A negative offset counts from the end, and a negative length leaves that many items off the end.
+fn slice<T: Clone>(items: &[T], offset: i64, length: Option<i64>) -> Result<Vec<T>, String> {
+    let count: i64 = items.len() as i64;
+    let start: i64 = if offset < 0 { offset + count } else { offset };
+    if start < 0 || start > count {
+        return Ok(Vec::new());
+    }
+    let end: i64 = match length {
+        None => count,
+        Some(length) => {
+            let end: i64 = if length < 0 { count + length } else { start.saturating_add(length).min(count) };
+            if end < start {
+                return Err(format!("{}: substring expression < 0", length));
+            }
+            end
+        }
+    };
+    Ok(items[start as usize..end as usize].to_vec())
+}
 
+fn change_case(value: &str, pattern: &Pattern, upper: bool, all: bool) -> String {
+    let mut result: String = String::new();
+    for (i, c) in value.chars().enumerate() {
+        if (i == 0 || all) && pattern.matches(&c.to_string()) {
+            match upper {
+                true => result.extend(c.to_uppercase()),
+                false => result.extend(c.to_lowercase()),
+            }
+        } else {
+            result.push(c);
+        }
+    }
     result
 }
 
-pub fn expand(state: &mut ShellState, cmd: &str) -> String {
-    expand_dots(&expand_env_vars(state, &expand_home(cmd).to_string()))
+/// Expands a word used as a pattern. Quoted parts are escaped so that they only match themselves.
+pub fn expand_pattern(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, word: &Word) -> Result<String, String> {
+    expand_escaped(state, conf, job_control, word, pattern::escape)
 }
 
-pub fn lim_expand(state: &mut ShellState, cmd: &str) -> String {
-    expand_env_vars(state, &expand_home(cmd).to_string())
+/// Expands the regular expression on the right of =~, where quoted parts also match literally.
+pub fn expand_regex(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, word: &Word) -> Result<String, String> {
+    expand_escaped(state, conf, job_control, word, escape_regex)
 }
 
-pub fn expand_aliases(cmd_parts: Vec<String>) -> Vec<String>
-{
-    // Load aliases
-    let alias_file_path: PathBuf = get_alias_file_path();
-    let aliases: HashMap<String, String> = load_aliases(&alias_file_path);
+fn expand_escaped(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, word: &Word, escape: fn(&str) -> String) -> Result<String, String> {
+    let mut result: String = String::new();
+    for part in &word.parts {
+        match part {
+            WordPart::Literal(text) => result.push_str(text),
+            WordPart::Quoted(text) => result.push_str(&escape(text)),
+            WordPart::DoubleQuoted(parts) => {
+                let mut text: String = String::new();
+                for inner in parts {
+                    expand_part(state, conf, job_control, inner, &mut text)?;
+                }
+                result.push_str(&escape(&text));
+            }
+            _ => expand_part(state, conf, job_control, part, &mut result)?,
+        }
+    }
+    Ok(result)
+}
 
-    // Check for alias and expand if found
-    return if let Some(alias_cmd) = aliases.get(&cmd_parts[0]) {
-        let mut new_cmd_parts: Vec<String> = alias_cmd.split_whitespace().map(String::from).collect();
-        new_cmd_parts.extend_from_slice(&cmd_parts[1..]);
-        new_cmd_parts
-    } else {
-        cmd_parts
-    };
+/// The byte offsets at which a character starts, plus the end of the string.
+fn char_boundaries(text: &str) -> Vec<usize> {
+    text.char_indices().map(|(i, _)| i).chain(std::iter::once(text.len())).collect()
 }
 
-pub fn expand_dots(cmd: &str) -> String {
-    let parts: Vec<&str> = cmd.split_whitespace().collect();
-    let mut result: Vec<String> = Vec::new();
+/// ${name/pattern/replacement} and its //, /# and /% forms. Each match is the longest possible.
+fn replace_pattern(value: &str, pattern: &Pattern, empty: bool, replacement: &str, mode: &ReplaceMode) -> String {
+    let bounds: Vec<usize> = char_boundaries(value);
+    match mode {
+        ReplaceMode::Prefix => match bounds.iter().rev().find(|end| pattern.matches(&value[..**end])) {
+            Some(end) => format!("{}{}", replacement, &value[*end..]),
+            None => value.to_owned(),
+        },
+        ReplaceMode::Suffix => match bounds.iter().find(|start| pattern.matches(&value[**start..])) {
+            Some(start) => format!("{}{}", &value[..*start], replacement),
+            None => value.to_owned(),
+        },
+        ReplaceMode::First | ReplaceMode::All => {
+            if empty {
+                return value.to_owned();
+            }
+            let mut result: String = String::new();
+            let mut i: usize = 0;
+            let mut replaced: bool = false;
+            while i + 1 < bounds.len() {
+                let start: usize = bounds[i];
+                let found: Option<usize> = match replaced && *mode == ReplaceMode::First {
+                    true => None,
+                    false => (i + 1..bounds.len()).rev().find(|j| pattern.matches(&value[start..bounds[*j]])),
+                };
+                match found {
+                    Some(j) => {
+                        result.push_str(replacement);
+                        replaced = true;
+                        i = j;
+                    }
+                    None => {
+                        result.push_str(&value[start..bounds[i + 1]]);
+                        i += 1;
+                    }
+                }
+            }
+            result
+        }
+    }
+}
 
+/// Expands the parameters and substitutions in an arithmetic expression, then evaluates it.
+pub fn expand_arithmetic(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, parts: &[WordPart]) -> Result<i64, String> {
+    let mut expression: String = String::new();
     for part in parts {
-        let expanded: String = if part.contains('.') {
-            let path: &Path = Path::new(part);
-            let mut components: Vec<String> = Vec::new();
-
-            for component in path.components() {
-                match component {
-                    std::path::Component::CurDir => {
-                        components.push(env::current_dir().unwrap_or(PathBuf::from("/")).to_string_lossy().to_string());
-                    },
-                    std::path::Component::ParentDir => {
-                        if !components.is_empty() {
-                            components.pop();
-                        } else {
-                            let mut parent: PathBuf = env::current_dir().unwrap_or(PathBuf::from("/"));
-                            parent.pop();
-                            components.push(parent.to_string_lossy().into_owned());
-                        }
-                    },
-                    _ => components.push(component.as_os_str().to_string_lossy().into_owned()),
+        match part {
+            WordPart::Literal(text) => expression.push_str(text),
+            _ => expand_part(state, conf, job_control, part, &mut expression)?,
+        }
+    }
+    evaluate(state, &expression).map_err(|e| format!("{}: {}", expression.trim(), e))
+}
+
+/// Expands the words of a command into its argument vector.
+pub fn expand_words(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, words: &[Word]) -> Result<Vec<String>, String> {
+    let mut fields: Vec<String> = Vec::new();
+    for word in words {
+        for word in expand_braces(word) {
+            fields.extend(expand_word_fields(state, conf, job_control, &word)?);
+        }
+    }
+    Ok(fields)
+}
+
+/// A character of unquoted text, or any other word part, which braces can't come from.
+#[derive(Clone)]
+enum BracePiece {
+    Char(char),
+    Part(WordPart),
+}
+
+/// Brace expansion, which runs before every other expansion: a{b,c}d becomes abd acd, and {1..3} becomes 1 2 3.
+pub fn expand_braces(word: &Word) -> Vec<Word> {
+    let mut pieces: Vec<BracePiece> = Vec::new();
+    for part in &word.parts {
+        match part {
+            WordPart::Literal(text) => pieces.extend(text.chars().map(BracePiece::Char)),
+            _ => pieces.push(BracePiece::Part(part.clone())),
+        }
+    }
+
+    brace_expand(&pieces).into_iter().map(|pieces| {
+        let mut parts: Vec<WordPart> = Vec::new();
+        let mut literal: String = String::new();
+        for piece in pieces {
+            match piece {
+                BracePiece::Char(c) => literal.push(c),
+                BracePiece::Part(part) => {
+                    if !literal.is_empty() {
+                        parts.push(WordPart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
                 }
             }
+        }
+        if !literal.is_empty() {
+            parts.push(WordPart::Literal(literal));
+        }
+        Word::new(parts)
+    }).collect()
+}
+
+fn brace_expand(pieces: &[BracePiece]) -> Vec<Vec<BracePiece>> {
+    for start in 0..pieces.len() {
+        if !matches!(pieces[start], BracePiece::Char('{')) {
+            continue;
+        }
 
-            components.join("/")
+        // Find the matching '}' and the commas at this level.
+        let mut depth: usize = 0;
+        let mut commas: Vec<usize> = Vec::new();
+        let mut end: Option<usize> = None;
+        for (i, piece) in pieces.iter().enumerate().skip(start + 1) {
+            match piece {
+                BracePiece::Char('{') => depth += 1,
+                BracePiece::Char('}') if depth == 0 => {
+                    end = Some(i);
+                    break;
+                }
+                BracePiece::Char('}') => depth -= 1,
+                BracePiece::Char(',') if depth == 0 => commas.push(i),
+                _ => {}
+            }
+        }
+        let Some(end) = end else {
+            continue;
+        };
+
+        let prefix: &[BracePiece] = &pieces[..start];
+        let suffix: &[BracePiece] = &pieces[end + 1..];
+        let alternatives: Vec<Vec<BracePiece>> = if commas.is_empty() {
+            match brace_sequence(&pieces[start + 1..end]) {
+                Some(items) => items.into_iter().map(|item| item.chars().map(BracePiece::Char).collect()).collect(),
+                None => continue,
+            }
         } else {
-            part.to_string()
+            let mut bounds: Vec<usize> = vec![start];
+            bounds.extend(&commas);
+            bounds.push(end);
+            bounds.windows(2).map(|pair| pieces[pair[0] + 1..pair[1]].to_vec()).collect()
         };
 
-        result.push(expanded);
+        let mut results: Vec<Vec<BracePiece>> = Vec::new();
+        for alternative in alternatives {
+            let mut rest: Vec<BracePiece> = alternative;
+            rest.extend_from_slice(suffix);
+            for expanded in brace_expand(&rest) {
+                let mut result: Vec<BracePiece> = prefix.to_vec();
+                result.extend(expanded);
+                results.push(result);
+            }
+        }
+        return results;
     }
 
-    result.join(" ")
+    vec![pieces.to_vec()]
 }
 
-pub fn expand_home(cmd: &str) -> Cow<str> {
-    if cmd.contains('~') {
-        match dirs::home_dir() {
-            Some(home) => {
-                let home_str: Cow<'_, str> = home.to_string_lossy();
-                Cow::Owned(cmd.replace('~', &home_str))
-            }
-            None => Cow::Borrowed(cmd),
+/// The items of a {x..y} or {x..y..step} sequence, where x and y are both integers or both single letters.
+fn brace_sequence(pieces: &[BracePiece]) -> Option<Vec<String>> {
+    let mut text: String = String::new();
+    for piece in pieces {
+        match piece {
+            BracePiece::Char(c) => text.push(*c),
+            BracePiece::Part(_) => return None,
         }
-    } else {
-        Cow::Borrowed(cmd)
+    }
+
+    let bounds: Vec<&str> = text.split("..").collect();
+    let step: i64 = match bounds.len() {
+        2 => 1,
+        3 => bounds[2].parse::<i64>().ok()?.checked_abs()?.max(1),
+        _ => return None,
+    };
+    let (first, last): (&str, &str) = (bounds[0], bounds[1]);
+
+    if let (Ok(from), Ok(to)) = (first.parse::<i64>(), last.parse::<i64>()) {
+        // A leading zero on either end pads every item to the same width.
+        let padded = |s: &str| s.trim_start_matches('-').len() > 1 && s.trim_start_matches('-').starts_with('0');
+        let width: usize = if padded(first) || padded(last) { first.len().max(last.len()) } else { 0 };
+        return Some(sequence(from, to, step).map(|n| format!("{:0width$}", n, width = width)).collect());
+    }
+
+    let mut first_chars: std::str::Chars<'_> = first.chars();
+    let mut last_chars: std::str::Chars<'_> = last.chars();
+    match (first_chars.next(), first_chars.next(), last_chars.next(), last_chars.next()) {
+        (Some(from), None, Some(to), None) if from.is_ascii_alphabetic() && to.is_ascii_alphabetic() => {
+            Some(sequence(from as i64, to as i64, step).map(|c| (c as u8 as char).to_string()).collect())
+        }
+        _ => None,
     }
 }
 
-pub fn expand_env_vars(state: &mut ShellState, cmd: &str) -> String {
-    let mut result: String = String::new();
-    let mut in_var: bool = false;
-    let mut var_name: String = String::new();
-
-    for c in cmd.chars() {
-        if c == '$' {
-            in_var = true;
-            var_name.clear();
-        } else if in_var {
-            if c.is_alphanumeric() || c == '_' {
-                var_name.push(c);
-            } else {
-                in_var = false;
-                if let Some(value) = state.get_var(&var_name) {
-                    result.push_str(&value);
-                } else {
-                    result.push('$');
-                    result.push_str(&var_name);
-                }
-                result.push(c);
+fn sequence(from: i64, to: i64, step: i64) -> impl Iterator<Item = i64> {
+    let count: i128 = (to as i128 - from as i128).abs() / step as i128 + 1;
+    let step: i128 = if to < from { -(step as i128) } else { step as i128 };
+    (0..count).map(move |i| (from as i128 + i * step) as i64)
+}
+
+/// Replaces an alias in command position by its body, followed by the rest of the command.
+pub fn expand_aliases(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, cmd_parts: Vec<String>) -> Result<Vec<String>, String> {
+    let alias_file_path: PathBuf = get_alias_file_path();
+    let aliases: HashMap<String, String> = load_aliases(&alias_file_path);
+
+    match aliases.get(&cmd_parts[0]) {
+        Some(alias_cmd) => {
+            let mut new_cmd_parts: Vec<String> = expand_alias_body(state, conf, job_control, &cmd_parts[0], alias_cmd)?;
+            new_cmd_parts.extend_from_slice(&cmd_parts[1..]);
+            Ok(new_cmd_parts)
+        }
+        None => Ok(cmd_parts),
+    }
+}
+
+/// The fields of an alias body, whose words are quoted and expanded like those typed in its place.
+/// Only a simple command's words can be aliased.
+fn expand_alias_body(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, name: &str, body: &str) -> Result<Vec<String>, String> {
+    let mut lexer: Lexer = Lexer::new(body);
+    let mut fields: Vec<String> = Vec::new();
+    loop {
+        match lexer.next_token().map_err(|e| format!("{}: {}", name, e))? {
+            Token::Word(word) => fields.extend(expand_word_fields(state, conf, job_control, &word)?),
+            Token::Eof => return Ok(fields),
+            token => return Err(format!("{}: {}", name, unexpected(&token))),
+        }
+    }
+}
+
+/// Makes a path absolute and resolves its "." and ".." components without touching the filesystem.
+/// Only used where the shell itself resolves paths, like `cd` and running a file by path.
+pub fn resolve_dots(path: &str) -> PathBuf {
+    let mut resolved: PathBuf = if path.starts_with('/') {
+        PathBuf::from("/")
+    } else {
+        env::current_dir().unwrap_or(PathBuf::from("/"))
+    };
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => {
+                resolved.pop();
             }
-        } else {
-            result.push(c);
+            Component::Normal(name) => resolved.push(name),
         }
     }
 
-    if in_var {
-        if let Some(value) = state.get_var(&var_name) {
-            result.push_str(&value);
-        } else {
-            result.push('$');
-            result.push_str(&var_name);
+    resolved
+}
+
+/// Tilde expansion of the unquoted text at the start of a word: ~ and ~/..., ~user, ~+ ($PWD) and ~- ($OLDPWD).
+/// Returns the expanded prefix and the rest of the text, or None when the text doesn't start with a tilde prefix.
+/// `whole` says whether the text is the entire word, in which case the prefix may run to its end.
+pub fn expand_tilde<'a>(state: &ShellState, text: &'a str, whole: bool) -> Option<(String, &'a str)> {
+    let after: &str = text.strip_prefix('~')?;
+    let (prefix, rest): (&str, &str) = match after.find('/') {
+        Some(slash) => after.split_at(slash),
+        None if whole => (after, ""),
+        None => return None,
+    };
+
+    let expanded: String = match prefix {
+        "" => state.get_var("HOME").or_else(|| dirs::home_dir().map(|home| home.to_string_lossy().into_owned()))?,
+        "+" => state.get_var("PWD").or_else(|| env::current_dir().ok().map(|dir| dir.to_string_lossy().into_owned()))?,
+        "-" => state.get_var("OLDPWD")?,
+        user => user_home(user)?,
+    };
+    Some((expanded, rest))
+}
+
+/// The home directory of a user from the password database.
+fn user_home(user: &str) -> Option<String> {
+    let name: CString = CString::new(user).ok()?;
+    unsafe {
+        let entry: *mut libc::passwd = libc::getpwnam(name.as_ptr());
+        if entry.is_null() || (*entry).pw_dir.is_null() {
+            return None;
         }
+        Some(CStr::from_ptr((*entry).pw_dir).to_string_lossy().into_owned())
     }
+}
 
-    result
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Lexer, Token};
+
+    fn expanded(state: &mut ShellState, conf: &mut Config, text: &str) -> Result<String, String> {
+        let word: Word = match Lexer::new(text).next_token() {
+            Ok(Token::Word(word)) => word,
+            token => panic!("expected a word, got {:?}", token),
+        };
+        expand_word(state, conf, &mut JobControl::new(), &word)
+    }
+
+    /// Expands `text` with the given variables set.
+    fn expand_with(variables: &[(&str, &str)], text: &str) -> Result<String, String> {
+        let mut state: ShellState = ShellState::default();
+        for (name, value) in variables {
+            state.set_var(name, value).unwrap();
+        }
+        expanded(&mut state, &mut Config::default(), text)
+    }
+
+    #[test]
+    fn defaults_alternatives_and_errors() {
+        assert_eq!(expand_with(&[], "${x:-d}"), Ok("d".to_owned()));
+        assert_eq!(expand_with(&[("x", "")], "${x-d}"), Ok(String::new()));
+        assert_eq!(expand_with(&[("x", "")], "${x:-d}"), Ok("d".to_owned()));
+        assert_eq!(expand_with(&[("x", "v")], "${x:+alt}"), Ok("alt".to_owned()));
+        assert_eq!(expand_with(&[("x", "")], "${x:+alt}"), Ok(String::new()));
+        assert_eq!(expand_with(&[("x", "")], "${x?}"), Ok(String::new()));
+        assert_eq!(expand_with(&[], "${x:?is required}"), Err("x: is required".to_owned()));
+        assert_eq!(expand_with(&[("x", "")], "${x:?}"), Err("x: parameter null or not set".to_owned()));
+
+        let mut state: ShellState = ShellState::default();
+        assert_eq!(expanded(&mut state, &mut Config::default(), "${x:=a b}"), Ok("a b".to_owned()));
+        assert_eq!(state.get_var("x").as_deref(), Some("a b"));
+    }
+
+    #[test]
+    fn prefix_and_suffix_removal() {
+        let path: [(&str, &str); 1] = [("p", "/a/b/c.tar.gz")];
+        assert_eq!(expand_with(&path, "${p#*/}"), Ok("a/b/c.tar.gz".to_owned()));
+        assert_eq!(expand_with(&path, "${p##*/}"), Ok("c.tar.gz".to_owned()));
+        assert_eq!(expand_with(&path, "${p%.*}"), Ok("/a/b/c.tar".to_owned()));
+        assert_eq!(expand_with(&path, "${p%%.*}"), Ok("/a/b/c".to_owned()));
+        assert_eq!(expand_with(&path, "${p#x}"), Ok("/a/b/c.tar.gz".to_owned()));
+        // Quoted pattern characters only match themselves.
+        assert_eq!(expand_with(&[("x", "a*b")], "${x#\"a*\"}"), Ok("b".to_owned()));
+        assert_eq!(expand_with(&[("x", "aab")], "${x#\"a*\"}"), Ok("aab".to_owned()));
+    }
+
+    #[test]
+    fn length_and_substrings() {
+        let s: [(&str, &str); 1] = [("s", "hello")];
+        assert_eq!(expand_with(&s, "${#s}"), Ok("5".to_owned()));
+        assert_eq!(expand_with(&[], "${#s}"), Ok("0".to_owned()));
+        assert_eq!(expand_with(&s, "${s:1:3}"), Ok("ell".to_owned()));
+        assert_eq!(expand_with(&s, "${s: -3}"), Ok("llo".to_owned()));
+        assert_eq!(expand_with(&s, "${s:1:-1}"), Ok("ell".to_owned()));
+        assert_eq!(expand_with(&s, "${s:2+1}"), Ok("lo".to_owned()));
+        assert_eq!(expand_with(&s, "${s:9}"), Ok(String::new()));
+        assert!(expand_with(&s, "${s:3:-3}").is_err());
+    }
+
+    #[test]
+    fn replacement_and_case_conversion() {
+        let s: [(&str, &str); 1] = [("s", "hello")];
+        assert_eq!(expand_with(&s, "${s/l/L}"), Ok("heLlo".to_owned()));
+        assert_eq!(expand_with(&s, "${s//l/L}"), Ok("heLLo".to_owned()));
+        assert_eq!(expand_with(&s, "${s/#h/H}"), Ok("Hello".to_owned()));
+        assert_eq!(expand_with(&s, "${s/%o/O}"), Ok("hellO".to_owned()));
+        assert_eq!(expand_with(&s, "${s//[el]}"), Ok("ho".to_owned()));
+        assert_eq!(expand_with(&s, "${s^^}"), Ok("HELLO".to_owned()));
+        assert_eq!(expand_with(&s, "${s^}"), Ok("Hello".to_owned()));
+        assert_eq!(expand_with(&s, "${s^^[lo]}"), Ok("heLLO".to_owned()));
+        assert_eq!(expand_with(&[("s", "HELLO")], "${s,,}"), Ok("hello".to_owned()));
+    }
+
+    #[test]
+    fn unset_variables_are_empty_unless_nounset() {
+        assert_eq!(expand_with(&[], "a${unset}b$unset"), Ok("ab".to_owned()));
+
+        let mut conf: Config = Config::default();
+        conf.set_rule("nounset", "true", true);
+        let mut state: ShellState = ShellState::default();
+        assert_eq!(expanded(&mut state, &mut conf, "$unset"), Err("unset: unbound variable".to_owned()));
+        assert_eq!(expanded(&mut state, &mut conf, "${#unset}"), Err("unset: unbound variable".to_owned()));
+        assert_eq!(expanded(&mut state, &mut conf, "${unset:-ok}"), Ok("ok".to_owned()));
+    }
+
+    #[test]
+    fn expansion_errors_are_fatal() {
+        let mut state: ShellState = ShellState::default();
+        assert!(expanded(&mut state, &mut Config::default(), "${x:?}").is_err());
+        assert_eq!(state.control_flow, Some(ControlFlow::Exit(1)));
+
+        let mut conf: Config = Config::default();
+        conf.set_rule("nounset", "true", true);
+        let mut state: ShellState = ShellState::default();
+        assert!(expanded(&mut state, &mut conf, "$unset").is_err());
+        assert_eq!(state.control_flow, Some(ControlFlow::Exit(1)));
+        let mut state: ShellState = ShellState::default();
+        assert_eq!(expanded(&mut state, &mut conf, "${unset-ok}"), Ok("ok".to_owned()));
+        assert_eq!(state.control_flow, None);
+    }
+
+    fn braces(text: &str) -> Vec<String> {
+        match Lexer::new(text).next_token() {
+            Ok(Token::Word(word)) => expand_braces(&word).iter().map(|word| word.to_string()).collect(),
+            token => panic!("expected a word, got {:?}", token),
+        }
+    }
+
+    #[test]
+    fn brace_lists_and_sequences() {
+        assert_eq!(braces("src/{bin,lib,tests}"), ["src/bin", "src/lib", "src/tests"]);
+        assert_eq!(braces("a{b,c{1,2}}d"), ["abd", "ac1d", "ac2d"]);
+        assert_eq!(braces("{1..10..4}"), ["1", "5", "9"]);
+        assert_eq!(braces("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(braces("{01..03}"), ["01", "02", "03"]);
+        assert_eq!(braces("{a..e..2}"), ["a", "c", "e"]);
+        // Nothing to expand without a comma or a valid range, or when the braces are quoted.
+        assert_eq!(braces("{a}"), ["{a}"]);
+        assert_eq!(braces("{1..x}"), ["{1..x}"]);
+        assert_eq!(braces("'{a,b}'"), ["'{a,b}'"]);
+        assert_eq!(braces("{a,\"b c\"}"), ["a", "\"b c\""]);
+    }
+
+    #[test]
+    fn tilde_expands_only_at_the_start_of_a_word() {
+        let home: [(&str, &str); 3] = [("HOME", "/home/u"), ("PWD", "/work"), ("OLDPWD", "/old")];
+        assert_eq!(expand_with(&home, "~"), Ok("/home/u".to_owned()));
+        assert_eq!(expand_with(&home, "~/x/y"), Ok("/home/u/x/y".to_owned()));
+        assert_eq!(expand_with(&home, "~+/x"), Ok("/work/x".to_owned()));
+        assert_eq!(expand_with(&home, "~-"), Ok("/old".to_owned()));
+        assert_eq!(expand_with(&home, "~root"), Ok("/root".to_owned()));
+        for word in ["HEAD~3", "a~/b", "~nosuchuser_x/a"] {
+            assert_eq!(expand_with(&home, word), Ok(word.to_owned()));
+        }
+        assert_eq!(expand_with(&home, "'~'/x"), Ok("~/x".to_owned()));
+        assert_eq!(expand_with(&home, "\\~"), Ok("~".to_owned()));
+    }
+
+    #[test]
+    fn dots_are_left_alone() {
+        for word in ["example.com", "a.tar.gz", "./x/../y", ".."] {
+            assert_eq!(expand_with(&[], word), Ok(word.to_owned()));
+        }
+        assert_eq!(resolve_dots("/a/./b/../c"), PathBuf::from("/a/c"));
+        assert_eq!(resolve_dots("/.."), PathBuf::from("/"));
+    }
+
+    fn fields(state: &mut ShellState, text: &str) -> Vec<String> {
+        let word: Word = match Lexer::new(text).next_token() {
+            Ok(Token::Word(word)) => word,
+            token => panic!("expected a word, got {:?}", token),
+        };
+        expand_word_fields(state, &mut Config::default(), &mut JobControl::new(), &word).unwrap()
+    }
+
+    fn state_with(variables: &[(&str, &str)]) -> ShellState {
+        let mut state: ShellState = ShellState::default();
+        for (name, value) in variables {
+            state.set_var(name, value).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn unquoted_expansions_split_on_ifs() {
+        let mut state: ShellState = state_with(&[("x", " a  b\tc\n"), ("empty", "")]);
+        assert_eq!(fields(&mut state, "$x"), ["a", "b", "c"]);
+        assert_eq!(fields(&mut state, "\"$x\""), [" a  b\tc\n"]);
+        assert_eq!(fields(&mut state, "1${x}2"), ["1", "a", "b", "c", "2"]);
+        assert!(fields(&mut state, "$empty").is_empty());
+        assert_eq!(fields(&mut state, "\"\""), [""]);
+        assert_eq!(fields(&mut state, "$empty\"\""), [""]);
+
+        let mut state: ShellState = state_with(&[("IFS", ":"), ("x", "a::b:")]);
+        assert_eq!(fields(&mut state, "$x"), ["a", "", "b"]);
+        let mut state: ShellState = state_with(&[("IFS", " :"), ("x", " a : b  c ")]);
+        assert_eq!(fields(&mut state, "$x"), ["a", "b", "c"]);
+        let mut state: ShellState = state_with(&[("IFS", ""), ("x", "a b")]);
+        assert_eq!(fields(&mut state, "$x"), ["a b"]);
+        // Literal text is never split.
+        let mut state: ShellState = state_with(&[("IFS", "a")]);
+        assert_eq!(fields(&mut state, "banana"), ["banana"]);
+    }
+
+    #[test]
+    fn positional_parameters_in_quotes() {
+        let mut state: ShellState = ShellState::default();
+        assert!(fields(&mut state, "\"$@\"").is_empty());
+        assert_eq!(fields(&mut state, "\"$*\""), [""]);
+        state.positional = vec!["a b".to_owned(), "c".to_owned()];
+        assert_eq!(fields(&mut state, "\"$@\""), ["a b", "c"]);
+        assert_eq!(fields(&mut state, "\"x$@y\""), ["xa b", "cy"]);
+        assert_eq!(fields(&mut state, "$@"), ["a", "b", "c"]);
+        assert_eq!(fields(&mut state, "\"$*\""), ["a b c"]);
+        assert_eq!(fields(&mut state, "\"$#\"$1"), ["2a", "b"]);
+        state.set_var("IFS", ",").unwrap();
+        assert_eq!(fields(&mut state, "\"$*\""), ["a b,c"]);
+    }
+
+    #[test]
+    fn positional_slices_from_zero_start_with_the_shell_name() {
+        let mut state: ShellState = state_with(&[("0", "sh")]);
+        state.positional = vec!["a".to_owned(), "b".to_owned()];
+        assert_eq!(fields(&mut state, "\"${@:0}\""), ["sh", "a", "b"]);
+        assert_eq!(fields(&mut state, "\"${@:0:2}\""), ["sh", "a"]);
+        assert_eq!(fields(&mut state, "\"${@:1}\""), ["a", "b"]);
+        assert_eq!(fields(&mut state, "\"${@: -3}\""), ["sh", "a", "b"]);
+        assert_eq!(fields(&mut state, "\"${*:0}\""), ["sh a b"]);
+        assert_eq!(fields(&mut state, "${0}"), ["sh"]);
+    }
+
+    #[test]
+    fn alias_bodies_expand_like_typed_words() {
+        let mut state: ShellState = state_with(&[("x", "a b"), ("HOME", "/home/u")]);
+        let body: Result<Vec<String>, String> = expand_alias_body(&mut state, &mut Config::default(), &mut JobControl::new(), "al", "ls \"$x\" $x '$y' ~/z a~");
+        assert_eq!(body, Ok(["ls", "a b", "a", "b", "$y", "/home/u/z", "a~"].map(String::from).to_vec()));
+        assert!(expand_alias_body(&mut state, &mut Config::default(), &mut JobControl::new(), "al", "ls | less").is_err());
+    }
 }
@@ -2,11 +2,14 @@ use crate::globals::*;
 use crate::helpers::*;
 use crate::jobs::JobControl;
 use std::env;
-use std::{path::PathBuf, fs::{self, remove_file, File}, io::Error, collections::HashMap, process::{self, Stdio, Command}};
+use std::{path::PathBuf, fs::{self, remove_file, File}, io::Error, collections::{BTreeMap, HashMap}, process::{self, Stdio, Command}};
 use crate::arguments::*;
 use crate::config::*;
-use crate::jobs::JobStatus;
-use crate::command_parsing::expand;
+use crate::jobs::{give_terminal_to, signal_name, signal_number, status_text, SIGNALS};
+use crate::command_parsing::resolve_dots;
+use crate::parser::is_valid_name;
+use crate::conditional::test_command;
+use crate::arithmetic::evaluate;
 
 
 pub fn reset(conf: &mut Config, nash_dir: PathBuf) -> String
@@ -59,27 +62,367 @@ pub fn show_help() -> String {
      rmalias <identifier>: Remove an alias for a command\n\
      help: Display this help menu\n\
      set <<<option> <value>>/<flag>>: Set a config rule to true or value\n\
-     unset <option> <temp(bool)>: Unset a config rule (unimplemented)\n\
+     set -- [args...]: Replace the positional parameters ($1, $2, ...)\n\
+     set -b: Report finished background jobs at once instead of before the next prompt\n\
+     set huponexit true: Send SIGHUP to all jobs when the shell exits\n\
+     shift [n]: Drop the first n (default 1) positional parameters\n\
+     break [n] / continue [n]: Leave or restart the nth enclosing loop\n\
+     local [-aAilnrux] <name>[=value]: Declare a variable local to the running function\n\
+     return [n]: Return from a function with status n\n\
+     declare -f|-F [name]: Show function definitions or names\n\
+     declare|typeset [-aAgilnrux] [+ilnux] <name>[=value]: Declare variables and set or remove their attributes\n\
+     declare -p [name]: Show variables as declare commands\n\
+     readonly <name>[=value]: Make a variable readonly\n\
+     unset [-fvn] <name>: Remove a variable or function\n\
+     test <expression> / [ <expression> ]: Check files, strings and integers\n\
+     unsetconf <option> <temp(bool)>: Remove a config rule\n\
      reset: Reset the application, erase if delete_on_reset rule is true\n\
      rconf <option> [temp(bool)]: Read the value of a config rule (unimplemented)\n\
      settings: Display a simple config menu\n\
-     export <variable>=<value>: Export a variable to the environment\n\
+     export [-n] <variable>[=<value>]: Pass a variable on to child processes, or with -n stop\n\
+     jobs [-lp] [%job...]: List jobs, with -l each of their processes, with -p only their process group ids\n\
+     fg [%job] / bg [%job]: Continue a stopped job in the foreground or the background\n\
+     kill [-s SIG | -SIG] <pid|%job>: Send a signal to a process or job, kill -l lists signals\n\
+     wait [-n] [pid|%job...]: Wait for jobs to finish and return the exit status of the last\n\
+     disown [-h] [%job]: Remove a job from the job list, with -h keep it but don't send it SIGHUP\n\
+     suspend [-f]: Stop the shell until it is continued\n\
      setprompt <format>: Set the PS1 prompt format".to_owned()
 }
 
+pub fn handle_shift(state: &mut ShellState, cmd_parts: &[String]) -> Result<String, String> {
+    let count: usize = match cmd_parts.get(1) {
+        Some(arg) => arg.parse::<usize>().map_err(|_| format!("shift: {}: numeric argument required", arg))?,
+        None => 1,
+    };
+    if count > state.positional.len() {
+        return Err(format!("shift: {}: shift count out of range", count));
+    }
+    state.positional.drain(..count);
+    Ok(NO_RESULT.to_owned())
+}
+
+/// break [n] and continue [n]. The jump itself happens as the enclosing loops unwind.
+pub fn handle_loop_control(state: &mut ShellState, cmd_parts: &[String]) -> Result<String, String> {
+    let levels: usize = match cmd_parts.get(1) {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(levels) if levels > 0 => levels,
+            _ => return Err(format!("{}: {}: loop count out of range", cmd_parts[0], arg)),
+        },
+        None => 1,
+    };
+    if state.loop_depth == 0 {
+        return Err(format!("{}: only meaningful in a 'for', 'while', or 'until' loop", cmd_parts[0]));
+    }
+    let levels: usize = levels.min(state.loop_depth);
+    state.control_flow = Some(if cmd_parts[0] == "break" { ControlFlow::Break(levels) } else { ControlFlow::Continue(levels) });
+    Ok(NO_RESULT.to_owned())
+}
+
+pub fn handle_return(state: &mut ShellState, cmd_parts: &[String]) -> Result<String, String> {
+    if state.call_stack.is_empty() {
+        return Err("return: can only 'return' from a function".to_owned());
+    }
+    let code: i32 = match cmd_parts.get(1) {
+        Some(arg) => arg.parse::<i32>().map_err(|_| format!("return: {}: numeric argument required", arg))?,
+        None => state.last_status,
+    };
+    state.control_flow = Some(ControlFlow::Return(code));
+    Ok(NO_RESULT.to_owned())
+}
+
+/// local [-aAilnrux] name[=value] ...: declares variables in the frame of the running function.
+pub fn handle_local(state: &mut ShellState, cmd_parts: &[String]) -> Result<String, String> {
+    if state.call_stack.is_empty() {
+        return Err("local: can only be used in a function".to_owned());
+    }
+    let (set, unset, args): (String, String, &[String]) = declaration_options(cmd_parts, "aAilnrux")?;
+    declare_variables(state, &cmd_parts[0], &set, &unset, args, true)
+}
+
+/// declare -f [name...] prints function definitions, declare -F just their names.
+/// Otherwise declare [-aAgilnrux] name[=value] ... declares variables with those attributes, which +x and the like
+/// take away again. In a function they are local unless -g is given. -p, or no names, prints variables instead.
+pub fn handle_declare(state: &mut ShellState, cmd_parts: &[String]) -> Result<String, String> {
+    let (set, unset, args): (String, String, &[String]) = declaration_options(cmd_parts, "aAfFgilnprux")?;
+    if !set.contains(['f', 'F']) {
+        if set.contains('p') || args.is_empty() {
+            return print_variables(state, &cmd_parts[0], &set, args);
+        }
+        let local: bool = !state.call_stack.is_empty() && !set.contains('g');
+        return declare_variables(state, &cmd_parts[0], &set, &unset, args, local);
+    }
+    let names_only: bool = set.contains('F');
+
+    let mut names: Vec<&String> = if !args.is_empty() {
+        args.iter().collect()
+    } else {
+        state.functions.keys().collect()
+    };
+    names.sort();
+
+    let mut output: Vec<String> = Vec::new();
+    for name in names {
+        match state.functions.get(name) {
+            Some(_) if names_only => output.push(format!("declare -f {}", name)),
+            Some(function) => output.push(function.to_string()),
+            None => return Err(format!("{}: {}: not found", cmd_parts[0], name)),
+        }
+    }
+    Ok(output.join("\n"))
+}
+
+/// export name[=value] ...: marks variables to be passed to child processes, or with -n no longer.
+pub fn handle_export(state: &mut ShellState, cmd_parts: &[String]) -> Result<String, String> {
+    let (set, _, args): (String, String, &[String]) = declaration_options(cmd_parts, "np")?;
+    if args.is_empty() {
+        return print_variables(state, &cmd_parts[0], "x", args);
+    }
+    match set.contains('n') {
+        true => declare_variables(state, &cmd_parts[0], "", "x", args, false),
+        false => declare_variables(state, &cmd_parts[0], "x", "", args, false),
+    }
+}
+
+/// readonly [-aA] name[=value] ...: makes variables readonly, or with no names lists those that are.
+pub fn handle_readonly(state: &mut ShellState, cmd_parts: &[String]) -> Result<String, String> {
+    let (set, _, args): (String, String, &[String]) = declaration_options(cmd_parts, "aAp")?;
+    if args.is_empty() {
+        return print_variables(state, &cmd_parts[0], "r", args);
+    }
+    declare_variables(state, &cmd_parts[0], &format!("r{}", set), "", args, false)
+}
+
+/// Splits the leading -x and +x options off a declaration builtin's arguments, allowing only the flags in `allowed`.
+/// Returns the flags turned on, those turned off and the remaining arguments.
+fn declaration_options<'a>(cmd_parts: &'a [String], allowed: &str) -> Result<(String, String, &'a [String]), String> {
+    let mut set: String = String::new();
+    let mut unset: String = String::new();
+    let mut args: &[String] = &cmd_parts[1..];
+    while let Some(arg) = args.first().filter(|arg| arg.len() > 1 && arg.starts_with(['-', '+'])) {
+        args = &args[1..];
+        if arg == "--" {
+            break;
+        }
+        for flag in arg[1..].chars() {
+            if !allowed.contains(flag) {
+                return Err(format!("{}: {}{}: invalid option", cmd_parts[0], &arg[..1], flag));
+            }
+            if arg.starts_with('-') { set.push(flag) } else { unset.push(flag) }
+        }
+    }
+    Ok((set, unset, args))
+}
+
+/// Declares each name[=value] in `args`, giving it the attributes in `set` and taking away those in `unset`.
+/// With `local` the variables are made in the running function's frame; otherwise they are changed where they are.
+fn declare_variables(state: &mut ShellState, builtin: &str, set: &str, unset: &str, args: &[String], local: bool) -> Result<String, String> {
+    let associative: bool = set.contains('A');
+    let indexed: bool = set.contains('a') && !associative;
+    // -n and +n change the nameref itself rather than the variable it refers to.
+    let nameref: bool = set.contains('n') || unset.contains('n');
+
+    for arg in args {
+        let (name, value): (&str, Option<&str>) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !is_valid_name(name) {
+            return Err(format!("{}: '{}': not a valid identifier", builtin, arg));
+        }
+        let name: String = if nameref || local { name.to_owned() } else { state.resolve_name(name) };
+
+        let current: Option<Variable> = match state.call_stack.last() {
+            Some(frame) if local => frame.locals.get(&name).cloned(),
+            _ => state.lookup(&name).cloned(),
+        };
+        let mut variable: Variable = current.unwrap_or_default();
+        if variable.attributes.readonly && (value.is_some() || unset.contains('r')) {
+            return Err(format!("{}: {}: readonly variable", builtin, name));
+        }
+        if builtin == "export" && set.contains('x') && matches!(variable.value, Some(Value::Indexed(_) | Value::Associative(_))) {
+            return Err(format!("{}: {}: arrays cannot be exported", builtin, name));
+        }
+        variable.value = match variable.value.take() {
+            Some(Value::Indexed(_)) if associative => return Err(format!("{}: {}: cannot convert an indexed array to an associative array", builtin, name)),
+            Some(Value::Associative(_)) if indexed => return Err(format!("{}: {}: cannot convert an associative array to an indexed array", builtin, name)),
+            Some(Value::Scalar(old)) if associative => Some(Value::Associative(BTreeMap::from([("0".to_owned(), old)]))),
+            Some(Value::Scalar(old)) if indexed => Some(Value::Indexed(BTreeMap::from([(0, old)]))),
+            None if associative => Some(Value::Associative(BTreeMap::new())),
+            None if indexed => Some(Value::Indexed(BTreeMap::new())),
+            current => current,
+        };
+        set_attributes(&mut variable.attributes, set, true);
+        set_attributes(&mut variable.attributes, unset, false);
+
+        // Readonly only takes effect once the value given with it is assigned.
+        let readonly: bool = std::mem::take(&mut variable.attributes.readonly);
+        let is_nameref: bool = variable.attributes.nameref;
+        *state.declare(&name, local) = variable;
+        match value {
+            Some(value) if is_nameref => state.declare(&name, local).value = Some(Value::Scalar(value.to_owned())),
+            Some(value) => state.set_var(&name, value).map_err(|e| format!("{}: {}", builtin, e))?,
+            None => {}
+        }
+        state.declare(&name, local).attributes.readonly = readonly;
+    }
+    Ok(NO_RESULT.to_owned())
+}
+
+fn set_attributes(attributes: &mut Attributes, flags: &str, on: bool) {
+    for flag in flags.chars() {
+        match flag {
+            'x' => attributes.exported = on,
+            'r' => attributes.readonly = on,
+            'i' => attributes.integer = on,
+            'n' => attributes.nameref = on,
+            'l' => {
+                attributes.lowercase = on;
+                attributes.uppercase &= !on;
+            }
+            'u' => {
+                attributes.uppercase = on;
+                attributes.lowercase &= !on;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The attribute flags of a variable as declare shows them, such as "ix" for an exported integer.
+fn attribute_flags(variable: &Variable) -> String {
+    let attributes: &Attributes = &variable.attributes;
+    [
+        ('a', matches!(variable.value, Some(Value::Indexed(_)))),
+        ('A', matches!(variable.value, Some(Value::Associative(_)))),
+        ('i', attributes.integer),
+        ('l', attributes.lowercase),
+        ('n', attributes.nameref),
+        ('r', attributes.readonly),
+        ('u', attributes.uppercase),
+        ('x', attributes.exported),
+    ]
+    .iter()
+    .filter(|(_, on)| *on)
+    .map(|(flag, _)| *flag)
+    .collect()
+}
+
+/// A variable as declare -p shows it: a declare command that recreates it.
+fn describe_variable(name: &str, variable: &Variable) -> String {
+    let flags: String = match attribute_flags(variable) {
+        flags if flags.is_empty() => "--".to_owned(),
+        flags => format!("-{}", flags),
+    };
+    let value: String = match &variable.value {
+        None => String::new(),
+        Some(Value::Scalar(value)) => format!("={}", double_quote(value)),
+        Some(array) => {
+            let elements: Vec<String> = array.keys().iter().zip(array.values()).map(|(key, element)| format!("[{}]={}", key, double_quote(&element))).collect();
+            format!("=({})", elements.join(" "))
+        }
+    };
+    format!("declare {} {}{}", flags, name, value)
+}
+
+fn double_quote(value: &str) -> String {
+    let mut quoted: String = String::from('"');
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// declare -p: describes the named variables, or every variable having all the attributes in `flags`.
+fn print_variables(state: &ShellState, builtin: &str, flags: &str, names: &[String]) -> Result<String, String> {
+    let mut output: Vec<String> = Vec::new();
+    if names.is_empty() {
+        let wanted: Vec<char> = flags.chars().filter(|flag| "aAilnrux".contains(*flag)).collect();
+        // $0 is kept with the variables but is a special parameter, not one to list
+        for (name, variable) in state.visible_variables().into_iter().filter(|(name, _)| is_valid_name(name)) {
+            let has: String = attribute_flags(variable);
+            if wanted.iter().all(|flag| has.contains(*flag)) {
+                output.push(describe_variable(name, variable));
+            }
+        }
+    }
+    for name in names {
+        match state.lookup(name) {
+            Some(variable) => output.push(describe_variable(name, variable)),
+            None => return Err(format!("{}: {}: not found", builtin, name)),
+        }
+    }
+    Ok(output.join("\n"))
+}
+
+/// unset [-fvn] name ...: removes variables, or with -f functions. A name that is no variable is tried as a
+/// function unless -v is given. name[subscript] removes a single array element.
+pub fn handle_unset(state: &mut ShellState, cmd_parts: &[String]) -> Result<String, String> {
+    let (flags, _, args): (String, String, &[String]) = declaration_options(cmd_parts, "fvn")?;
+    for arg in args {
+        if flags.contains('f') {
+            state.functions.remove(arg);
+            continue;
+        }
+        if let Some((name, subscript)) = arg.strip_suffix(']').and_then(|arg| arg.split_once('[')) {
+            let key: String = match state.is_associative(name) {
+                true => subscript.to_owned(),
+                false => {
+                    let index: i64 = evaluate(state, subscript).map_err(|e| format!("unset: {}", e))?;
+                    let index: i64 = if index < 0 { index + state.next_index(name) as i64 } else { index };
+                    if index < 0 {
+                        return Err(format!("unset: {}: bad array subscript", arg));
+                    }
+                    index.to_string()
+                }
+            };
+            state.unset_element(name, &key).map_err(|e| format!("unset: {}", e))?;
+            continue;
+        }
+        if !is_valid_name(arg) {
+            return Err(format!("unset: '{}': not a valid identifier", arg));
+        }
+        if !flags.contains('v') && state.lookup(arg).is_none() {
+            state.functions.remove(arg);
+            continue;
+        }
+        state.unset_var(arg, flags.contains('n')).map_err(|e| format!("unset: {}", e))?;
+    }
+    Ok(NO_RESULT.to_owned())
+}
+
+/// test expression and [ expression ]. A false expression fails without a message.
+pub fn handle_test(state: &ShellState, cmd_parts: &[String]) -> Result<String, String> {
+    let mut args: &[String] = &cmd_parts[1..];
+    if cmd_parts[0] == "[" {
+        match args.split_last() {
+            Some((last, rest)) if last == "]" => args = rest,
+            _ => return Err("[: missing ']'".to_owned()),
+        }
+    }
+    match test_command(state, args) {
+        Ok(true) => Ok(NO_RESULT.to_owned()),
+        Ok(false) => Err(String::new()),
+        Err(e) => Err(format!("{}: {}", cmd_parts[0], e)),
+    }
+}
+
 #[allow(unused_variables)]
-pub fn test_nash(conf: &mut Config, state: &mut ShellState, job_control: &mut JobControl, cmd: &[String]) -> String
+pub fn test_nash(conf: &mut Config, state: &mut ShellState, job_control: &mut JobControl, cmd: &[String]) -> Result<String, String>
 {
-    "TEST isn't implemented or documented. Why did you run it?".to_string()
+    Err("TEST isn't implemented or documented. Why did you run it?".to_string())
 }
 
-pub fn handle_settings(conf: &mut Config, cmd_parts: &[String]) -> String {
+pub fn handle_settings(conf: &mut Config, cmd_parts: &[String]) -> Result<String, String> {
     let (_, flag_args) = parse_args(cmd_parts);
     let temp: bool = flag_args.contains_key("temp") || flag_args.contains_key("t");
     let settings: Vec<GUIEntry> = vec![
-        GUIEntry::new("error", "bool", &conf.get_rule("error", false).unwrap_or("false")),
-        GUIEntry::new("hist_size", "int", &conf.get_rule("hist_size", false).unwrap_or("500")),
-        GUIEntry::new("delete_on_reset", "bool", &conf.get_rule("delete_on_reset", false).unwrap_or("false")),
+        GUIEntry::new("error", "bool", conf.get_rule("error", false).unwrap_or("false")),
+        GUIEntry::new("hist_size", "int", conf.get_rule("hist_size", false).unwrap_or("500")),
+        GUIEntry::new("delete_on_reset", "bool", conf.get_rule("delete_on_reset", false).unwrap_or("false")),
     ];
 
     let mut menu: GUIMenu = GUIMenu::new("Nash settings".to_string(), settings);
@@ -93,18 +436,18 @@ pub fn handle_settings(conf: &mut Config, cmd_parts: &[String]) -> String {
             {
                 conf.save_rules();
             }
-            "Settings updated successfully".to_owned()
+            Ok("Settings updated successfully".to_owned())
         }
-        Err(e) => format!("Error in settings menu: {}", e),
+        Err(e) => Err(format!("Error in settings menu: {}", e)),
     }
 }
-pub fn handle_summon(cmd_parts: &[String]) -> String {
+pub fn handle_summon(cmd_parts: &[String]) -> Result<String, String> {
     let (main_args, flag_args) = parse_summon_args(cmd_parts);
     let wait_for_exit: bool = flag_args.contains_key("w");
     let mut output = String::new();
     
     if main_args.is_empty() {
-        return "Usage: summon [-w] <command>".to_owned();
+        return Err("Usage: summon [-w] <command>".to_owned());
     }
 
     let executable: &String = &main_args[0];
@@ -130,11 +473,11 @@ pub fn handle_summon(cmd_parts: &[String]) -> String {
 
     // No terminal :(
     if installed_terminals.is_empty() {
-        return "Unable to find a suitable terminal emulator".to_owned();
+        return Err("Unable to find a suitable terminal emulator".to_owned());
     }
 
     // Use the first installed terminal in the list
-    let terminal: &str = &installed_terminals[0];
+    let terminal: &str = installed_terminals[0];
     output.push_str(&format!("Using terminal: {}\n", terminal));
 
     let result: Result<process::Child, Error> = match terminal {
@@ -143,7 +486,7 @@ pub fn handle_summon(cmd_parts: &[String]) -> String {
             cmd.extend(main_args.iter().map(|s| s.as_str()));
             output.push_str(&format!("Executing: {} -- {} {:?}\n", terminal, cmd.join(" "), cmd));
             Command::new(terminal)
-                .args(&["--"])
+                .args(["--"])
                 .args(&cmd)
                 .stdin(Stdio::null())
                 .stdout(Stdio::null())
@@ -187,19 +530,19 @@ pub fn handle_summon(cmd_parts: &[String]) -> String {
             if wait_for_exit {
                 match child.wait() {
                     Ok(status) => output.push_str(&format!("Process exited with status: {}\n", status)),
-                    Err(e) => output.push_str(&format!("Error waiting for process: {}\n", e)),
+                    Err(e) => return Err(format!("{}Error waiting for process: {}", output, e)),
                 }
             } else {
                 output.push_str(&format!("Process started with PID: {}\n", child.id()))
             }
         },
-        Err(e) => output.push_str(&format!("An error occurred: {} (Command: {})\n", e, executable)),
+        Err(e) => return Err(format!("{}An error occurred: {} (Command: {})", output, e, executable)),
     }
 
-    output
+    Ok(output)
 }
 
-pub fn handle_history(cmd: &[String]) -> String {
+pub fn handle_history(cmd: &[String]) -> Result<String, String> {
     let (_, flag_args) = parse_args(cmd);
     let size: bool = flag_args.contains_key("size") || flag_args.contains_key("s");
     let clear: bool = flag_args.contains_key("clear") || flag_args.contains_key("c");
@@ -213,7 +556,7 @@ pub fn handle_history(cmd: &[String]) -> String {
                     output.push_str(&format!("{}: {}\n", i + 1, line));
                 }
             }
-            Err(e) => output.push_str(&format!("Failed to read history: {}\n", e)),
+            Err(e) => return Err(format!("Failed to read history: {}", e)),
         }
     } else {
         if size {
@@ -222,49 +565,50 @@ pub fn handle_history(cmd: &[String]) -> String {
         if clear {
             match File::create(get_history_file_path()) {
                 Ok(_) => output.push_str("Successfully cleared history\n"),
-                Err(e) => output.push_str(&format!("Could not clear history. Received error: {}\n", e))
+                Err(e) => return Err(format!("{}Could not clear history. Received error: {}", output, e))
             };
         }
     }
-    output
+    Ok(output)
 }
 
-pub fn handle_alias(state: &mut ShellState, cmd_parts: &[String]) -> String {
+pub fn handle_alias(cmd_parts: &[String]) -> Result<String, String> {
     let alias_file_path: PathBuf = get_alias_file_path();
     let mut aliases: HashMap<String, String> = load_aliases(&alias_file_path);
 
     if cmd_parts.len() == 1 {
         // List all aliases
         if aliases.is_empty() {
-            return "No aliases defined.".to_owned();
+            return Ok("No aliases defined.".to_owned());
         }
-        return aliases.iter()
+        Ok(aliases.iter()
             .map(|(k, v)| format!("alias {}='{}'", k, v))
             .collect::<Vec<String>>()
-            .join("\n");
+            .join("\n"))
     } else {
         let alias_str: String = cmd_parts[1..].join(" ");
         if let Some(pos) = alias_str.find('=') {
             let (name, command) = alias_str.split_at(pos);
             let name: &str = name.trim();
-            let command: &str = &expand(state, command[1..].trim().trim_matches('\'').trim_matches('"'));
+            // The body is kept as given, and expanded each time the alias is used
+            let command: &str = command[1..].trim();
             aliases.insert(name.to_string(), command.to_string());
             save_aliases(&alias_file_path, &aliases);
-            return format!("Alias '{}' created.", name);
+            Ok(format!("Alias '{}' created.", name))
         } else {
             // If no '=' is found, treat it as a query for a specific alias
             if let Some(command) = aliases.get(&alias_str) {
-                return format!("alias {}='{}'", alias_str, command);
+                Ok(format!("alias {}='{}'", alias_str, command))
             } else {
-                return format!("Alias '{}' not found.", alias_str);
+                Err(format!("Alias '{}' not found.", alias_str))
             }
         }
     }
 }
 
-pub fn handle_remove_alias(cmd_parts: &[String]) -> String {
+pub fn handle_remove_alias(cmd_parts: &[String]) -> Result<String, String> {
     if cmd_parts.len() != 2 {
-        return "Usage: rmalias <alias_name>".to_owned();
+        return Err("Usage: rmalias <alias_name>".to_owned());
     }
 
     let alias_name: &String = &cmd_parts[1];
@@ -273,124 +617,300 @@ pub fn handle_remove_alias(cmd_parts: &[String]) -> String {
 
     if aliases.remove(alias_name).is_some() {
         save_aliases(&alias_file_path, &aliases);
-        format!("Alias '{}' removed.", alias_name)
+        Ok(format!("Alias '{}' removed.", alias_name))
     } else {
-        format!("Alias '{}' not found.", alias_name)
+        Err(format!("Alias '{}' not found.", alias_name))
     }
 }
 
-pub fn handle_cd(cmd_parts: &[String]) -> String {
+pub fn handle_cd(state: &mut ShellState, cmd_parts: &[String]) -> Result<String, String> {
     match cmd_parts.len() {
         1 => {
-            "No directory passed. Usage: cd <directory>".to_owned()
+            Err("No directory passed. Usage: cd <directory>".to_owned())
         }
         2 => {
-            let new_path: PathBuf = if cmd_parts[1].starts_with('/') {
-                PathBuf::from(&cmd_parts[1])
-            } else {
-                env::current_dir().unwrap_or(PathBuf::from("/")).join(&cmd_parts[1])
-            };
+            let new_path: PathBuf = resolve_dots(&cmd_parts[1]);
 
             if new_path.is_dir() {
-                // Canonicalize the path to resolve any ".." or "." components
-                match new_path.canonicalize() {
-                    Ok(canonical_path) => {
-                        match env::set_current_dir(canonical_path.to_string_lossy().into_owned())
-                        {
-                            Ok(_) => NO_RESULT.to_owned(),
-                            Err(e) => return format!("Error setting cwd: {}", e)
-                        };
-                        NO_RESULT.to_owned()
+                let old_path: PathBuf = env::current_dir().unwrap_or(PathBuf::from("/"));
+                match env::set_current_dir(&new_path)
+                {
+                    Ok(_) => {
+                        // Kept up to date for ~+ and ~-
+                        state.set_var("OLDPWD", &old_path.to_string_lossy()).map_err(|e| format!("cd: {}", e))?;
+                        state.set_var("PWD", &new_path.to_string_lossy()).map_err(|e| format!("cd: {}", e))?;
+                        Ok(NO_RESULT.to_owned())
                     }
-                    Err(e) => format!("Error resolving path: {}", e),
+                    Err(e) => Err(format!("Error setting cwd: {}", e))
                 }
             } else {
-                format!("Directory not found: {}", new_path.display())
+                Err(format!("Directory not found: {}", new_path.display()))
             }
         }
-        _ => "Usage: cd [path]".to_owned(),
+        _ => Err("Usage: cd [path]".to_owned()),
     }
 }
 
-pub fn handle_fg(cmd: &[String], job_control: &mut JobControl) -> String {
+pub fn handle_fg(cmd: &[String], job_control: &mut JobControl) -> Result<String, String> {
     let (main_args, _) = parse_args(cmd);
+    if !job_control.monitor {
+        return Err("fg: no job control".to_owned());
+    }
     
     let job: libc::pid_t = if main_args.is_empty() {
         match job_control.get_current_job() {
-            Some(job) => job.pid,
-            None => return "No current job".to_string(),
+            Some(job) => job.pgid,
+            None => return Err("No current job".to_string()),
         }
     } else {
-        match parse_job_specifier(&main_args[0], job_control) {
-            Ok(pid) => pid,
-            Err(e) => return e,
-        }
+        parse_job_specifier(&main_args[0], job_control)?
     };
 
     match job_control.resume_job(job, true) {
-        Ok(_) => NO_RESULT.to_string(),
-        Err(e) => format!("Could not bring job to foreground: {}", e),
+        Ok(_) => Ok(NO_RESULT.to_string()),
+        Err(e) => Err(format!("Could not bring job to foreground: {}", e)),
     }
 }
 
-pub fn handle_bg(cmd: &[String], job_control: &mut JobControl) -> String {
+pub fn handle_bg(cmd: &[String], job_control: &mut JobControl) -> Result<String, String> {
     let (main_args, _) = parse_args(cmd);
+    if !job_control.monitor {
+        return Err("bg: no job control".to_owned());
+    }
     
     let job: i32 = if main_args.is_empty() {
         match job_control.get_current_job() {
-            Some(job) => job.pid,
-            None => return "No current job".to_string(),
+            Some(job) => job.pgid,
+            None => return Err("No current job".to_string()),
         }
     } else {
-        match parse_job_specifier(&main_args[0], job_control) {
-            Ok(pid) => pid,
-            Err(e) => return e,
-        }
+        parse_job_specifier(&main_args[0], job_control)?
     };
 
     match job_control.resume_job(job, false) {
-        Ok(_) => NO_RESULT.to_string(),
-        Err(e) => format!("Could not continue job in background: {}", e),
+        Ok(_) => Ok(NO_RESULT.to_string()),
+        Err(e) => Err(format!("Could not continue job in background: {}", e)),
     }
 }
 
-/// Handle the 'jobs' command
-pub fn handle_jobs(job_control: &mut JobControl) -> String {
-    // Get list of all jobs
-    let jobs: Vec<&crate::jobs::Job> = job_control.list_jobs();
-    
-    if jobs.is_empty() {
-        return "No jobs".to_string();
+/// Handle the 'jobs' command: -l adds every process of a job with its pid and state, -p prints only process group ids.
+pub fn handle_jobs(cmd: &[String], job_control: &mut JobControl) -> Result<String, String> {
+    let (main_args, options) = parse_args(cmd);
+    job_control.reap_jobs();
+
+    let mut pgids: Vec<libc::pid_t> = Vec::new();
+    for spec in &main_args {
+        pgids.push(parse_job_specifier(spec, job_control)?);
+    }
+    let jobs: Vec<&crate::jobs::Job> = job_control
+        .list_jobs()
+        .into_iter()
+        .filter(|job| pgids.is_empty() || pgids.contains(&job.pgid))
+        .collect();
+
+    if jobs.is_empty() && main_args.is_empty() && options.is_empty() {
+        return Ok("No jobs".to_string());
     }
 
     let mut output: String = String::new();
-    
-    // Format job listing
     for job in jobs {
-        let current_marker: &str = if Some(job.pid) == job_control.get_current_job().map(|j| j.pid) {
-            "+"
+        if options.contains_key("p") {
+            output.push_str(&format!("{}\n", job.pgid));
+        } else if options.contains_key("l") {
+            let marker: &str = job_control.marker(job);
+            for (i, process) in job.processes.iter().enumerate() {
+                let prefix: String = if i == 0 { format!("[{}]{}", job.id, marker) } else { String::new() };
+                let pipe: &str = if i == 0 { "" } else { "| " };
+                output.push_str(&format!(
+                    "{:<5}{:>7} {:<24}{}{}\n",
+                    prefix, process.pid, status_text(&process.status, process.exit_code), pipe, process.command
+                ));
+            }
         } else {
-            "-"
+            output.push_str(&format!("{}\n", job_control.describe_job(job)));
+        }
+    }
+
+    // Every change has been shown here, so there is nothing left to announce.
+    job_control.take_notices();
+    Ok(output)
+}
+
+/// kill [-s SIG | -n NUM | -SIG] pid|%job ...: sends a signal (TERM by default) to processes or whole jobs.
+/// kill -l [SIG ...] lists the signals, or translates between their names and numbers.
+pub fn handle_kill(cmd: &[String], job_control: &mut JobControl) -> Result<String, String> {
+    let usage: String = "kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ... or kill -l [sigspec]".to_owned();
+    let mut args: &[String] = &cmd[1..];
+    let mut signal: i32 = libc::SIGTERM;
+
+    match args.first().map(String::as_str) {
+        Some("-l") | Some("-L") => return list_signals(&args[1..]),
+        Some("-s") | Some("-n") => {
+            let spec: &String = args.get(1).ok_or_else(|| usage.clone())?;
+            signal = signal_number(spec).ok_or_else(|| format!("kill: {}: invalid signal specification", spec))?;
+            args = &args[2..];
+        }
+        Some("--") => args = &args[1..],
+        Some(arg) if arg.len() > 1 && arg.starts_with('-') => {
+            signal = signal_number(&arg[1..]).ok_or_else(|| format!("kill: {}: invalid signal specification", &arg[1..]))?;
+            args = &args[1..];
+            if args.first().is_some_and(|arg| arg == "--") {
+                args = &args[1..];
+            }
+        }
+        _ => {}
+    }
+    if args.is_empty() {
+        return Err(usage);
+    }
+
+    let mut errors: Vec<String> = Vec::new();
+    for target in args {
+        let result: Result<(), String> = if target.starts_with('%') {
+            parse_job_specifier(target, job_control)
+                .and_then(|pgid| job_control.kill_job(pgid, signal).map_err(|e| format!("({}) - {}", pgid, e)))
+        } else {
+            match target.parse::<libc::pid_t>() {
+                Ok(pid) if unsafe { libc::kill(pid, signal) } == -1 => Err(format!("({}) - {}", pid, Error::last_os_error())),
+                Ok(_) => Ok(()),
+                Err(_) => Err(format!("{}: arguments must be process or job IDs", target)),
+            }
+        };
+        if let Err(e) = result {
+            errors.push(format!("kill: {}", e));
+        }
+    }
+    if errors.is_empty() { Ok(NO_RESULT.to_owned()) } else { Err(errors.join("\n")) }
+}
+
+/// kill -l: every signal as "n) SIGNAME", or the name of each number given and the number of each name.
+fn list_signals(specs: &[String]) -> Result<String, String> {
+    if specs.is_empty() {
+        let entries: Vec<String> = SIGNALS.iter().map(|(name, number)| format!("{:>2}) SIG{:<8}", number, name)).collect();
+        return Ok(entries.chunks(5).map(|row| row.join("\t").trim_end().to_owned()).collect::<Vec<String>>().join("\n"));
+    }
+
+    let mut output: Vec<String> = Vec::new();
+    for spec in specs {
+        match spec.parse::<i32>() {
+            // Exit statuses of killed processes are 128 + the signal
+            Ok(number) => {
+                let number: i32 = if number > 128 { number - 128 } else { number };
+                output.push(signal_name(number).ok_or_else(|| format!("kill: {}: invalid signal specification", spec))?.to_owned());
+            }
+            Err(_) => {
+                let number: i32 = signal_number(spec).ok_or_else(|| format!("kill: {}: invalid signal specification", spec))?;
+                output.push(number.to_string());
+            }
+        }
+    }
+    Ok(output.join("\n"))
+}
+
+/// wait [-n] [pid|%job ...]: waits for the given jobs, or all of them, and sets the exit status of the last one.
+/// With -n it returns as soon as any one of them finishes.
+pub fn handle_wait(state: &mut ShellState, cmd: &[String], job_control: &mut JobControl) -> Result<String, String> {
+    let (main_args, options) = parse_args(cmd);
+    job_control.reap_jobs();
+
+    if options.contains_key("n") {
+        let mut pgids: Vec<libc::pid_t> = Vec::new();
+        for spec in &main_args {
+            pgids.push(parse_job_specifier(spec, job_control).map_err(|e| format!("wait: {}", e))?);
+        }
+        if main_args.is_empty() {
+            pgids = job_control.list_jobs().iter().map(|job| job.pgid).collect();
+        }
+        let code: i32 = match job_control.wait_for_any(&pgids).map_err(|e| format!("wait: {}", e))? {
+            Some((_, code)) => code,
+            None => 127,
         };
+        state.builtin_status = Some(code);
+        return Ok(NO_RESULT.to_owned());
+    }
 
-        let status_str: &str = match job.status {
-            JobStatus::Running => "Running",
-            JobStatus::Stopped => "Stopped",
-            JobStatus::Done => "Done",
+    if main_args.is_empty() {
+        job_control.wait_for_all().map_err(|e| format!("wait: {}", e))?;
+        return Ok(NO_RESULT.to_owned());
+    }
+
+    let mut errors: Vec<String> = Vec::new();
+    let mut code: i32 = 0;
+    for spec in &main_args {
+        let pid: libc::pid_t = match parse_job_specifier(spec, job_control) {
+            Ok(pid) => pid,
+            Err(e) => {
+                errors.push(format!("wait: {}", e));
+                code = 127;
+                continue;
+            }
+        };
+        // A job spec waits for the whole job, a pid for that one process
+        let result: std::io::Result<Option<i32>> = if spec.starts_with('%') {
+            job_control.wait_for_job_exit(pid).map(Some)
+        } else {
+            job_control.wait_for_pid(pid)
         };
+        match result {
+            Ok(Some(status)) => code = status,
+            Ok(None) => {
+                errors.push(format!("wait: pid {} is not a child of this shell", pid));
+                code = 127;
+            }
+            Err(e) => {
+                errors.push(format!("wait: {}", e));
+                code = 1;
+            }
+        }
+    }
+    state.builtin_status = Some(code);
+    if errors.is_empty() { Ok(NO_RESULT.to_owned()) } else { Err(errors.join("\n")) }
+}
 
-        output.push_str(&format!(
-            "[{}]{} {} {}: {}\n",
-            job.pid, current_marker, status_str, job.pid, job.command
-        ));
+/// disown [-h] [%job ...]: drops jobs (the current one by default) from the job list, or with -h
+/// keeps them but spares them the SIGHUP sent when the shell is hung up on.
+pub fn handle_disown(cmd: &[String], job_control: &mut JobControl) -> Result<String, String> {
+    let (main_args, options) = parse_args(cmd);
+    let mut pgids: Vec<libc::pid_t> = Vec::new();
+    if main_args.is_empty() {
+        match job_control.get_current_job() {
+            Some(job) => pgids.push(job.pgid),
+            None => return Err("disown: current: no such job".to_owned()),
+        }
+    }
+    for spec in &main_args {
+        let pgid: libc::pid_t = parse_job_specifier(spec, job_control).map_err(|e| format!("disown: {}", e))?;
+        if job_control.find_job(pgid).is_none() {
+            return Err(format!("disown: {}: no such job", spec));
+        }
+        pgids.push(pgid);
     }
 
-    output
+    for pgid in pgids {
+        job_control.disown(pgid, options.contains_key("h"));
+    }
+    Ok(NO_RESULT.to_owned())
+}
+
+/// suspend [-f]: stops the shell until it gets SIGCONT. A login shell is only stopped with -f,
+/// since there may be nothing left to continue it.
+pub fn handle_suspend(cmd: &[String]) -> Result<String, String> {
+    let (_, options) = parse_args(cmd);
+    let pid: libc::pid_t = unsafe { libc::getpid() };
+    if !options.contains_key("f") && unsafe { libc::getsid(0) } == pid {
+        return Err("suspend: cannot suspend a login shell".to_owned());
+    }
+    if unsafe { libc::kill(pid, libc::SIGSTOP) } == -1 {
+        return Err(format!("suspend: {}", Error::last_os_error()));
+    }
+    // Whoever continued us may have kept the terminal
+    let _ = give_terminal_to(unsafe { libc::getpgrp() });
+    Ok(NO_RESULT.to_owned())
 }
 
-pub fn cmd_set_prompt(args: &Vec<String>, state: &mut ShellState) -> String {
+pub fn cmd_set_prompt(args: &[String], state: &mut ShellState) -> Result<String, String> {
     if args.len() != 1 {
-        return "Usage: set_prompt <format>".to_string();
+        return Err("Usage: set_prompt <format>".to_string());
     }
 
     let prompt_format: String = args[0].clone();
@@ -405,7 +925,7 @@ pub fn cmd_set_prompt(args: &Vec<String>, state: &mut ShellState) -> String {
 
     // Write the PS1 assignment to the prompt file
     match fs::write(&prompt_file, ps1_assignment) {
-        Ok(_) => "Prompt format updated and saved".to_string(),
-        Err(e) => format!("Error saving prompt: {}", e),
+        Ok(_) => Ok("Prompt format updated and saved".to_string()),
+        Err(e) => Err(format!("Error saving prompt: {}", e)),
     }
 }
@@ -0,0 +1,325 @@
+// The operators shared by the test / [ builtin and [[ ]]: file tests, string tests and integer comparisons,
+// plus the argument parser for test. [[ ]] is parsed ahead of time and evaluated in evaluation.rs.
+
+use crate::globals::ShellState;
+use std::ffi::CString;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+pub const UNARY_OPERATORS: [&str; 24] = [
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-G", "-h", "-k", "-L", "-N", "-O", "-p", "-r", "-s", "-S", "-t", "-u", "-w", "-x",
+    "-z", "-n", "-v",
+];
+
+pub const BINARY_OPERATORS: [&str; 15] = [
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef", "=~",
+];
+
+pub fn is_unary_operator(op: &str) -> bool {
+    UNARY_OPERATORS.contains(&op)
+}
+
+pub fn is_binary_operator(op: &str) -> bool {
+    BINARY_OPERATORS.contains(&op)
+}
+
+/// Applies a unary operator such as -f or -z.
+pub fn unary_test(state: &ShellState, op: &str, operand: &str) -> Result<bool, String> {
+    let metadata: Option<Metadata> = fs::metadata(operand).ok();
+    let mode: u32 = metadata.as_ref().map(|m| m.mode()).unwrap_or(0);
+
+    Ok(match op {
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        "-v" => state.get_var(operand).is_some(),
+        "-a" | "-e" => metadata.is_some(),
+        "-f" => metadata.is_some_and(|m| m.is_file()),
+        "-d" => metadata.is_some_and(|m| m.is_dir()),
+        "-b" => metadata.is_some_and(|m| m.file_type().is_block_device()),
+        "-c" => metadata.is_some_and(|m| m.file_type().is_char_device()),
+        "-p" => metadata.is_some_and(|m| m.file_type().is_fifo()),
+        "-S" => metadata.is_some_and(|m| m.file_type().is_socket()),
+        "-h" | "-L" => fs::symlink_metadata(operand).is_ok_and(|m| m.file_type().is_symlink()),
+        "-s" => metadata.is_some_and(|m| m.len() > 0),
+        "-g" => mode & libc::S_ISGID != 0,
+        "-u" => mode & libc::S_ISUID != 0,
+        "-k" => mode & libc::S_ISVTX != 0,
+        "-O" => metadata.is_some_and(|m| m.uid() == unsafe { libc::geteuid() }),
+        "-G" => metadata.is_some_and(|m| m.gid() == unsafe { libc::getegid() }),
+        "-N" => metadata.is_some_and(|m| m.mtime() > m.atime() || (m.mtime() == m.atime() && m.mtime_nsec() > m.atime_nsec())),
+        "-r" => accessible(operand, libc::R_OK),
+        "-w" => accessible(operand, libc::W_OK),
+        "-x" => accessible(operand, libc::X_OK),
+        "-t" => match operand.parse::<i32>() {
+            Ok(fd) => unsafe { libc::isatty(fd) == 1 },
+            Err(_) => return Err(format!("{}: integer expression expected", operand)),
+        },
+        _ => return Err(format!("{}: unary operator expected", op)),
+    })
+}
+
+/// Applies a binary operator. `=`, `==` and `!=` compare strings exactly here; [[ ]] does its own pattern matching.
+pub fn binary_test(left: &str, op: &str, right: &str) -> Result<bool, String> {
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => compare_integers(parse_integer(left)?, op, parse_integer(right)?),
+        "-nt" | "-ot" => {
+            let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+            match (modified(left), modified(right)) {
+                (Some(l), Some(r)) => if op == "-nt" { l > r } else { l < r },
+                (Some(_), None) => op == "-nt",
+                (None, Some(_)) => op == "-ot",
+                (None, None) => false,
+            }
+        }
+        "-ef" => match (fs::metadata(left), fs::metadata(right)) {
+            (Ok(l), Ok(r)) => l.dev() == r.dev() && l.ino() == r.ino(),
+            _ => false,
+        },
+        _ => return Err(format!("{}: binary operator expected", op)),
+    })
+}
+
+pub fn compare_integers(left: i64, op: &str, right: i64) -> bool {
+    match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        _ => left >= right,
+    }
+}
+
+fn parse_integer(text: &str) -> Result<i64, String> {
+    text.trim().parse::<i64>().map_err(|_| format!("{}: integer expression expected", text))
+}
+
+fn accessible(path: &str, mode: i32) -> bool {
+    match CString::new(path) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// Evaluates the arguments of test (or of [ without its closing ]).
+pub fn test_command(state: &ShellState, args: &[String]) -> Result<bool, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let mut parser: TestParser<'_> = TestParser { state, args: &args, pos: 0 };
+
+    if args.is_empty() {
+        return Ok(false);
+    }
+    let result: bool = parser.parse_or()?;
+    match parser.args.get(parser.pos) {
+        None => Ok(result),
+        Some(arg) => Err(format!("{}: too many arguments", arg)),
+    }
+}
+
+/// Recursive descent over test's arguments: -o binds loosest, then -a, then !, then ( ) and the primaries.
+struct TestParser<'a> {
+    state: &'a ShellState,
+    args: &'a [&'a str],
+    pos: usize,
+}
+
+impl TestParser<'_> {
+    fn remaining(&self) -> usize {
+        self.args.len() - self.pos
+    }
+
+    fn parse_or(&mut self) -> Result<bool, String> {
+        let mut result: bool = self.parse_and()?;
+        while self.args.get(self.pos) == Some(&"-o") && self.remaining() > 1 {
+            self.pos += 1;
+            let right: bool = self.parse_and()?;
+            result = result || right;
+        }
+        Ok(result)
+    }
+
+    fn parse_and(&mut self) -> Result<bool, String> {
+        let mut result: bool = self.parse_not()?;
+        while self.args.get(self.pos) == Some(&"-a") && self.remaining() > 1 {
+            self.pos += 1;
+            let right: bool = self.parse_not()?;
+            result = result && right;
+        }
+        Ok(result)
+    }
+
+    fn parse_not(&mut self) -> Result<bool, String> {
+        // A lone "!" or one followed by a binary operator is just a string.
+        if self.args[self.pos] == "!" && self.remaining() > 1 && !(self.remaining() == 3 && is_binary_operator(self.args[self.pos + 1])) {
+            self.pos += 1;
+            return Ok(!self.parse_not()?);
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<bool, String> {
+        let arg: &str = match self.args.get(self.pos) {
+            Some(arg) => arg,
+            None => return Err("argument expected".to_owned()),
+        };
+
+        if self.remaining() >= 3 && is_binary_operator(self.args[self.pos + 1]) && self.args[self.pos + 1] != "=~" {
+            let (left, op, right): (&str, &str, &str) = (arg, self.args[self.pos + 1], self.args[self.pos + 2]);
+            self.pos += 3;
+            return binary_test(left, op, right);
+        }
+        if arg == "(" && self.remaining() >= 3 {
+            self.pos += 1;
+            let result: bool = self.parse_or()?;
+            if self.args.get(self.pos) != Some(&")") {
+                return Err("')' expected".to_owned());
+            }
+            self.pos += 1;
+            return Ok(result);
+        }
+        if is_unary_operator(arg) && self.remaining() >= 2 {
+            let operand: &str = self.args[self.pos + 1];
+            self.pos += 2;
+            return unary_test(self.state, arg, operand);
+        }
+
+        self.pos += 1;
+        Ok(!arg.is_empty())
+    }
+}
+
+/// Escapes the characters that are special in an extended regular expression.
+pub fn escape_regex(text: &str) -> String {
+    let mut escaped: String = String::new();
+    for c in text.chars() {
+        if matches!(c, '.' | '[' | ']' | '\\' | '(' | ')' | '*' | '+' | '?' | '{' | '}' | '|' | '^' | '$') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Matches `text` against a POSIX extended regular expression. On a match, returns the matched text
+/// followed by what each parenthesized subexpression matched (empty if it took no part).
+pub fn regex_match(regex: &str, text: &str) -> Result<Option<Vec<String>>, String> {
+    let c_regex: CString = CString::new(regex).map_err(|_| format!("{}: invalid regular expression", regex))?;
+    let c_text: CString = CString::new(text).map_err(|_| format!("{}: invalid string", text))?;
+
+    unsafe {
+        let mut compiled: libc::regex_t = std::mem::zeroed();
+        if libc::regcomp(&mut compiled, c_regex.as_ptr(), libc::REG_EXTENDED) != 0 {
+            return Err(format!("{}: invalid regular expression", regex));
+        }
+
+        let mut matches: Vec<libc::regmatch_t> = vec![libc::regmatch_t { rm_so: -1, rm_eo: -1 }; count_groups(regex) + 1];
+        let found: bool = libc::regexec(&compiled, c_text.as_ptr(), matches.len(), matches.as_mut_ptr(), 0) == 0;
+        libc::regfree(&mut compiled);
+
+        if !found {
+            return Ok(None);
+        }
+        let groups: Vec<String> = matches
+            .iter()
+            .map(|m| if m.rm_so < 0 { String::new() } else { text[m.rm_so as usize..m.rm_eo as usize].to_owned() })
+            .collect();
+        Ok(Some(groups))
+    }
+}
+
+/// The number of parenthesized subexpressions in a regular expression, not counting escaped
+/// parentheses or those inside a bracket expression.
+fn count_groups(regex: &str) -> usize {
+    let chars: Vec<char> = regex.chars().collect();
+    let mut count: usize = 0;
+    let mut pos: usize = 0;
+    while pos < chars.len() {
+        match chars[pos] {
+            '\\' => pos += 1,
+            '(' => count += 1,
+            '[' => {
+                pos += 1;
+                if chars.get(pos) == Some(&'^') {
+                    pos += 1;
+                }
+                // A ']' right at the start is a member, not the end.
+                if chars.get(pos) == Some(&']') {
+                    pos += 1;
+                }
+                while pos < chars.len() && chars[pos] != ']' {
+                    pos += 1;
+                }
+            }
+            _ => {}
+        }
+        pos += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test(args: &str) -> Result<bool, String> {
+        let args: Vec<String> = args.split_whitespace().map(|arg| arg.replace("''", "")).collect();
+        test_command(&ShellState::default(), &args)
+    }
+
+    #[test]
+    fn test_strings_and_integers() {
+        assert_eq!(test(""), Ok(false));
+        assert_eq!(test("word"), Ok(true));
+        assert_eq!(test("''"), Ok(false));
+        assert_eq!(test("-n ''"), Ok(false));
+        assert_eq!(test("-z ''"), Ok(true));
+        assert_eq!(test("a = a"), Ok(true));
+        assert_eq!(test("a != a"), Ok(false));
+        assert_eq!(test("a* = abc"), Ok(false));
+        assert_eq!(test("b > a"), Ok(true));
+        assert_eq!(test("10 -gt 9"), Ok(true));
+        assert_eq!(test("-3 -le -3"), Ok(true));
+        assert!(test("x -eq 1").is_err());
+        assert!(test("a b").is_err());
+    }
+
+    #[test]
+    fn test_files() {
+        assert_eq!(test("-f Cargo.toml"), Ok(true));
+        assert_eq!(test("-d Cargo.toml"), Ok(false));
+        assert_eq!(test("-d src -a -r src"), Ok(true));
+        assert_eq!(test("-e no/such/file"), Ok(false));
+        assert_eq!(test("-s Cargo.toml"), Ok(true));
+        assert_eq!(test("src/main.rs -nt no/such/file"), Ok(true));
+        assert_eq!(test("Cargo.toml -ef ./Cargo.toml"), Ok(true));
+    }
+
+    #[test]
+    fn test_operators_and_grouping() {
+        assert_eq!(test("! a = b"), Ok(true));
+        assert_eq!(test("!"), Ok(true));
+        assert_eq!(test("! = !"), Ok(true));
+        assert_eq!(test("a = b -o -n x"), Ok(true));
+        assert_eq!(test("a = a -a -z x"), Ok(false));
+        assert_eq!(test("-n x -o a = a -a -z x"), Ok(true));
+        assert_eq!(test("( -n x -o a = a ) -a -z x"), Ok(false));
+        assert!(test("( a = a").is_err());
+    }
+
+    #[test]
+    fn regex_groups() {
+        assert_eq!(regex_match("^(a+)(b)?c$", "aac"), Ok(Some(vec!["aac".to_owned(), "aa".to_owned(), String::new()])));
+        assert_eq!(regex_match("[0-9]+", "foo123bar"), Ok(Some(vec!["123".to_owned()])));
+        assert_eq!(regex_match("\\(x\\)([)(])", "(x))"), Ok(Some(vec!["(x))".to_owned(), ")".to_owned()])));
+        assert_eq!(regex_match("^b", "abc"), Ok(None));
+        assert!(regex_match("(", "x").is_err());
+        let escaped: String = escape_regex("a.b*(c)");
+        assert_eq!(escaped, "a\\.b\\*\\(c\\)");
+        assert_eq!(regex_match(&escaped, "a.b*(c)"), Ok(Some(vec!["a.b*(c)".to_owned()])));
+        assert_eq!(regex_match(&escaped, "axbbc"), Ok(None));
+    }
+}
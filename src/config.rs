@@ -1,9 +1,10 @@
 use std::io::{BufRead, BufReader, Write};
-use crate::globals::get_nash_dir;
+use crate::globals::{get_nash_dir, NO_RESULT};
+use crate::jobs::JobControl;
 use std::fs::File;
 use std::collections::HashMap;
 use std::path::PathBuf;
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Config
 {
     pub rules: HashMap<String, String>,
@@ -52,6 +53,15 @@ impl Config
         let rules: &HashMap<String, String> = if temp { &self.temp_rules } else { &self.rules };
         rules.get(rule).map(String::as_str)
     }
+    /// The value of a rule, with the temporary value taking precedence.
+    pub fn get_value(&self, rule: &str) -> Option<&str> {
+        self.get_rule(rule, true).or_else(|| self.get_rule(rule, false))
+    }
+
+    /// Whether an on/off option is set.
+    pub fn is_enabled(&self, rule: &str) -> bool {
+        self.get_value(rule) == Some("true")
+    }
     pub fn remove_rule(&mut self, rule: &str, temp: bool) -> Option<(String, String)>
     {
         let rules: &mut HashMap<String, String> = if temp { &mut self.temp_rules } else { &mut self.rules };
@@ -76,23 +86,29 @@ impl Config
     }
 }
 
-pub fn set_conf_rule(conf: &mut Config, cmd: &Vec<String>) -> String {
+pub fn set_conf_rule(conf: &mut Config, job_control: &mut JobControl, cmd: &[String]) -> Result<String, String> {
     if cmd.len() < 2 {
-        return "Usage: set <flag> OR set <option> <value>".to_owned();
+        return Err("Usage: set <flag> OR set <option> <value>".to_owned());
     }
     let set: bool;
 
     match cmd.len() {
         2 => {
-            // Command is in "set <flag>" format
-            let flag: &str = &cmd[1].trim_start_matches('-');
+            // Command is in "set <flag>" format; "+flag" turns the flag off again
+            let value: &str = if cmd[1].starts_with('+') { "false" } else { "true" };
+            let flag: &str = cmd[1].trim_start_matches(['-', '+']);
             match flag
             {
-                "e" => conf.set_rule("error", "true", true),
-                "d" => conf.set_rule("delete_on_reset", "true", true),
-                _ => conf.set_rule(flag, "true", false)
+                "e" => conf.set_rule("error", value, true),
+                "d" => conf.set_rule("delete_on_reset", value, true),
+                "u" => conf.set_rule("nounset", value, true),
+                "b" => conf.set_rule("notify", value, true),
+                _ => conf.set_rule(flag, value, false)
             }
-            set = true;
+            job_control.notify = conf.is_enabled("notify");
+            // Like any other shell's set flags, these are silent
+            conf.save_rules();
+            return Ok(NO_RESULT.to_owned());
         }
         3 => {
             // Command is in "set <option> <value>" format
@@ -102,7 +118,7 @@ pub fn set_conf_rule(conf: &mut Config, cmd: &Vec<String>) -> String {
             {
                 "error" => conf.set_rule("error", value, true),
                 "delete_on_reset" => conf.set_rule("delete_on_reset", value, true),
-                _ => conf.set_rule(&option, &value, false)
+                _ => conf.set_rule(option, value, false)
             }
             set = true;
         }
@@ -111,22 +127,23 @@ pub fn set_conf_rule(conf: &mut Config, cmd: &Vec<String>) -> String {
             let option: &str = &cmd[1];
             let value: &String = &cmd[2];
             let temp: &bool = &cmd[3].parse::<bool>().unwrap_or(true);
-            conf.set_rule(&option, &value, *temp);
+            conf.set_rule(option, value, *temp);
             set = true;
         }
         _ => {
             // Invalid usage
-            return "Invalid usage. Use 'set <flag>' or 'set <option> <value>'.".to_owned()
+            return Err("Invalid usage. Use 'set <flag>' or 'set <option> <value>'.".to_owned())
         }
     }
+    job_control.notify = conf.is_enabled("notify");
     if set
     {
         conf.save_rules();
     }
-    return if set {"Successfully set option".to_owned()} else {"Failed to set option".to_owned()}
+    if set {Ok("Successfully set option".to_owned())} else {Err("Failed to set option".to_owned())}
 }
 
-pub fn unset_conf_rule(conf: &mut Config, cmd: &Vec<String>) -> String
+pub fn unset_conf_rule(conf: &mut Config, job_control: &mut JobControl, cmd: &[String]) -> Result<String, String>
 {
     let mut errored: bool = false;
     if cmd.len() == 3
@@ -138,16 +155,18 @@ pub fn unset_conf_rule(conf: &mut Config, cmd: &Vec<String>) -> String
         };
         if errored
         {
-            return "You must specify whether the rule is in the temporary or consistent rules.".to_owned();
+            return Err("You must specify whether the rule is in the temporary or consistent rules.".to_owned());
         }
-        conf.remove_rule(&cmd[1], temp).unwrap_or(("".to_owned(), "".to_owned())).1
+        let removed: String = conf.remove_rule(&cmd[1], temp).unwrap_or(("".to_owned(), "".to_owned())).1;
+        job_control.notify = conf.is_enabled("notify");
+        Ok(removed)
     }
     else {
-        "Usage: unset <option> <temp>".to_owned()
+        Err("Usage: unsetconf <option> <temp>".to_owned())
     }
 }
 
-pub fn read_conf(conf: &Config, cmd: &Vec<String>) -> String
+pub fn read_conf(conf: &Config, cmd: &[String]) -> Result<String, String>
 {
     if cmd.len() == 3
     {
@@ -156,24 +175,24 @@ pub fn read_conf(conf: &Config, cmd: &Vec<String>) -> String
             Ok(b) => b,
             Err(e) => {println!("Could not determine whether searching the temporary list or consistent rules. Assuming consistent. Recieved error: {}", e); false}
         };
-        return match conf.get_rule(&cmd[1], temp)
+        match conf.get_rule(&cmd[1], temp)
         {
-            None => "Rule not set.".to_owned(),
-            Some(s) => s.to_owned()
-        };
+            None => Err("Rule not set.".to_owned()),
+            Some(s) => Ok(s.to_owned())
+        }
     } else if cmd.len() == 2
     {
-        return match conf.get_rule(&cmd[1], false)
+        match conf.get_rule(&cmd[1], false)
         {
-            None => format!("Rule not set in consistent, checking temporary.\n{}", match conf.get_rule(&cmd[1], true)
+            None => Ok(format!("Rule not set in consistent, checking temporary.\n{}", match conf.get_rule(&cmd[1], true)
         {
             None => "Rule not set in temporary",
             Some(c) => c
-        }).to_owned(),
-            Some(s) => s.to_owned()
-        };
+        })),
+            Some(s) => Ok(s.to_owned())
+        }
     }
     else {
-        "Usage: rconf <option> [temp(bool)]".to_owned()
+        Err("Usage: rconf <option> [temp(bool)]".to_owned())
     }
 }
\ No newline at end of file
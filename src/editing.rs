@@ -2,6 +2,7 @@ use std::path::PathBuf;
 use rustyline::completion::{Completer, Pair};
 use rustyline::highlight::Highlighter;
 use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
 use rustyline::Context;
 use rustyline::Result;
 use rustyline::history::History;
@@ -10,6 +11,7 @@ use std::io;
 use std::borrow::Cow;
 use std::env;
 use console::Style;
+use crate::parser::{parse, ParseError};
 
 pub struct AutoCompleter {
     current_dir: PathBuf,
@@ -34,6 +36,7 @@ impl AutoCompleter {
             "help".to_string(),
             "set".to_string(),
             "unset".to_string(),
+            "unsetconf".to_string(),
             "rconf".to_string(),
             "reset".to_string(),
             "settings".to_string(),
@@ -75,8 +78,8 @@ impl AutoCompleter {
         if path.starts_with('~') {
             if path == "~" {
                 self.home_dir.clone()
-            } else if path.starts_with("~/") {
-                self.home_dir.join(&path[2..])
+            } else if let Some(rest) = path.strip_prefix("~/") {
+                self.home_dir.join(rest)
             } else {
                 PathBuf::from(path)
             }
@@ -156,6 +159,12 @@ pub struct LineHighlighter {
     path_style: Style,
 }
 
+impl Default for LineHighlighter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LineHighlighter {
     pub fn new() -> Self {
         LineHighlighter {
@@ -203,7 +212,9 @@ impl Highlighter for LineHighlighter {
     }
 
     fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
-        Cow::Owned(self.highlight_command_line(line))
+        // Highlight each physical line on its own so continuation lines keep their breaks.
+        let lines: Vec<String> = line.split('\n').map(|l| self.highlight_command_line(l)).collect();
+        Cow::Owned(lines.join("\n"))
     }
 
     fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
@@ -223,8 +234,8 @@ impl CommandHinter {
     }
 
     // Modified to accept Vec<String> instead of History
-    pub fn update_history(&mut self, history_entries: &Vec<String>) {
-        self.history = history_entries.clone();
+    pub fn update_history(&mut self, history_entries: &[String]) {
+        self.history = history_entries.to_vec();
     }
 }
 
@@ -246,3 +257,16 @@ impl Hinter for CommandHinter {
         None
     }
 }
+
+/// Keeps reading lines while the input so far is an unfinished command,
+/// e.g. an open quote or a here-document still waiting for its delimiter.
+pub struct InputValidator;
+
+impl Validator for InputValidator {
+    fn validate(&self, ctx: &mut ValidationContext) -> Result<ValidationResult> {
+        match parse(ctx.input()) {
+            Err(ParseError::Incomplete(_)) => Ok(ValidationResult::Incomplete),
+            _ => Ok(ValidationResult::Valid(None)),
+        }
+    }
+}
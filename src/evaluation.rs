@@ -2,319 +2,1185 @@ use crate::config::*;
 use crate::globals::*;
 use crate::commands::*;
 use crate::command_parsing::*;
-use crate::jobs::JobControl;
-use std::process::{self, Stdio, Command};
-use std::{fs::OpenOptions, io::{Write, Error}, env, path::PathBuf, os::unix::process::CommandExt};
+use crate::jobs::{JobControl, JobStatus, exit_code_of, give_terminal_to, reset_child_signals};
+use crate::ast::*;
+use crate::parser::{parse, as_assignment, DECLARATION_BUILTINS};
+use crate::pattern;
+use crate::conditional;
+use std::process;
+use std::collections::BTreeMap;
+use std::{fs::{File, OpenOptions}, io::{Read, Write, Seek, SeekFrom, Error}, env, path::{Path, PathBuf}, os::unix::process::CommandExt, os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd}};
 
-pub fn eval(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, cmd: String, internal: bool) -> String {
-    let chars_to_check: [char; 3] = [';', '|', '>'];
+pub fn eval(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, cmd: String, internal: bool) -> i32 {
+    let list: List = match parse(&cmd) {
+        Ok(list) => list,
+        Err(e) => {
+            eprintln!("{}", e);
+            state.last_status = 2;
+            return 2;
+        }
+    };
 
-    let expanded_cmd: String = if cmd.starts_with('.') { lim_expand(state, &cmd) } else { expand(state, &cmd) };
-    let cmd_parts: Vec<String> = split_command(&expanded_cmd);
+    special_eval(state, conf, job_control, &list, internal)
+}
 
-    if cmd_parts.is_empty() {
-        return "Empty command".to_owned();
+/// Runs each and-or list in order and returns the status of the last pipeline that ran.
+pub fn special_eval(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, list: &List, internal: bool) -> i32 {
+    for and_or in &list.items {
+        if state.control_flow.is_some() {
+            break;
+        }
+        if and_or.background {
+            state.last_status = start_background_job(state, conf, job_control, and_or, internal);
+            continue;
+        }
+        eval_and_or(state, conf, job_control, and_or, internal);
     }
 
-    // Check if the first part is an environment variable assignment
-    if cmd_parts[0].contains('=') {
-        let parts: Vec<&str> = cmd_parts[0].splitn(2, '=').collect();
-        if parts.len() == 2 {
-            state.set_local_var(parts[0], parts[1]);
-            return NO_RESULT.to_owned();
-        } else {
-            return "Invalid variable assignment".to_owned();
+    state.last_status
+}
+
+fn eval_and_or(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, and_or: &AndOr, internal: bool) -> i32 {
+    let mut status: i32 = eval_pipeline(state, conf, job_control, &and_or.first, internal);
+    state.last_status = status;
+
+    for (op, pipeline) in &and_or.rest {
+        if state.control_flow.is_some() {
+            break;
+        }
+        let run: bool = match op {
+            AndOrOp::And => status == 0,
+            AndOrOp::Or => status != 0,
+        };
+        if run {
+            status = eval_pipeline(state, conf, job_control, pipeline, internal);
+            state.last_status = status;
         }
     }
+    status
+}
 
-    let expanded_cmd_parts: Vec<String> = expand_aliases(cmd_parts);
+/// Forks a child to run `and_or` as a job, without the terminal, and doesn't wait for it. With job control it gets
+/// a process group of its own, and an interactive shell announces it as "[n] pid".
+fn start_background_job(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, and_or: &AndOr, internal: bool) -> i32 {
+    if and_or.rest.is_empty() {
+        return start_background_pipeline(state, conf, job_control, &and_or.first, internal);
+    }
+    let _ = std::io::stdout().flush();
+    let pid: libc::pid_t = unsafe { libc::fork() };
+    if pid == 0 {
+        if job_control.monitor {
+            unsafe {
+                libc::setpgid(0, 0);
+            }
+        }
+        reset_child_signals();
+        job_control.monitor = false;
+        state.subshell = true;
+        let status: i32 = eval_and_or(state, conf, job_control, and_or, true);
+        let _ = std::io::stdout().flush();
+        unsafe { libc::_exit(status) }
+    }
+    if pid < 0 {
+        eprintln!("Failed to fork: {}", Error::last_os_error());
+        return 1;
+    }
 
-    if cmd.contains(|c| chars_to_check.contains(&c)) {
-        return special_eval(state, conf, job_control, expanded_cmd_parts.join(" "));
+    state.last_background_pid = Some(pid);
+    if job_control.monitor {
+        // Also set the group from the parent so there is no race with the child.
+        unsafe {
+            libc::setpgid(pid, pid);
+        }
+    }
+    // Without job control the job is still listed, so that wait and jobs know about it.
+    let mut command: AndOr = and_or.clone();
+    command.background = false;
+    let id: usize = job_control.add_job(pid, command.to_string(), vec![(pid, command.to_string())]);
+    if job_control.monitor && !internal {
+        eprintln!("[{}] {}", id, pid);
     }
+    0
+}
 
-    if expanded_cmd_parts[0].as_str().starts_with('.') {
-        execute_file(&cmd[1..], &expanded_cmd_parts[1..])
+/// A background job that is a single pipeline: its stages are forked straight into the job's process group,
+/// so the job knows every one of them. $! is the last stage.
+fn start_background_pipeline(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, pipeline: &Pipeline, internal: bool) -> i32 {
+    let _ = std::io::stdout().flush();
+    let (pids, pgid, failed): (Vec<libc::pid_t>, libc::pid_t, bool) = fork_pipeline(state, conf, job_control, pipeline);
+    let last: libc::pid_t = match pids.last() {
+        Some(&pid) => pid,
+        None => return 1,
+    };
+
+    state.last_background_pid = Some(last);
+    let members: Vec<(libc::pid_t, String)> = pids.iter().zip(&pipeline.commands).map(|(&pid, command)| (pid, command.to_string())).collect();
+    let id: usize = job_control.add_job(pgid, pipeline.to_string(), members);
+    if job_control.monitor && !internal {
+        eprintln!("[{}] {}", id, last);
     }
-    else {
-        match expanded_cmd_parts[0].as_str() {
-            cmd if cmd.starts_with('.') => "This path should be unreachable.".to_owned(),
-            "cd" => handle_cd(&expanded_cmd_parts),
-            "history" => handle_history(&expanded_cmd_parts),
-            "exit" => {
-                println!("Exiting...");
-                process::exit(0);
+    failed as i32
+}
+
+fn eval_pipeline(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, pipeline: &Pipeline, internal: bool) -> i32 {
+    let status: i32 = if pipeline.commands.len() > 1 {
+        pipe_eval(state, conf, job_control, pipeline, internal)
+    } else {
+        eval_command(state, conf, job_control, &pipeline.commands[0], internal)
+    };
+
+    if pipeline.negated {
+        (status == 0) as i32
+    } else {
+        status
+    }
+}
+
+/// Forks one child per stage of a pipeline, connected by OS pipes, without waiting for them. With job control they
+/// share a new process group. Returns their pids, the group, and whether a stage could not be started.
+fn fork_pipeline(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, pipeline: &Pipeline) -> (Vec<libc::pid_t>, libc::pid_t, bool) {
+    let stage_count: usize = pipeline.commands.len();
+    let mut pids: Vec<libc::pid_t> = Vec::new();
+    let mut pgid: libc::pid_t = 0;
+    let mut prev_read: Option<RawFd> = None;
+    let mut failed: bool = false;
+
+    for (i, command) in pipeline.commands.iter().enumerate() {
+        let (read_end, write_end) = if i + 1 < stage_count {
+            match create_pipe() {
+                Ok((r, w)) => (Some(r), Some(w)),
+                Err(e) => {
+                    eprintln!("Failed to create pipe: {}", e);
+                    failed = true;
+                    break;
+                }
             }
-            "summon" => handle_summon(&expanded_cmd_parts),
-            "alias" => handle_alias(state, &expanded_cmd_parts),
-            "rmalias" => handle_remove_alias(&expanded_cmd_parts),
-            "help" => show_help(),
-            "set" => set_conf_rule(conf, &expanded_cmd_parts),
-            "unset" => unset_conf_rule(conf, &expanded_cmd_parts),
-            "rconf" => read_conf(conf, &expanded_cmd_parts),
-            "reset" => reset(conf, get_nash_dir()),
-            "fg" => handle_fg(&expanded_cmd_parts, job_control),
-            "bg" => handle_bg(&expanded_cmd_parts, job_control),
-            "jobs" => handle_jobs(job_control),
-            "pwd" => env::current_dir().unwrap().to_str().unwrap().to_string(),
-            "settings" => handle_settings(conf, &expanded_cmd_parts),
-            "TEST" => test_nash(conf, state, job_control, &expanded_cmd_parts),
-            "setprompt" => cmd_set_prompt(&expanded_cmd_parts, state),
-            "export" => export_env_var_eval(state, &expanded_cmd_parts),
+        } else {
+            (None, None)
+        };
 
-            _ => {
-                // If not a built-in command, execute as an external command
-                let result: String = execute_external_command(&expanded_cmd_parts[0], &expanded_cmd_parts, internal, job_control);
-                if !result.is_empty() {
-                    return format!("{}", result);
+        let pid: libc::pid_t = unsafe { libc::fork() };
+        if pid == 0 {
+            // Child: join the pipeline's process group and wire up stdin/stdout before running the stage.
+            unsafe {
+                if job_control.monitor {
+                    libc::setpgid(0, pgid);
+                }
+                if let Some(fd) = prev_read {
+                    libc::dup2(fd, libc::STDIN_FILENO);
+                }
+                if let Some(fd) = write_end {
+                    libc::dup2(fd, libc::STDOUT_FILENO);
                 }
-                NO_RESULT.to_owned()
             }
-        }.trim().to_owned()
+            reset_child_signals();
+            job_control.monitor = false;
+            state.subshell = true;
+            let status: i32 = run_pipeline_stage(state, conf, job_control, command);
+            let _ = std::io::stdout().flush();
+            unsafe { libc::_exit(status) }
+        }
+
+        // Parent: close our copies of the pipe ends the child now owns.
+        unsafe {
+            if let Some(fd) = prev_read {
+                libc::close(fd);
+            }
+            if let Some(fd) = write_end {
+                libc::close(fd);
+            }
+        }
+        prev_read = read_end;
+
+        if pid < 0 {
+            eprintln!("Failed to fork: {}", Error::last_os_error());
+            failed = true;
+            break;
+        }
+        if pgid == 0 {
+            pgid = pid;
+        }
+        if job_control.monitor {
+            // Also set the group from the parent so there is no race with the child.
+            unsafe {
+                libc::setpgid(pid, pgid);
+            }
+        }
+        pids.push(pid);
+    }
+
+    if let Some(fd) = prev_read {
+        unsafe {
+            libc::close(fd);
+        }
     }
+
+    (pids, pgid, failed)
 }
 
-pub fn special_eval(state: &mut ShellState, conf: &mut Config , job_control: &mut JobControl, cmd: String) -> String {
-    let mut result: String = String::new();
-    let commands: Vec<String> = cmd.split(';').map(|s| s.trim().to_owned()).collect();
+/// Starts every stage of a pipeline at once, connected by OS pipes and sharing one process group.
+/// The last stage writes straight to the shell's stdout.
+pub fn pipe_eval(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, pipeline: &Pipeline, internal: bool) -> i32 {
+    let (pids, pgid, failed): (Vec<libc::pid_t>, libc::pid_t, bool) = fork_pipeline(state, conf, job_control, pipeline);
 
-    for command in commands {
-        if command.contains("|") {
-            result = pipe_eval(state, conf, job_control, command);
-        } else if command.contains(">") {
-            result = out_redir_eval(state, conf, job_control, command);
-        } else {
-            result = eval(state, conf, job_control, command, false);
+    if pids.is_empty() {
+        return 1;
+    }
+
+    let monitor: bool = job_control.monitor;
+    if monitor {
+        let members: Vec<(libc::pid_t, String)> = pids.iter().zip(&pipeline.commands).map(|(&pid, command)| (pid, command.to_string())).collect();
+        job_control.add_job(pgid, pipeline.to_string(), members);
+        if !internal {
+            let _ = give_terminal_to(pgid);
         }
     }
-    result
-}
 
-pub fn pipe_eval(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, cmd: String) -> String {
-    let parts: Vec<String> = cmd.split('|').map(|s| s.trim().to_owned()).collect();
-    let mut input: String = String::new();
+    let status: std::io::Result<(JobStatus, i32)> = job_control.wait_for_pipeline(&pids);
 
-    for part in parts {
-        let expanded_cmd: String = if part.starts_with('.') { lim_expand(state, &part) } else { expand(state, &part) };
-        let cmd_parts: Vec<String> = split_command(&expanded_cmd);
+    if monitor && !internal {
+        let _ = give_terminal_to(unsafe { libc::getpgrp() });
+    }
 
-        if cmd_parts.is_empty() {
-            return "Empty command in pipe".to_owned();
+    match status {
+        Ok((JobStatus::Stopped, code)) => {
+            if let Some(job) = job_control.find_job(pgid) {
+                println!("\n[{}]+  Stopped                 {}", job.id, pipeline);
+            }
+            code
         }
+        Ok((_, code)) => {
+            job_control.remove_job(pgid);
+            if failed { 1 } else { code }
+        }
+        Err(e) => {
+            eprintln!("Failed to wait for pipeline: {}", e);
+            1
+        }
+    }
+}
 
-        // Check for environment variable assignment (unlikely in a pipe, but we'll handle it)
-        if cmd_parts[0].contains('=') {
-            return "Environment variable assignment not supported in pipes".to_owned();
+/// Runs a command in the shell process, with its redirections if it has any.
+fn eval_command(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, command: &Command, internal: bool) -> i32 {
+    match command {
+        Command::Simple(simple) if simple.redirects.is_empty() => eval_simple_command(state, conf, job_control, simple, internal),
+        Command::Simple(simple) => redir_eval(state, conf, job_control, &simple.redirects, |state, conf, job_control| {
+            eval_simple_command(state, conf, job_control, simple, internal)
+        }),
+        Command::Compound(CompoundCommand::Subshell(_), _) => {
+            // A subshell runs like a pipeline of one: forked, in its own process group and as a job of its own.
+            let pipeline: Pipeline = Pipeline { negated: false, commands: vec![command.clone()] };
+            pipe_eval(state, conf, job_control, &pipeline, internal)
+        }
+        Command::Compound(compound, redirects) => redir_eval(state, conf, job_control, redirects, |state, conf, job_control| {
+            eval_compound(state, conf, job_control, compound, internal)
+        }),
+        Command::FunctionDefinition(function) => {
+            state.functions.insert(function.name.clone(), function.clone());
+            0
         }
+    }
+}
 
-        let expanded_cmd_parts: Vec<String> = expand_aliases(cmd_parts);
+fn eval_compound(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, compound: &CompoundCommand, internal: bool) -> i32 {
+    match compound {
+        CompoundCommand::Arithmetic(expression) => match expand_arithmetic(state, conf, job_control, expression) {
+            Ok(value) => (value == 0) as i32,
+            Err(e) => {
+                print_error(&e);
+                1
+            }
+        },
+        CompoundCommand::If { branches, else_branch } => {
+            for (condition, body) in branches {
+                let status: i32 = special_eval(state, conf, job_control, condition, internal);
+                if state.control_flow.is_some() {
+                    return status;
+                }
+                if status == 0 {
+                    return special_eval(state, conf, job_control, body, internal);
+                }
+            }
+            match else_branch {
+                Some(body) => special_eval(state, conf, job_control, body, internal),
+                None => 0,
+            }
+        }
+        CompoundCommand::While { until, condition, body } => {
+            state.loop_depth += 1;
+            let mut status: i32 = 0;
+            loop {
+                let condition_status: i32 = special_eval(state, conf, job_control, condition, internal);
+                if end_of_iteration(state) || (condition_status == 0) == *until {
+                    break;
+                }
+                status = special_eval(state, conf, job_control, body, internal);
+                if end_of_iteration(state) {
+                    break;
+                }
+            }
+            state.loop_depth -= 1;
+            status
+        }
+        CompoundCommand::For { name, words, body } => {
+            let items: Vec<String> = match words {
+                Some(words) => match expand_words(state, conf, job_control, words) {
+                    Ok(items) => items,
+                    Err(e) => {
+                        print_error(&e);
+                        return 1;
+                    }
+                },
+                None => state.positional.clone(),
+            };
 
-        let result: String = if expanded_cmd_parts[0].as_str().starts_with('.') {
-            execute_file(&part[1..], &expanded_cmd_parts[1..])
-        } else {
-            match expanded_cmd_parts[0].as_str() {
-                "cd" => handle_cd(&expanded_cmd_parts),
-                "history" => handle_history(&expanded_cmd_parts),
-                "alias" => handle_alias(state, &expanded_cmd_parts),
-                "rmalias" => handle_remove_alias(&expanded_cmd_parts),
-                "help" => show_help(),
-                "set" => set_conf_rule(conf, &expanded_cmd_parts),
-                "unset" => unset_conf_rule(conf, &expanded_cmd_parts),
-                "rconf" => read_conf(conf, &expanded_cmd_parts),
-                "jobs" => handle_jobs(job_control),
-                "pwd" => env::current_dir().unwrap().to_str().unwrap().to_string(),
-                "settings" => handle_settings(conf, &expanded_cmd_parts),
-                "setprompt" => cmd_set_prompt(&expanded_cmd_parts, state),
-                "exit" | "reset" | "fg" | "bg" | "summon" | "export" => {
-                    return format!("Command '{}' is not supported in pipes", expanded_cmd_parts[0]);
+            state.loop_depth += 1;
+            let mut status: i32 = 0;
+            for item in items {
+                if let Err(e) = state.set_var(name, &item) {
+                    print_error(&e);
+                    status = 1;
+                    break;
                 }
-                _ => {
-                    // If not a built-in command, execute as an external command
-                    let mut child: process::Child = match Command::new(&expanded_cmd_parts[0])
-                        .args(&expanded_cmd_parts[1..])
-                        .stdin(Stdio::piped())
-                        .stdout(Stdio::piped())
-                        .spawn()
-                    {
-                        Ok(child) => child,
-                        Err(e) => {
-                            return format!("Failed to execute command: {}", e);
-                        }
-                    };
-
-                    if !input.is_empty() {
-                        match child.stdin.as_mut() {
-                            Some(stdin) => {
-                                if let Err(e) = stdin.write_all(input.as_bytes()) {
-                                    return format!("Failed to write to stdin: {}", e);
-                                }
-                            },
-                            None => {
-                                return "Failed to open stdin".to_string();
+                status = special_eval(state, conf, job_control, body, internal);
+                if end_of_iteration(state) {
+                    break;
+                }
+            }
+            state.loop_depth -= 1;
+            status
+        }
+        CompoundCommand::ArithmeticFor { init, condition, update, body } => {
+            state.loop_depth += 1;
+            let status: Result<i32, String> = eval_arithmetic_for(state, conf, job_control, [init, condition, update], body, internal);
+            state.loop_depth -= 1;
+            status.unwrap_or_else(|e| {
+                print_error(&e);
+                1
+            })
+        }
+        CompoundCommand::BraceGroup(list) => special_eval(state, conf, job_control, list, internal),
+        // eval_command has already forked; this is the child.
+        CompoundCommand::Subshell(list) => special_eval(state, conf, job_control, list, internal),
+        CompoundCommand::Conditional(expression) => match eval_conditional(state, conf, job_control, expression) {
+            Ok(result) => !result as i32,
+            Err(e) => {
+                print_error(&e);
+                2
+            }
+        },
+        CompoundCommand::Case { word, items } => {
+            let subject: String = match expand_word(state, conf, job_control, word) {
+                Ok(subject) => subject,
+                Err(e) => {
+                    print_error(&e);
+                    return 1;
+                }
+            };
+
+            let mut status: i32 = 0;
+            // Set by ;& so the next item runs without testing its patterns.
+            let mut fall_through: bool = false;
+            for item in items {
+                if !fall_through {
+                    let mut matched: bool = false;
+                    for pattern in &item.patterns {
+                        match expand_pattern(state, conf, job_control, pattern) {
+                            Ok(pattern) if pattern::matches(&pattern, &subject) => {
+                                matched = true;
+                                break;
+                            }
+                            Ok(_) => {}
+                            Err(e) => {
+                                print_error(&e);
+                                return 1;
                             }
                         }
                     }
-
-                    let output: process::Output = child.wait_with_output().expect("Failed to read stdout");
-                    if output.status.success() {
-                        String::from_utf8_lossy(&output.stdout).into_owned()
-                    } else {
-                        return format!("Command failed: {}", String::from_utf8_lossy(&output.stderr));
+                    if !matched {
+                        continue;
                     }
                 }
+
+                status = special_eval(state, conf, job_control, &item.body, internal);
+                if state.control_flow.is_some() {
+                    break;
+                }
+                match item.terminator {
+                    CaseTerminator::Break => break,
+                    CaseTerminator::FallThrough => fall_through = true,
+                    CaseTerminator::Continue => fall_through = false,
+                }
             }
-        };
+            status
+        }
+    }
+}
+
+/// Evaluates the expression of [[ ]]. The operands of integer comparisons are arithmetic expressions,
+/// and =~ leaves what it matched in BASH_REMATCH, followed by what each subexpression matched.
+fn eval_conditional(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, expression: &CondExpr) -> Result<bool, String> {
+    match expression {
+        CondExpr::Unary(op, word) => {
+            let operand: String = expand_word(state, conf, job_control, word)?;
+            conditional::unary_test(state, op, &operand)
+        }
+        CondExpr::Binary(left, op, right) => match op.as_str() {
+            "=" | "==" | "!=" => {
+                let text: String = expand_word(state, conf, job_control, left)?;
+                let pattern: String = expand_pattern(state, conf, job_control, right)?;
+                Ok(pattern::matches(&pattern, &text) == (op != "!="))
+            }
+            "=~" => {
+                let text: String = expand_word(state, conf, job_control, left)?;
+                let regex: String = expand_regex(state, conf, job_control, right)?;
+                let groups: Option<Vec<String>> = conditional::regex_match(&regex, &text)?;
+                let matched: bool = groups.is_some();
+                state.set_value("BASH_REMATCH", Value::Indexed(groups.unwrap_or_default().into_iter().enumerate().collect()))?;
+                Ok(matched)
+            }
+            "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                let left: i64 = expand_arithmetic(state, conf, job_control, &left.parts)?;
+                let right: i64 = expand_arithmetic(state, conf, job_control, &right.parts)?;
+                Ok(conditional::compare_integers(left, op, right))
+            }
+            _ => {
+                let left: String = expand_word(state, conf, job_control, left)?;
+                let right: String = expand_word(state, conf, job_control, right)?;
+                conditional::binary_test(&left, op, &right)
+            }
+        },
+        CondExpr::Word(word) => Ok(!expand_word(state, conf, job_control, word)?.is_empty()),
+        CondExpr::Not(expression) => Ok(!eval_conditional(state, conf, job_control, expression)?),
+        CondExpr::And(left, right) => Ok(eval_conditional(state, conf, job_control, left)? && eval_conditional(state, conf, job_control, right)?),
+        CondExpr::Or(left, right) => Ok(eval_conditional(state, conf, job_control, left)? || eval_conditional(state, conf, job_control, right)?),
+        CondExpr::Group(expression) => eval_conditional(state, conf, job_control, expression),
+    }
+}
 
-        input = result;
+/// for ((init; condition; update)); an empty condition counts as true.
+fn eval_arithmetic_for(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, [init, condition, update]: [&Vec<WordPart>; 3], body: &List, internal: bool) -> Result<i32, String> {
+    if !init.is_empty() {
+        expand_arithmetic(state, conf, job_control, init)?;
     }
+    let mut status: i32 = 0;
+    while condition.is_empty() || expand_arithmetic(state, conf, job_control, condition)? != 0 {
+        status = special_eval(state, conf, job_control, body, internal);
+        if end_of_iteration(state) {
+            break;
+        }
+        if !update.is_empty() {
+            expand_arithmetic(state, conf, job_control, update)?;
+        }
+    }
+    Ok(status)
+}
 
-    input
+/// Handles a pending break or continue at the end of a loop iteration.
+/// Returns true if the loop should stop, either because of a break or because the jump targets an outer loop.
+fn end_of_iteration(state: &mut ShellState) -> bool {
+    match state.control_flow.take() {
+        None => false,
+        Some(flow @ (ControlFlow::Return(_) | ControlFlow::Exit(_))) => {
+            state.control_flow = Some(flow);
+            true
+        }
+        Some(ControlFlow::Continue(1)) => false,
+        Some(ControlFlow::Break(1)) => true,
+        Some(ControlFlow::Break(levels)) => {
+            state.control_flow = Some(ControlFlow::Break(levels - 1));
+            true
+        }
+        Some(ControlFlow::Continue(levels)) => {
+            state.control_flow = Some(ControlFlow::Continue(levels - 1));
+            true
+        }
+    }
 }
-// TODO: Input redirection, actual error redirection
-pub fn out_redir_eval(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, cmd: String) -> String {
-    let parts: Vec<String> = if cmd.contains("2>>") {
-        cmd.splitn(2, "2>>").map(|s| s.trim().to_owned()).collect()
-    } else if cmd.contains(">>") {
-        cmd.splitn(2, ">>").map(|s| s.trim().to_owned()).collect()
-    } else if cmd.contains("2>") {
-        cmd.splitn(2, "2>").map(|s| s.trim().to_owned()).collect()
-    } else {
-        cmd.splitn(2, ">").map(|s| s.trim().to_owned()).collect()
+
+/// Runs `run` with the redirections applied, left to right, to the shell's own descriptors,
+/// so builtins read from and write to the targets and external commands inherit them.
+pub fn redir_eval(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, redirects: &[Redirect], run: impl FnOnce(&mut ShellState, &mut Config, &mut JobControl) -> i32) -> i32 {
+    // Each touched descriptor with its saved copy, or None if it was closed before.
+    let mut saved: Vec<(RawFd, Option<RawFd>)> = Vec::new();
+    let mut error: Option<String> = None;
+
+    let _ = std::io::stdout().flush();
+    for redirect in redirects {
+        for fd in redirected_fds(redirect) {
+            if saved.iter().any(|(saved_fd, _)| *saved_fd == fd) {
+                continue;
+            }
+            match save_fd(fd) {
+                Ok(copy) => saved.push((fd, Some(copy))),
+                Err(e) if e.raw_os_error() == Some(libc::EBADF) => saved.push((fd, None)),
+                Err(e) => error = Some(format!("Failed to save file descriptor {}: {}", fd, e)),
+            }
+        }
+        if error.is_some() {
+            break;
+        }
+        if let Err(e) = apply_redirect(state, conf, job_control, redirect) {
+            error = Some(e);
+            break;
+        }
+    }
+
+    let status: i32 = match error {
+        None => run(state, conf, job_control),
+        Some(_) => 1,
     };
 
-    if parts.len() != 2 {
-        return "Invalid output redirection syntax".to_owned();
+    let _ = std::io::stdout().flush();
+    for (fd, copy) in saved.into_iter().rev() {
+        match copy {
+            Some(copy) => restore_fd(fd, copy),
+            None => unsafe {
+                libc::close(fd);
+            },
+        }
     }
 
-    let command: String = parts[0].clone();
-    let file_path: String = parts[1].clone();
-    let append_mode: bool = cmd.contains(">>");
+    // Reported only now so the message reaches the real stderr
+    if let Some(e) = error {
+        eprintln!("{}", e);
+    }
+    status
+}
 
-    let expanded_cmd: String = if command.starts_with('.') { lim_expand(state, &command) } else { expand(state, &command) };
-    let cmd_parts: Vec<String> = split_command(&expanded_cmd);
+/// Runs one stage of a pipeline inside its forked child and returns the exit status.
+fn run_pipeline_stage(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, command: &Command) -> i32 {
+    let command: &SimpleCommand = match command {
+        Command::Simple(simple) => simple,
+        Command::Compound(compound, redirects) => {
+            for redirect in redirects {
+                if let Err(e) = apply_redirect(state, conf, job_control, redirect) {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            }
+            return eval_compound(state, conf, job_control, compound, true);
+        }
+        Command::FunctionDefinition(_) => return eval_command(state, conf, job_control, command, true),
+    };
+
+    let (assignments, cmd_parts, arrays): ExpandedCommand = match expand_command(state, conf, job_control, command) {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            print_error(&e);
+            return 1;
+        }
+    };
+
+    for redirect in &command.redirects {
+        if let Err(e) = apply_redirect(state, conf, job_control, redirect) {
+            eprintln!("{}", e);
+            return 1;
+        }
+    }
 
     if cmd_parts.is_empty() {
-        return "Empty command".to_owned();
+        for assignment in &command.assignments {
+            if let Err(e) = assign(state, conf, job_control, assignment) {
+                print_error(&e);
+                return 1;
+            }
+        }
+        return 0;
     }
 
-    // Check if the first part is an environment variable assignment
-    if cmd_parts[0].contains('=') {
-        return "Environment variable assignment not supported with output redirection".to_owned();
+    let expanded_cmd_parts: Vec<String> = match expand_aliases(state, conf, job_control, cmd_parts) {
+        Ok(parts) if parts.is_empty() => return 0,
+        Ok(parts) => parts,
+        Err(e) => {
+            print_error(&e);
+            return 1;
+        }
+    };
+
+    if let Some(function) = state.functions.get(&expanded_cmd_parts[0]).cloned() {
+        return call_function(state, conf, job_control, &function, &expanded_cmd_parts, &assignments, true);
+    }
+    if let Some(result) = run_builtin(state, conf, job_control, &expanded_cmd_parts) {
+        return finish_builtin(state, conf, job_control, result, &arrays);
+    }
+
+    let environment: Vec<(String, String)> = state.environment(&assignments);
+    match find_command_in_path(&expanded_cmd_parts[0], &environment) {
+        Some(path) => {
+            let e: Error = process::Command::new(path)
+                .arg0(&expanded_cmd_parts[0])
+                .args(&expanded_cmd_parts[1..])
+                .env_clear()
+                .envs(environment)
+                .exec();
+            eprintln!("Failed to execute command: {}", e);
+            126
+        }
+        None => {
+            eprintln!("Command not found: {}", expanded_cmd_parts[0]);
+            127
+        }
+    }
+}
+
+/// The descriptors a redirection will replace or close.
+fn redirected_fds(redirect: &Redirect) -> Vec<RawFd> {
+    match redirect.kind {
+        RedirectKind::OutputBoth | RedirectKind::AppendBoth => vec![libc::STDOUT_FILENO, libc::STDERR_FILENO],
+        _ => vec![redirect.fd],
     }
+}
 
-    let expanded_cmd_parts: Vec<String> = expand_aliases(cmd_parts);
+/// Expands the target of a redirection and performs it on the current process's descriptors.
+fn apply_redirect(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, redirect: &Redirect) -> Result<(), String> {
+    let target: String = expand_word(state, conf, job_control, &redirect.target)?;
 
-    let output: String = if expanded_cmd_parts[0].as_str().starts_with('.') {
-        execute_file(&command[1..], &expanded_cmd_parts[1..])
-    } else {
-        match expanded_cmd_parts[0].as_str() {
-            "cd" => handle_cd(&expanded_cmd_parts),
-            "history" => handle_history(&expanded_cmd_parts),
-            "alias" => handle_alias(state, &expanded_cmd_parts),
-            "rmalias" => handle_remove_alias(&expanded_cmd_parts),
-            "help" => show_help(),
-            "set" => set_conf_rule(conf, &expanded_cmd_parts),
-            "unset" => unset_conf_rule(conf, &expanded_cmd_parts),
-            "rconf" => read_conf(conf, &expanded_cmd_parts),
-            "jobs" => handle_jobs(job_control),
-            "pwd" => env::current_dir().unwrap().to_str().unwrap().to_string(),
-            "settings" => handle_settings(conf, &expanded_cmd_parts),
-            "setprompt" => cmd_set_prompt(&expanded_cmd_parts, state),
-            "exit" | "reset" | "fg" | "bg" | "summon" | "export" => {
-                return format!("Command '{}' is not supported with output redirection", expanded_cmd_parts[0]);
+    let file: File = match redirect.kind {
+        RedirectKind::DupInput | RedirectKind::DupOutput => {
+            if target == "-" {
+                unsafe {
+                    libc::close(redirect.fd);
+                }
+                return Ok(());
             }
-            _ => {
-                // If not a built-in command, execute as an external command
-                execute_external_command(&expanded_cmd_parts[0], &expanded_cmd_parts, true, job_control)
+            let source: RawFd = match target.parse::<RawFd>() {
+                Ok(fd) if fd >= 0 => fd,
+                _ => return Err(format!("{}: ambiguous redirect", target)),
+            };
+            if source != redirect.fd && unsafe { libc::dup2(source, redirect.fd) } == -1 {
+                return Err(format!("{}: {}", source, Error::last_os_error()));
             }
+            return Ok(());
+        }
+        RedirectKind::HereString => file_with_contents(&format!("{}\n", target))
+            .map_err(|e| format!("Failed to create here-string: {}", e))?,
+        RedirectKind::HereDoc => file_with_contents(&target)
+            .map_err(|e| format!("Failed to create here-document: {}", e))?,
+        _ => {
+            let mut file_options: OpenOptions = OpenOptions::new();
+            match redirect.kind {
+                RedirectKind::Input => {
+                    file_options.read(true);
+                }
+                RedirectKind::ReadWrite => {
+                    file_options.read(true).write(true).create(true);
+                }
+                RedirectKind::Append | RedirectKind::AppendBoth => {
+                    file_options.append(true).create(true);
+                }
+                _ => {
+                    file_options.write(true).create(true).truncate(true);
+                }
+            }
+            file_options.open(&target).map_err(|e| format!("{}: {}", target, e))?
         }
     };
 
-    let mut file_options: OpenOptions = OpenOptions::new();
-    file_options.write(true).create(true);
-    if append_mode {
-        file_options.append(true);
-    } else {
-        file_options.truncate(true);
+    let fds: Vec<RawFd> = redirected_fds(redirect);
+    if fds.contains(&file.as_raw_fd()) {
+        // The fd being redirected was closed, so open handed out that very one: keep it, minus close-on-exec
+        let opened: RawFd = file.into_raw_fd();
+        if unsafe { libc::fcntl(opened, libc::F_SETFD, 0) } == -1 {
+            return Err(format!("{}: {}", target, Error::last_os_error()));
+        }
+        for fd in fds.into_iter().filter(|fd| *fd != opened) {
+            if unsafe { libc::dup2(opened, fd) } == -1 {
+                return Err(format!("{}: {}", target, Error::last_os_error()));
+            }
+        }
+        return Ok(());
+    }
+    for fd in fds {
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(format!("{}: {}", target, Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// Runs a command list in a forked child and returns what it wrote to stdout, minus trailing newlines.
+/// The child's exit status becomes $?.
+pub fn command_substitution(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, list: &List) -> String {
+    let (read_fd, write_fd) = match create_pipe() {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("Failed to create pipe: {}", e);
+            return String::new();
+        }
+    };
+
+    let _ = std::io::stdout().flush();
+    let pid: libc::pid_t = unsafe { libc::fork() };
+    if pid == 0 {
+        unsafe {
+            libc::dup2(write_fd, libc::STDOUT_FILENO);
+        }
+        reset_child_signals();
+        job_control.monitor = false;
+        state.subshell = true;
+        let status: i32 = special_eval(state, conf, job_control, list, true);
+        let _ = std::io::stdout().flush();
+        unsafe { libc::_exit(status) }
+    }
+
+    unsafe {
+        libc::close(write_fd);
+    }
+    // Taking ownership closes the read end once we are done with it.
+    let mut reader: File = unsafe { File::from_raw_fd(read_fd) };
+    if pid < 0 {
+        eprintln!("Failed to fork: {}", Error::last_os_error());
+        return String::new();
+    }
+
+    let mut output: Vec<u8> = Vec::new();
+    if let Err(e) = reader.read_to_end(&mut output) {
+        eprintln!("Failed to read command output: {}", e);
+    }
+    let mut status: i32 = 0;
+    unsafe {
+        libc::waitpid(pid, &mut status, 0);
+    }
+    state.last_status = exit_code_of(status);
+
+    let mut text: String = String::from_utf8_lossy(&output).into_owned();
+    while text.ends_with('\n') {
+        text.pop();
+    }
+    text
+}
+
+/// An already unlinked temporary file holding `contents`, positioned at its start.
+fn file_with_contents(contents: &str) -> std::io::Result<File> {
+    let nanos: u128 = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let path: PathBuf = env::temp_dir().join(format!("nash-{}-{}", process::id(), nanos));
+    let mut file: File = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    std::fs::remove_file(&path)?;
+    file.write_all(contents.as_bytes())?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Duplicates a descriptor out of the way so it can be put back after a redirection.
+fn save_fd(fd: RawFd) -> std::io::Result<RawFd> {
+    let copy: RawFd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) };
+    if copy == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(copy)
+}
+
+fn restore_fd(fd: RawFd, copy: RawFd) {
+    unsafe {
+        libc::dup2(copy, fd);
+        libc::close(copy);
+    }
+}
+
+fn create_pipe() -> std::io::Result<(RawFd, RawFd)> {
+    let mut fds: [RawFd; 2] = [0; 2];
+    unsafe {
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) == -1 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok((fds[0], fds[1]))
+}
+
+/// Prints a builtin's result the way the REPL always has: trimmed, with one trailing newline.
+fn print_output(output: &str) {
+    let output: &str = output.trim_end();
+    if !output.is_empty() {
+        let mut stdout: std::io::Stdout = std::io::stdout();
+        // The reader may already be gone (e.g. `help | head -1`), which is not an error for us.
+        let _ = writeln!(stdout, "{}", output);
+        let _ = stdout.flush();
+    }
+}
+
+fn print_error(message: &str) {
+    let message: &str = message.trim_end();
+    if !message.is_empty() {
+        let _ = writeln!(std::io::stderr(), "{}", message);
+    }
+}
+
+/// Prints a builtin's output or error and turns it into an exit status.
+fn report_builtin(result: Result<String, String>) -> i32 {
+    match result {
+        Ok(output) => {
+            print_output(&output);
+            0
+        }
+        Err(message) => {
+            print_error(&message);
+            1
+        }
     }
+}
+
+/// A simple command's expanded assignments and argument vector, plus the name=(...) arguments of a
+/// declaration builtin, which are assigned once the builtin has declared the names.
+type ExpandedCommand = (Vec<(String, String)>, Vec<String>, Vec<Assignment>);
 
-    match file_options.open(&file_path) {
-        Ok(mut file) => {
-            match file.write_all(output.as_bytes()) {
-                Ok(_) => NO_RESULT.to_owned(),
-                Err(e) => format!("Failed to write to file: {}", e),
+/// How deeply functions may call each other unless the function_depth rule says otherwise.
+const MAX_FUNCTION_DEPTH: usize = 1000;
+
+/// Expands the words of a simple command, then the assignments in front of it.
+/// Assignments without a command are left to eval_simple_command, which performs them in order.
+fn expand_command(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, command: &SimpleCommand) -> Result<ExpandedCommand, String> {
+    // Builtins that declare variables take NAME=value arguments without splitting or globbing the value.
+    let builtin: Option<&str> = command.words.first().and_then(|w| w.as_literal()).filter(|name| DECLARATION_BUILTINS.contains(name));
+    let mut cmd_parts: Vec<String> = Vec::new();
+    let mut arrays: Vec<Assignment> = Vec::new();
+    for word in &command.words {
+        match as_assignment(word).filter(|_| builtin.is_some()) {
+            Some(assignment) if assignment.array().is_some() => {
+                if builtin == Some("export") {
+                    return Err(format!("export: {}: arrays cannot be exported", assignment.name));
+                }
+                if state.attributes(&assignment.name).readonly {
+                    return Err(format!("{}: readonly variable", assignment.name));
+                }
+                cmd_parts.push(assignment.name.clone());
+                arrays.push(assignment);
             }
+            Some(_) => cmd_parts.push(expand_word(state, conf, job_control, word)?),
+            None => cmd_parts.extend(expand_words(state, conf, job_control, std::slice::from_ref(word))?),
         }
-        Err(e) => format!("Failed to open file: {}", e),
     }
+
+    let mut assignments: Vec<(String, String)> = Vec::new();
+    if !cmd_parts.is_empty() {
+        for assignment in &command.assignments {
+            if assignment.index.is_some() || assignment.array().is_some() {
+                return Err(format!("{}: arrays can't be passed to a command", assignment.name));
+            }
+            let mut value: String = expand_word(state, conf, job_control, &assignment.value)?;
+            if assignment.append {
+                value.insert_str(0, &state.get_var(&assignment.name).unwrap_or_default());
+            }
+            assignments.push((assignment.name.clone(), value));
+        }
+    }
+    Ok((assignments, cmd_parts, arrays))
 }
 
-pub fn execute_external_command(cmd: &str, cmd_parts: &[String], internal: bool, job_control: &mut JobControl) -> String {
-    match find_command_in_path(cmd) {
-        Some(path) => {
-            let mut command: Command = Command::new(path);
-            if cmd_parts.len() > 1 {
-                command.args(&cmd_parts[1..]);
+/// Performs a NAME=value, NAME+=value, NAME[index]=value or NAME=(...) assignment.
+fn assign(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, assignment: &Assignment) -> Result<(), String> {
+    let name: &str = &assignment.name;
+    let elements: &[ArrayElement] = match (assignment.array(), &assignment.index) {
+        (Some(_), Some(index)) => return Err(format!("{}[{}]: cannot assign a list to an array element", name, index)),
+        (Some(elements), None) => elements,
+        (None, Some(index)) => {
+            let key: String = expand_subscript(state, conf, job_control, name, index)?;
+            let mut value: String = expand_word(state, conf, job_control, &assignment.value)?;
+            if assignment.append {
+                value = appended(state, state.get_element(name, &key), &value, name);
             }
+            return state.set_element(name, &key, &value);
+        }
+        (None, None) => {
+            let mut value: String = expand_word(state, conf, job_control, &assignment.value)?;
+            if assignment.append {
+                value = appended(state, state.get_var(name), &value, name);
+            }
+            return state.set_var(name, &value);
+        }
+    };
 
-            command.process_group(0); // Create a new process group
+    let associative: bool = state.is_associative(name);
+    let mut value: Value = match state.get_value(name) {
+        Some(Value::Scalar(old)) if assignment.append => Value::Indexed(BTreeMap::from([(0, old)])),
+        Some(old) if assignment.append => old,
+        _ if associative => Value::Associative(BTreeMap::new()),
+        _ => Value::Indexed(BTreeMap::new()),
+    };
+    let mut next: usize = value.next_index();
+    for element in elements {
+        match &element.index {
+            Some(index) => {
+                let key: String = expand_subscript(state, conf, job_control, name, index)?;
+                value.set(&key, &expand_word(state, conf, job_control, &element.value)?);
+                if let Ok(position) = key.parse::<usize>() {
+                    next = position + 1;
+                }
+            }
+            None if associative => return Err(format!("{}: {}: must use a subscript when assigning an associative array", name, element.value)),
+            None => {
+                for item in expand_words(state, conf, job_control, std::slice::from_ref(&element.value))? {
+                    value.set(&next.to_string(), &item);
+                    next += 1;
+                }
+            }
+        }
+    }
+    state.set_value(name, value)
+}
 
-            if internal {
-                command.stdin(Stdio::null());
-                command.stdout(Stdio::piped());
-                command.stderr(Stdio::piped());
-            } else {
-                command.stdin(Stdio::inherit());
-                command.stdout(Stdio::piped());
-                command.stderr(Stdio::piped());
+/// What name+=value assigns: the two values concatenated, or added for an integer variable.
+fn appended(state: &ShellState, old: Option<String>, value: &str, name: &str) -> String {
+    let old: String = old.unwrap_or_default();
+    match state.attributes(name).integer {
+        true => format!("({}) + ({})", if old.is_empty() { "0" } else { &old }, value),
+        false => old + value,
+    }
+}
+
+/// Prints a builtin's result; if it succeeded, makes the name=(...) assignments among its arguments.
+fn finish_builtin(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, result: Result<String, String>, arrays: &[Assignment]) -> i32 {
+    let mut status: i32 = report_builtin(result);
+    if let Some(code) = state.builtin_status.take() {
+        status = code;
+    }
+    if status == 0 {
+        for assignment in arrays {
+            // The builtin may just have made the name readonly, which doesn't stop the value given with it.
+            let readonly: bool = state.attributes(&assignment.name).readonly;
+            if let Some(variable) = state.variable_mut(&assignment.name) {
+                variable.attributes.readonly = false;
+            }
+            let result: Result<(), String> = assign(state, conf, job_control, assignment);
+            if let Some(variable) = state.variable_mut(&assignment.name) {
+                variable.attributes.readonly = readonly;
             }
+            if let Err(e) = result {
+                print_error(&e);
+                status = 1;
+            }
+        }
+    }
+    status
+}
 
-            match command.spawn() {
-                Ok(child) => {
-                    let pid: i32 = child.id() as libc::pid_t;
-                    let cmd_string: String = cmd_parts.join(" ");
-                    job_control.add_job(pid, cmd_string.clone());
+/// Expands and runs a single command, returning its exit status.
+fn eval_simple_command(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, command: &SimpleCommand, internal: bool) -> i32 {
+    let (assignments, cmd_parts, arrays): ExpandedCommand = match expand_command(state, conf, job_control, command) {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            print_error(&e);
+            return 1;
+        }
+    };
 
-                    if !internal {
-                        // Give terminal control to the child process group
-                        unsafe {
-                            libc::tcsetpgrp(libc::STDIN_FILENO, pid);
-                        }
-                    }
+    if cmd_parts.is_empty() {
+        // A line of only assignments sets shell variables, one after the other.
+        for assignment in &command.assignments {
+            if let Err(e) = assign(state, conf, job_control, assignment) {
+                print_error(&e);
+                return 1;
+            }
+        }
+        // With no command to run, the status is that of the last command substitution, if any.
+        let substituted: bool = command.assignments.iter().any(|a| a.value.has_command_substitution())
+            || command.words.iter().any(|w| w.has_command_substitution());
+        return if substituted { state.last_status } else { 0 };
+    }
 
-                    // Wait for the child process and capture its output
-                    let output: process::Output = child.wait_with_output().expect("Failed to wait on child");
+    let expanded_cmd_parts: Vec<String> = match expand_aliases(state, conf, job_control, cmd_parts) {
+        Ok(parts) if parts.is_empty() => return 0,
+        Ok(parts) => parts,
+        Err(e) => {
+            print_error(&e);
+            return 1;
+        }
+    };
 
-                    if !internal {
-                        // Always take back terminal control
-                        unsafe {
-                            libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
-                        }
-                    }
+    if let Some(function) = state.functions.get(&expanded_cmd_parts[0]).cloned() {
+        return call_function(state, conf, job_control, &function, &expanded_cmd_parts, &assignments, internal);
+    }
 
-                    let stdout: String = String::from_utf8_lossy(&output.stdout).into_owned();
-                    let stderr: String = String::from_utf8_lossy(&output.stderr).into_owned();
+    if expanded_cmd_parts[0].starts_with('.') {
+        return execute_file(&expanded_cmd_parts[0], &expanded_cmd_parts, &state.environment(&assignments), internal, job_control);
+    }
 
-                    if output.status.success() {
-                        job_control.remove_job(pid);
-                        if stdout.is_empty() && stderr.is_empty() {
-                            NO_RESULT.to_owned()
-                        } else {
-                            format!("{}{}", stdout, stderr)
-                        }
-                    } else {
-                        format!("Command failed: {}", stderr)
-                    }
-                }
-                Err(e) => format!("Failed to execute command: {}", e),
+    match run_builtin(state, conf, job_control, &expanded_cmd_parts) {
+        Some(result) => finish_builtin(state, conf, job_control, result, &arrays),
+        None => {
+            // If not a built-in command, execute as an external command
+            execute_external_command(&expanded_cmd_parts[0], &expanded_cmd_parts, &state.environment(&assignments), internal, job_control)
+        }
+    }
+}
+
+/// Runs a shell function with its own positional parameters and a new frame for local variables.
+/// Assignments written before the call are local to it.
+fn call_function(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, function: &FunctionDefinition, cmd_parts: &[String], assignments: &[(String, String)], internal: bool) -> i32 {
+    let max_depth: usize = conf.get_value("function_depth").and_then(|depth| depth.parse::<usize>().ok()).unwrap_or(MAX_FUNCTION_DEPTH);
+    if state.call_stack.len() >= max_depth {
+        eprintln!("{}: maximum function nesting level exceeded ({})", function.name, max_depth);
+        return 1;
+    }
+
+    let mut frame: CallFrame = CallFrame::default();
+    for (name, value) in assignments {
+        let attributes: Attributes = Attributes { exported: true, ..Attributes::default() };
+        frame.locals.insert(name.clone(), Variable { value: Some(Value::Scalar(value.clone())), attributes });
+    }
+    state.call_stack.push(frame);
+    let positional: Vec<String> = std::mem::replace(&mut state.positional, cmd_parts[1..].to_vec());
+    // Loops in the caller can't be broken out of from inside the function.
+    let loop_depth: usize = std::mem::take(&mut state.loop_depth);
+
+    let mut status: i32 = eval_command(state, conf, job_control, &function.body, internal);
+    if let Some(ControlFlow::Return(code)) = state.control_flow {
+        state.control_flow = None;
+        status = code;
+    }
+
+    state.loop_depth = loop_depth;
+    state.positional = positional;
+    state.call_stack.pop();
+    status
+}
+
+/// Runs a builtin, or returns None if the command is not one.
+/// Ok carries the builtin's output, Err a message for stderr and a failing status.
+fn run_builtin(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl, cmd_parts: &[String]) -> Option<Result<String, String>> {
+    let result: Result<String, String> = match cmd_parts[0].as_str() {
+        "cd" => handle_cd(state, cmd_parts),
+        "history" => handle_history(cmd_parts),
+        "exit" => {
+            let code: i32 = match cmd_parts.get(1) {
+                Some(arg) => match arg.parse::<i32>() {
+                    Ok(code) => code,
+                    Err(_) => return Some(Err(format!("exit: {}: numeric argument required", arg))),
+                },
+                None => state.last_status,
+            };
+            // A forked child just ends, leaving the shell's jobs and farewell to the shell itself
+            if state.subshell {
+                let _ = std::io::stdout().flush();
+                unsafe { libc::_exit(code) }
+            }
+            if conf.is_enabled("huponexit") {
+                job_control.hang_up_jobs();
+            }
+            eprintln!("Exiting...");
+            process::exit(code);
+        }
+        "summon" => handle_summon(cmd_parts),
+        "alias" => handle_alias(cmd_parts),
+        "rmalias" => handle_remove_alias(cmd_parts),
+        "help" => Ok(show_help()),
+        "set" if cmd_parts.get(1).is_some_and(|arg| arg == "--") => {
+            state.positional = cmd_parts[2..].to_vec();
+            Ok(NO_RESULT.to_owned())
+        }
+        "set" => set_conf_rule(conf, job_control, cmd_parts),
+        "shift" => handle_shift(state, cmd_parts),
+        "break" | "continue" => handle_loop_control(state, cmd_parts),
+        ":" => Ok(NO_RESULT.to_owned()),
+        "test" | "[" => handle_test(state, cmd_parts),
+        "return" => handle_return(state, cmd_parts),
+        "local" => handle_local(state, cmd_parts),
+        "declare" | "typeset" => handle_declare(state, cmd_parts),
+        "unsetconf" => unset_conf_rule(conf, job_control, cmd_parts),
+        "unset" => handle_unset(state, cmd_parts),
+        "readonly" => handle_readonly(state, cmd_parts),
+        "rconf" => read_conf(conf, cmd_parts),
+        "reset" => Ok(reset(conf, get_nash_dir())),
+        "fg" => handle_fg(cmd_parts, job_control),
+        "bg" => handle_bg(cmd_parts, job_control),
+        "jobs" => handle_jobs(cmd_parts, job_control),
+        "kill" => handle_kill(cmd_parts, job_control),
+        "wait" => handle_wait(state, cmd_parts, job_control),
+        "disown" => handle_disown(cmd_parts, job_control),
+        "suspend" => handle_suspend(cmd_parts),
+        "pwd" => env::current_dir().map(|dir| dir.display().to_string()).map_err(|e| format!("pwd: {}", e)),
+        "settings" => handle_settings(conf, cmd_parts),
+        "TEST" => test_nash(conf, state, job_control, cmd_parts),
+        "setprompt" => cmd_set_prompt(cmd_parts, state),
+        "export" => handle_export(state, cmd_parts),
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// Runs an external program in its own process group. Its stdio is the shell's, so output
+/// goes straight to the terminal (or wherever the shell's descriptors currently point).
+/// `env_vars` is its whole environment, as built by ShellState::environment.
+pub fn execute_external_command(cmd: &str, cmd_parts: &[String], env_vars: &[(String, String)], internal: bool, job_control: &mut JobControl) -> i32 {
+    match find_command_in_path(cmd, env_vars) {
+        Some(path) => spawn_and_wait(Path::new(&path), cmd_parts, env_vars, internal, job_control),
+        None => {
+            eprintln!("Command not found: {}", cmd);
+            127
+        }
+    }
+}
+
+fn spawn_and_wait(path: &Path, cmd_parts: &[String], env_vars: &[(String, String)], internal: bool, job_control: &mut JobControl) -> i32 {
+    let mut command: process::Command = process::Command::new(path);
+    command.arg0(&cmd_parts[0]);
+    command.args(&cmd_parts[1..]);
+    command.env_clear();
+    command.envs(env_vars.iter().map(|(k, v)| (k, v)));
+    if job_control.monitor {
+        command.process_group(0); // Create a new process group
+    }
+    unsafe {
+        command.pre_exec(|| {
+            reset_child_signals();
+            Ok(())
+        });
+    }
+
+    let child: process::Child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to execute command: {}", e);
+            return if e.kind() == std::io::ErrorKind::NotFound { 127 } else { 126 };
+        }
+    };
+
+    let pid: libc::pid_t = child.id() as libc::pid_t;
+    let cmd_string: String = cmd_parts.join(" ");
+    let monitor: bool = job_control.monitor;
+    if monitor {
+        job_control.add_job(pid, cmd_string.clone(), vec![(pid, cmd_string.clone())]);
+        if !internal {
+            // Give terminal control to the child process group
+            let _ = give_terminal_to(pid);
+        }
+    }
+
+    let status: std::io::Result<(JobStatus, i32)> = job_control.wait_for_pipeline(&[pid]);
+
+    if monitor && !internal {
+        // Always take back terminal control
+        let _ = give_terminal_to(unsafe { libc::getpgrp() });
+    }
+
+    match status {
+        Ok((JobStatus::Stopped, code)) => {
+            if let Some(job) = job_control.find_job(pid) {
+                println!("\n[{}]+  Stopped                 {}", job.id, cmd_string);
             }
+            code
+        }
+        Ok((_, code)) => {
+            job_control.remove_job(pid);
+            code
+        }
+        Err(e) => {
+            eprintln!("Failed to wait for command: {}", e);
+            1
         }
-        None => format!("Command not found: {}", cmd),
     }
 }
 
-fn find_command_in_path(cmd: &str) -> Option<String> {
-    if let Ok(path) = env::var("PATH") {
+/// Looks `cmd` up in the PATH of the environment it will run with.
+fn find_command_in_path(cmd: &str, env_vars: &[(String, String)]) -> Option<String> {
+    // Paths are used as they are
+    if cmd.contains('/') {
+        return Some(cmd.to_owned());
+    }
+    if let Some((_, path)) = env_vars.iter().find(|(name, _)| name == "PATH") {
         for dir in path.split(":") {
             let full_path: String = format!("{}/{}", dir, cmd);
             if std::fs::metadata(&full_path).is_ok() {
@@ -325,67 +1191,413 @@ fn find_command_in_path(cmd: &str) -> Option<String> {
     None
 }
 
-pub fn export_env_var_eval(state: &mut ShellState, cmd_parts: &[String]) -> String {
-    if cmd_parts.len() < 2 {
-        return "Usage: export <variable>=<value>".to_owned();
+pub fn execute_file(path: &str, cmd_parts: &[String], env_vars: &[(String, String)], internal: bool, job_control: &mut JobControl) -> i32 {
+    let full_path: PathBuf = resolve_dots(path);
+
+    if full_path.is_file() {
+        spawn_and_wait(&full_path, cmd_parts, env_vars, internal, job_control)
+    } else {
+        eprintln!("File not found or not executable: {}", full_path.display());
+        127
     }
+}
 
-    for part in &cmd_parts[1..] {
-        if let Some(pos) = part.find('=') {
-            let (name, value) = part.split_at(pos);
-            let name: &str = name.trim();
-            let value: &str = &value[1..].trim(); // Skip the '=' character
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            if name.is_empty() || value.is_empty() {
-                return "Invalid export syntax. Usage: export <variable>=<value>".to_owned();
-            }
+    /// Tests that fork take turns, so that the shell waiting on its children never collects another test's.
+    static FORKS: std::sync::Mutex<()> = std::sync::Mutex::new(());
 
-            // Move from local vars to environment
-            if let Some(local_value) = state.local_vars.remove(name) {
-                env::set_var(name, local_value);
-            } else {
-                env::set_var(name, value);
-            }
-        } else {
-            // Handle exporting a variable without assignment
-            let name = part.trim();
-            if let Some(local_value) = state.local_vars.get(name) {
-                env::set_var(name, local_value);
-            } else if let Ok(env_value) = env::var(name) {
-                env::set_var(name, env_value);
-            } else {
-                return format!("Variable '{}' not found", name);
-            }
+    fn forking() -> std::sync::MutexGuard<'static, ()> {
+        FORKS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Runs `script` in a fresh shell with the given config and returns the shell afterwards.
+    fn run_with(conf: &mut Config, script: &str) -> ShellState {
+        let mut state: ShellState = ShellState::default();
+        eval(&mut state, conf, &mut JobControl::new(), script.to_owned(), true);
+        state
+    }
+
+    fn run(script: &str) -> ShellState {
+        run_with(&mut Config::default(), script)
+    }
+
+    fn var(state: &ShellState, name: &str) -> String {
+        state.get_var(name).unwrap_or_default()
+    }
+
+    #[test]
+    fn if_elif_else() {
+        assert_eq!(var(&run("if ((0)); then r=a; elif ((1)); then r=b; else r=c; fi"), "r"), "b");
+        assert_eq!(var(&run("if ((0)); then r=a; elif ((0)); then r=b; else r=c; fi"), "r"), "c");
+        assert_eq!(var(&run("if ((1))\nthen\n  r=a\nfi"), "r"), "a");
+        // An if without a branch taken succeeds.
+        assert_eq!(run("((0)); if ((0)); then :; fi").last_status, 0);
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(var(&run("i=0; while ((i < 3)); do s=$s$i; ((i++)); done"), "s"), "012");
+        assert_eq!(var(&run("i=0; until ((i == 3)); do s=$s$i; ((i++)); done"), "s"), "012");
+        assert_eq!(var(&run("for w in a \"b c\" d; do s=\"$s[$w]\"; done"), "s"), "[a][b c][d]");
+        assert_eq!(var(&run("for ((i = 0; i < 3; i++)); do s=$s$i; done"), "s"), "012");
+        assert_eq!(var(&run("set -- x y; for w; do s=$s$w; done"), "s"), "xy");
+        assert_eq!(var(&run("for w in; do s=bad; done"), "s"), "");
+    }
+
+    #[test]
+    fn break_and_continue_with_levels() {
+        let state: ShellState = run("for i in 1 2; do for j in a b; do s=$s$i$j; break 2; done; done; after=1");
+        assert_eq!(var(&state, "s"), "1a");
+        assert_eq!(var(&state, "after"), "1");
+        assert_eq!(var(&run("for i in 1 2; do for j in a b; do s=$s$i$j; continue 2; s=bad; done; done"), "s"), "1a2a");
+        assert_eq!(var(&run("for i in 1 2; do for j in a b; do s=$s$i$j; break; done; done"), "s"), "1a2a");
+        // A level past the outermost loop ends all of them.
+        assert_eq!(var(&run("for i in 1 2; do while :; do s=$s$i; break 5; done; done"), "s"), "1");
+        assert_eq!(run("for i in 1; do break 0; done").last_status, 1);
+    }
+
+    #[test]
+    fn case_patterns_and_fallthrough() {
+        assert_eq!(var(&run("case abc in x|a*) s=1;; *) s=2;; esac"), "s"), "1");
+        assert_eq!(var(&run("case abc in \"a*\") s=1;; *) s=2;; esac"), "s"), "2");
+        assert_eq!(var(&run("case abc in a*) s=1;& x) s=${s}2;; *) s=${s}3;; esac"), "s"), "12");
+        assert_eq!(var(&run("case abc in a*) s=1;;& x) s=${s}2;; *c) s=${s}3;; esac"), "s"), "13");
+        assert_eq!(run("((0)); case x in y) ;; esac").last_status, 0);
+    }
+
+    #[test]
+    fn functions_take_positional_parameters() {
+        assert_eq!(var(&run("f() { r=$2$1$#; }; f a b"), "r"), "ba2");
+        assert_eq!(var(&run("function f { r=$1; }; f x"), "r"), "x");
+        assert_eq!(var(&run("set -- outer; f() { :; }; f inner; r=$1"), "r"), "outer");
+    }
+
+    #[test]
+    fn locals_go_out_of_scope() {
+        let state: ShellState = run("x=global; f() { local x=local v=1; inner=$x; }; f; outer=$x");
+        assert_eq!(var(&state, "inner"), "local");
+        assert_eq!(var(&state, "outer"), "global");
+        assert_eq!(state.get_var("v"), None);
+        // Scoping is dynamic: functions called from f see its locals.
+        assert_eq!(var(&run("x=global; g() { r=$x; x=changed; }; f() { local x=local; g; }; f; s=$x"), "r"), "local");
+        assert_eq!(var(&run("x=global; g() { x=changed; }; f() { local x; g; }; f"), "x"), "global");
+        assert_eq!(run("local x=1").last_status, 1);
+    }
+
+    #[test]
+    fn return_ends_the_function() {
+        let state: ShellState = run("f() { return 3; r=bad; }; f; s=$?");
+        assert_eq!(var(&state, "s"), "3");
+        assert_eq!(state.get_var("r"), None);
+        assert_eq!(var(&run("f() { ((0)); return; }; f; s=$?"), "s"), "1");
+        assert_eq!(var(&run("f() { for i in 1 2; do while :; do return 4; done; done; }; f; s=$?"), "s"), "4");
+        assert_eq!(run("return 2").last_status, 1);
+    }
+
+    #[test]
+    fn recursion_is_limited() {
+        assert_eq!(var(&run("f() { if (($1 > 0)); then f $(($1 - 1)); n=$n$1; fi; }; f 3"), "n"), "123");
+        let mut conf: Config = Config::default();
+        conf.set_rule("function_depth", "5", true);
+        let state: ShellState = run_with(&mut conf, "f() { n=$((n + 1)); f; }; f");
+        assert_eq!(var(&state, "n"), "5");
+        assert!(state.call_stack.is_empty());
+    }
+
+    #[test]
+    fn declare_and_unset_functions() {
+        let mut state: ShellState = run("f() { echo hi; }; g() { :; }");
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+        assert_eq!(handle_declare(&mut state, &args(&["declare", "-F"])), Ok("declare -f f\ndeclare -f g".to_owned()));
+        assert_eq!(handle_declare(&mut state, &args(&["declare", "-f", "f"])), Ok("f () { echo hi; }".to_owned()));
+        assert!(handle_declare(&mut state, &args(&["declare", "-f", "h"])).is_err());
+        let state: ShellState = run("f() { r=1; }; unset -f f; f");
+        assert!(state.functions.is_empty());
+        assert_eq!(state.get_var("r"), None);
+        assert_eq!(state.last_status, 127);
+    }
+
+    #[test]
+    fn conditional_expressions() {
+        let status = |script: &str| -> i32 { run(script).last_status };
+        assert_eq!(status("x='a  b'; [[ $x == 'a  b' ]]"), 0);
+        assert_eq!(status("[[ abc == a* ]]"), 0);
+        assert_eq!(status("[[ abc == \"a*\" ]]"), 1);
+        assert_eq!(status("[[ abc != @(x|a*) ]]"), 1);
+        assert_eq!(status("[[ -n $unset || 1 -eq 1 ]]"), 0);
+        assert_eq!(status("[[ ! (a == b) && 2 -gt 1+0 ]]"), 0);
+        assert_eq!(status("[[ -z $unset && $unset ]]"), 1);
+        assert_eq!(status("[[ a < b ]]"), 0);
+        assert_eq!(status("[[ x -eq ]]"), 2);
+        assert_eq!(status("[ a = a ]"), 0);
+        assert_eq!(status("test a = b"), 1);
+        assert_ne!(status("[ a = a"), 0);
+    }
+
+    #[test]
+    fn regex_matches_fill_bash_rematch() {
+        let state: ShellState = run("[[ foo123 =~ [0-9]+ ]]");
+        assert_eq!(state.last_status, 0);
+        assert_eq!(var(&state, "BASH_REMATCH"), "123");
+        // Quoted parts of the regex match literally.
+        assert_eq!(run("[[ x =~ \".\" ]]").last_status, 1);
+        assert_eq!(run("[[ a.c =~ ^a\".\"c$ ]]").last_status, 0);
+        assert_eq!(run("[[ a =~ ( ]]").last_status, 2);
+    }
+
+    #[test]
+    fn indexed_arrays() {
+        let state: ShellState = run("a=(x 'y z' w); n=${#a[@]}; e=${a[1]}; s=$a; a+=(v); last=${a[3]}; k=\"${!a[*]}\"");
+        assert_eq!(var(&state, "n"), "3");
+        assert_eq!(var(&state, "e"), "y z");
+        assert_eq!(var(&state, "s"), "x");
+        assert_eq!(var(&state, "last"), "v");
+        assert_eq!(var(&state, "k"), "0 1 2 3");
+        let state: ShellState = run("a[5]=x; a[1]=y; n=${#a[@]}; all=\"${a[*]}\"; i=0; b=(p q); j=${b[i+1]}");
+        assert_eq!(var(&state, "n"), "2");
+        assert_eq!(var(&state, "all"), "y x");
+        assert_eq!(var(&state, "j"), "q");
+    }
+
+    #[test]
+    fn array_fields() {
+        let state: ShellState = run("a=('p q' r); n=0; for w in \"${a[@]}\"; do n=$((n+1)); done; m=0; for w in ${a[@]}; do m=$((m+1)); done");
+        assert_eq!(var(&state, "n"), "2");
+        assert_eq!(var(&state, "m"), "3");
+    }
+
+    #[test]
+    fn associative_arrays() {
+        let state: ShellState = run("declare -A m; m[one]=1; m[two]=2; keys=\"${!m[@]}\"; v=${m[two]}; n=${#m[@]}");
+        assert_eq!(var(&state, "keys"), "one two");
+        assert_eq!(var(&state, "v"), "2");
+        assert_eq!(var(&state, "n"), "2");
+        let state: ShellState = run("declare -A m=([a]=1 [b]=2); s=\"${m[a]}${m[b]}\"");
+        assert_eq!(var(&state, "s"), "12");
+    }
+
+    #[test]
+    fn regex_groups_fill_bash_rematch_array() {
+        let state: ShellState = run("[[ key=value =~ ^([a-z]+)=(.*)$ ]]; k=${BASH_REMATCH[1]}; v=${BASH_REMATCH[2]}; all=$BASH_REMATCH");
+        assert_eq!(var(&state, "k"), "key");
+        assert_eq!(var(&state, "v"), "value");
+        assert_eq!(var(&state, "all"), "key=value");
+    }
+
+    #[test]
+    fn readonly_variables_cannot_change() {
+        let state: ShellState = run("readonly r=1; r=2; s=$?");
+        assert_eq!(var(&state, "r"), "1");
+        assert_eq!(var(&state, "s"), "1");
+        assert_eq!(var(&run("readonly r=1; unset r; s=$?"), "r"), "1");
+        assert_eq!(var(&run("readonly r=1; declare r=3"), "r"), "1");
+        assert_eq!(var(&run("readonly r=1; ((r++))"), "r"), "1");
+        assert_eq!(var(&run("readonly a=(x); a[1]=y; n=${#a[@]}"), "n"), "1");
+        // The value given along with readonly is still assigned.
+        assert_eq!(var(&run("declare -r r=1"), "r"), "1");
+    }
+
+    #[test]
+    fn declare_attributes() {
+        let state: ShellState = run("declare -i n=2+3; n+=1; declare -u up=abc; declare -l low=ABC; low+=DEF");
+        assert_eq!(var(&state, "n"), "6");
+        assert_eq!(var(&state, "up"), "ABC");
+        assert_eq!(var(&state, "low"), "abcdef");
+        let state: ShellState = run("x=1; declare -n ref=x; ref=2; y=$ref; unset -n ref");
+        assert_eq!(var(&state, "x"), "2");
+        assert_eq!(var(&state, "y"), "2");
+        assert_eq!(state.get_var("ref"), None);
+        let state: ShellState = run("export e=1; export -n e; f=1; export f");
+        assert!(!state.attributes("e").exported);
+        assert!(state.attributes("f").exported);
+        assert!(state.environment(&[]).contains(&("f".to_owned(), "1".to_owned())));
+        let state: ShellState = run("a=(x y); export a; s=$?; declare -A m; export m; t=$?");
+        assert_eq!(var(&state, "s"), "1");
+        assert_eq!(var(&state, "t"), "1");
+        assert!(!state.attributes("a").exported);
+        assert!(!state.attributes("m").exported);
+        assert_eq!(run("declare -A m; declare -a m").last_status, 1);
+        assert_eq!(run("declare 1x=2").last_status, 1);
+    }
+
+    #[test]
+    fn declare_prints_variables() {
+        let mut state: ShellState = run("s='a \"b\"'; declare -ix i=3; a=(x y); declare -A m=([k]=v); declare u");
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+        let printed = |state: &mut ShellState, name: &str| -> Result<String, String> { handle_declare(state, &args(&["declare", "-p", name])) };
+        assert_eq!(printed(&mut state, "s"), Ok("declare -- s=\"a \\\"b\\\"\"".to_owned()));
+        assert_eq!(printed(&mut state, "i"), Ok("declare -ix i=\"3\"".to_owned()));
+        assert_eq!(printed(&mut state, "a"), Ok("declare -a a=([0]=\"x\" [1]=\"y\")".to_owned()));
+        assert_eq!(printed(&mut state, "m"), Ok("declare -A m=([k]=\"v\")".to_owned()));
+        assert_eq!(printed(&mut state, "u"), Ok("declare -- u".to_owned()));
+        assert!(printed(&mut state, "missing").is_err());
+    }
+
+    #[test]
+    fn locals_keep_their_attributes_in_scope() {
+        let state: ShellState = run("x=global; f() { local -i x=1+1; inner=$x; declare y=local; declare -g z=global; }; f");
+        assert_eq!(var(&state, "inner"), "2");
+        assert_eq!(var(&state, "x"), "global");
+        assert_eq!(state.get_var("y"), None);
+        assert_eq!(var(&state, "z"), "global");
+        // Unsetting a local doesn't uncover the global variable.
+        assert_eq!(var(&run("x=global; f() { local x=local; unset x; r=${x-none}; }; f"), "r"), "none");
+    }
+
+    #[test]
+    fn unset_elements() {
+        let state: ShellState = run("a=(x y z); unset 'a[1]'; n=${#a[@]}; all=\"${a[*]}\"; unset 'a[-1]'; m=${#a[@]}");
+        assert_eq!(var(&state, "n"), "2");
+        assert_eq!(var(&state, "all"), "x z");
+        assert_eq!(var(&state, "m"), "1");
+    }
+
+    #[test]
+    fn unset_leaves_config_rules_to_unsetconf() {
+        let mut conf: Config = Config::default();
+        conf.set_rule("x", "1", true);
+        let state: ShellState = run_with(&mut conf, "x=1; unset x true");
+        assert_eq!(state.get_var("x"), None);
+        assert_eq!(conf.get_rule("x", true), Some("1"));
+        run_with(&mut conf, "unsetconf x true");
+        assert_eq!(conf.get_rule("x", true), None);
+    }
+
+    #[test]
+    fn brace_groups_run_in_the_shell() {
+        let state: ShellState = run("x=1; { x=2; y=3; }; { ((0)); }; s=$?");
+        assert_eq!(var(&state, "x"), "2");
+        assert_eq!(var(&state, "y"), "3");
+        assert_eq!(var(&state, "s"), "1");
+    }
+
+    #[test]
+    fn redirects_onto_a_closed_fd() {
+        // The lowest free descriptor is the one open hands out next
+        let fd: RawFd = unsafe { libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY) };
+        unsafe {
+            libc::close(fd);
         }
+        let path: PathBuf = env::temp_dir().join(format!("nash-test-{}-closed-fd", process::id()));
+        run(&format!("{{ pwd; }} {}>{} >&{}", fd, path.display(), fd));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", env::current_dir().unwrap().display()));
+        let _ = std::fs::remove_file(&path);
     }
 
-    NO_RESULT.to_owned()
-}
+    #[test]
+    fn subshells_keep_changes_to_themselves() {
+        let _forking = forking();
+        let cwd: std::path::PathBuf = std::env::current_dir().unwrap();
+        let state: ShellState = run("x=1; (x=2; y=3; exit 4); s=$?; (cd /); ((0)) || (:); t=$?");
+        assert_eq!(var(&state, "x"), "1");
+        assert_eq!(state.get_var("y"), None);
+        assert_eq!(var(&state, "s"), "4");
+        assert_eq!(var(&state, "t"), "0");
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+    }
 
-pub fn execute_file(path: &str, args: &[String]) -> String {
-    let full_path: PathBuf = if path.starts_with('/') {
-        PathBuf::from(path)
-    } else {
-        env::current_dir().unwrap_or(PathBuf::from("/")).join(path)
-    };
+    #[test]
+    fn expansion_errors_end_a_subshell() {
+        let _forking = forking();
+        let state: ShellState = run("x=1; (: ${unset:?oops}; x=2; exit 3); s=$?; after=yes");
+        assert_eq!(var(&state, "s"), "1");
+        assert_eq!(var(&state, "after"), "yes");
+        // The rest of the line is dropped, as in an interactive shell, and loops and functions unwind.
+        let state: ShellState = run("f() { for i in 1 2; do : ${unset:?}; n=$i; done; r=1; }; f; after=yes");
+        assert_eq!(state.get_var("n"), None);
+        assert_eq!(state.get_var("r"), None);
+        assert_eq!(state.get_var("after"), None);
+        assert_eq!(state.last_status, 1);
+    }
 
-    if full_path.is_file() {
-        let output: Result<process::Output, Error> = Command::new(&full_path).args(args).output();
-
-        match output {
-            Ok(output) => {
-                if output.status.success() {
-                    String::from_utf8_lossy(&output.stdout).into_owned()
-                } else {
-                    format!(
-                        "Program failed and output error: {}",
-                        String::from_utf8_lossy(&output.stderr)
-                    )
-                }
+    #[test]
+    fn background_jobs_are_numbered_and_set_last_pid() {
+        let _forking = forking();
+        let mut state: ShellState = ShellState::default();
+        let mut job_control: JobControl = JobControl::new();
+        let status: i32 = eval(&mut state, &mut Config::default(), &mut job_control, "x=1 && (exit 3) & p=$!".to_owned(), true);
+        assert_eq!(status, 0);
+        let pid: libc::pid_t = state.last_background_pid.unwrap();
+        assert_eq!(var(&state, "p"), pid.to_string());
+        // The background job's assignment happened in the child.
+        assert_eq!(state.get_var("x"), None);
+        let job: crate::jobs::Job = job_control.get_job(1).cloned().unwrap();
+        assert_eq!((job.pgid, job.command.as_str()), (pid, "x=1 && ( exit 3 )"));
+        assert_eq!(job_control.wait_for_job(pid).unwrap(), (JobStatus::Done, 3));
+    }
+
+    #[test]
+    fn pipelines_become_one_job() {
+        let _forking = forking();
+        let mut state: ShellState = ShellState::default();
+        let mut job_control: JobControl = JobControl::new();
+        eval(&mut state, &mut Config::default(), &mut job_control, "(exit 1) | (exit 2) &".to_owned(), true);
+        let job: crate::jobs::Job = job_control.get_job(1).cloned().unwrap();
+        let stages: Vec<&str> = job.processes.iter().map(|process| process.command.as_str()).collect();
+        assert_eq!(stages, vec!["( exit 1 )", "( exit 2 )"]);
+        assert_eq!(state.last_background_pid, job.processes.last().map(|process| process.pid));
+        assert_eq!(job_control.wait_for_job(job.pgid).unwrap(), (JobStatus::Done, 2));
+        assert_eq!(job_control.take_notices(), Vec::<String>::new());
+        let status: i32 = eval(&mut state, &mut Config::default(), &mut job_control, "(exit 1) | (exit 2)".to_owned(), true);
+        assert_eq!(status, 2);
+        assert!(job_control.jobs.is_empty());
+    }
+
+    #[test]
+    fn wait_and_kill_jobs() {
+        let _forking = forking();
+        let mut state: ShellState = ShellState::default();
+        let mut job_control: JobControl = JobControl::new();
+        let mut run = |script: &str| -> i32 { eval(&mut state, &mut Config::default(), &mut job_control, script.to_owned(), true) };
+        assert_eq!(run("(exit 3) & wait $!"), 3);
+        assert_eq!(run("(exit 4) & (exit 5) & wait %1 %2"), 5);
+        assert_eq!(run("(while :; do :; done) & kill %1; wait %1"), 128 + libc::SIGTERM);
+        assert_eq!(run("wait %1"), 127);
+        assert_eq!(run("(exit 6) & disown; wait"), 0);
+        assert_eq!(run("kill -s NOPE %1"), 1);
+    }
+
+    #[test]
+    fn hung_up_jobs_die() {
+        let _forking = forking();
+        // A subshell is forked without exec, so it would keep the shell's own handler unless that is reset.
+        unsafe {
+            libc::signal(libc::SIGHUP, crate::jobs::handle_sighup as *const () as libc::sighandler_t);
+        }
+        let mut state: ShellState = ShellState::default();
+        let mut job_control: JobControl = JobControl::new();
+        eval(&mut state, &mut Config::default(), &mut job_control, "(while :; do :; done; exit 0) &".to_owned(), true);
+        let pid: libc::pid_t = state.last_background_pid.unwrap();
+        // Give the child time to reset its signals and get into the loop.
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        job_control.hang_up_jobs();
+
+        let mut status: i32 = 0;
+        for _ in 0..50 {
+            if unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } == pid {
+                break;
             }
-            Err(e) => format!("Failed to execute file: {}", e),
+            std::thread::sleep(std::time::Duration::from_millis(100));
         }
-    } else {
-        format!("File not found or not executable: {}", full_path.display())
+        let survived: bool = unsafe { libc::kill(pid, libc::SIGKILL) } == 0;
+        if survived {
+            unsafe {
+                libc::waitpid(pid, &mut status, 0);
+            }
+        }
+        assert!(!survived);
+        assert_eq!(exit_code_of(status), 128 + libc::SIGHUP);
+    }
+
+    #[test]
+    fn subshells_list_their_background_jobs() {
+        let _forking = forking();
+        assert_eq!(var(&run("( (exit 4) & wait %1 ); s=$?"), "s"), "4");
+        assert_eq!(var(&run("( (exit 4) & (exit 5) & wait %2 %1 ); s=$?"), "s"), "4");
+        assert_eq!(var(&run("( (while :; do :; done) & kill %1; wait $! ); s=$?"), "s"), (128 + libc::SIGTERM).to_string());
+        assert_eq!(var(&run("(fg); s=$?"), "s"), "1");
     }
 }
@@ -7,26 +7,421 @@ use termion::raw::IntoRawMode;
 use termion::cursor::Goto;
 use termion::clear;
 use std::env;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use crate::ast::FunctionDefinition;
 
+#[derive(Default)]
 pub struct ShellState {
     pub hostname: String,
     pub username: String,
     pub history_limit: usize,
     pub ps1_prompt: String,
-    pub local_vars: HashMap<String, String>,
+    /// Global variables. Those of running functions are in their call frames.
+    pub variables: HashMap<String, Variable>,
+    /// Exit status of the last command, as seen by $?
+    pub last_status: i32,
+    /// Process id of the last background job, as seen by $!
+    pub last_background_pid: Option<i32>,
+    /// Set by a builtin whose exit status is more than success or failure, such as wait.
+    pub builtin_status: Option<i32>,
+    /// True in a forked child, such as a subshell, pipeline stage or command substitution, whose exit is silent.
+    pub subshell: bool,
+    /// $1, $2, ... as passed to the running script
+    pub positional: Vec<String>,
+    /// How many loops are currently running, which bounds break and continue.
+    pub loop_depth: usize,
+    /// Set by break, continue and return; commands are skipped until the loop or function it targets handles it.
+    pub control_flow: Option<ControlFlow>,
+    pub functions: HashMap<String, FunctionDefinition>,
+    /// One frame per running function call, innermost last.
+    pub call_stack: Vec<CallFrame>,
+}
+
+#[derive(Debug, Default)]
+pub struct CallFrame {
+    /// Variables declared with `local`. One declared without a value still hides outer ones.
+    pub locals: HashMap<String, Variable>,
+}
+
+/// How many namerefs may be followed before giving up, which stops reference loops.
+const MAX_NAMEREF_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variable {
+    /// None for a variable that has attributes but was never given a value.
+    pub value: Option<Value>,
+    pub attributes: Attributes,
+}
+
+/// What declare, export and readonly set on a variable. Whether it is an array is told by its value.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Attributes {
+    /// Passed on to child processes.
+    pub exported: bool,
+    pub readonly: bool,
+    /// Values assigned to it are evaluated as arithmetic.
+    pub integer: bool,
+    pub lowercase: bool,
+    pub uppercase: bool,
+    /// Its value is the name of another variable, which is used in its place.
+    pub nameref: bool,
+}
+
+/// The value of a shell variable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(String),
+    /// a=(x y z). Elements may be missing, as after a[10]=x.
+    Indexed(BTreeMap<usize, String>),
+    /// declare -A m; m[key]=value
+    Associative(BTreeMap<String, String>),
+}
+
+impl Value {
+    pub fn is_array(&self) -> bool {
+        !matches!(self, Value::Scalar(_))
+    }
+
+    /// What $name expands to: element 0 of an array.
+    pub fn as_scalar(&self) -> Option<String> {
+        match self {
+            Value::Scalar(value) => Some(value.clone()),
+            _ => self.get("0"),
+        }
+    }
+
+    /// The element under `key`, which for an indexed array is a non-negative number.
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self {
+            Value::Scalar(value) => (key == "0").then(|| value.clone()),
+            Value::Indexed(elements) => key.parse::<usize>().ok().and_then(|index| elements.get(&index).cloned()),
+            Value::Associative(elements) => elements.get(key).cloned(),
+        }
+    }
+
+    /// Sets an element, turning a scalar into an indexed array whose element 0 is the old value.
+    pub fn set(&mut self, key: &str, element: &str) {
+        if let Value::Scalar(value) = self {
+            if key == "0" {
+                *value = element.to_owned();
+                return;
+            }
+            *self = Value::Indexed(BTreeMap::from([(0, std::mem::take(value))]));
+        }
+        match self {
+            Value::Indexed(elements) => {
+                if let Ok(index) = key.parse::<usize>() {
+                    elements.insert(index, element.to_owned());
+                }
+            }
+            Value::Associative(elements) => {
+                elements.insert(key.to_owned(), element.to_owned());
+            }
+            Value::Scalar(_) => {}
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        match self {
+            Value::Scalar(value) => {
+                if key == "0" {
+                    value.clear();
+                }
+            }
+            Value::Indexed(elements) => {
+                if let Ok(index) = key.parse::<usize>() {
+                    elements.remove(&index);
+                }
+            }
+            Value::Associative(elements) => {
+                elements.remove(key);
+            }
+        }
+    }
+
+    pub fn values(&self) -> Vec<String> {
+        match self {
+            Value::Scalar(value) => vec![value.clone()],
+            Value::Indexed(elements) => elements.values().cloned().collect(),
+            Value::Associative(elements) => elements.values().cloned().collect(),
+        }
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        match self {
+            Value::Scalar(_) => vec!["0".to_owned()],
+            Value::Indexed(elements) => elements.keys().map(|index| index.to_string()).collect(),
+            Value::Associative(elements) => elements.keys().cloned().collect(),
+        }
+    }
+
+    /// One past the highest index, which is where a+=(...) continues.
+    pub fn next_index(&self) -> usize {
+        match self {
+            Value::Indexed(elements) => elements.keys().next_back().map_or(0, |last| last + 1),
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlFlow {
+    /// break n
+    Break(usize),
+    /// continue n
+    Continue(usize),
+    /// return n
+    Return(i32),
+    /// A fatal expansion error, such as ${name:?} or set -u on an unset name. Everything unwinds,
+    /// then a script or subshell exits with the status, while an interactive shell drops the rest of the line.
+    Exit(i32),
 }
 
 impl ShellState {
-    pub fn set_local_var(&mut self, name: &str, value: &str) {
-        self.local_vars.insert(name.to_string(), value.to_string());
+    /// The variable `name` refers to once namerefs are followed.
+    pub fn resolve_name(&self, name: &str) -> String {
+        let mut name: String = name.to_owned();
+        for _ in 0..MAX_NAMEREF_DEPTH {
+            match self.lookup(&name) {
+                Some(Variable { value: Some(Value::Scalar(target)), attributes }) if attributes.nameref && !target.is_empty() => name = target.clone(),
+                _ => break,
+            }
+        }
+        name
+    }
+
+    /// The variable itself, without following namerefs: the innermost local one, or the global one.
+    pub fn lookup(&self, name: &str) -> Option<&Variable> {
+        for frame in self.call_stack.iter().rev() {
+            if let Some(local) = frame.locals.get(name) {
+                return Some(local);
+            }
+        }
+        self.variables.get(name)
+    }
+
+    fn lookup_mut(&mut self, name: &str) -> Option<&mut Variable> {
+        for frame in self.call_stack.iter_mut().rev() {
+            if let Some(local) = frame.locals.get_mut(name) {
+                return Some(local);
+            }
+        }
+        self.variables.get_mut(name)
+    }
+
+    /// The variable `name` refers to.
+    pub fn variable(&self, name: &str) -> Option<&Variable> {
+        self.lookup(&self.resolve_name(name))
+    }
+
+    pub fn variable_mut(&mut self, name: &str) -> Option<&mut Variable> {
+        let name: String = self.resolve_name(name);
+        self.lookup_mut(&name)
+    }
+
+    pub fn attributes(&self, name: &str) -> Attributes {
+        self.variable(name).map(|variable| variable.attributes).unwrap_or_default()
+    }
+
+    /// The variable `name` for declare, local, export and readonly to change, created without a value if needed:
+    /// with `local` in the running function's frame, otherwise wherever it is in scope, or else globally.
+    pub fn declare(&mut self, name: &str, local: bool) -> &mut Variable {
+        let frame: Option<usize> = match local {
+            true => self.call_stack.len().checked_sub(1),
+            false => self.call_stack.iter().rposition(|frame| frame.locals.contains_key(name)),
+        };
+        match frame {
+            Some(depth) => self.call_stack[depth].locals.entry(name.to_owned()).or_default(),
+            None => self.variables.entry(name.to_owned()).or_default(),
+        }
+    }
+
+    /// Applies the integer and case attributes of a variable to a value being assigned to it.
+    fn convert(&mut self, attributes: Attributes, value: &str) -> Result<String, String> {
+        if attributes.integer {
+            return crate::arithmetic::evaluate(self, value).map(|n| n.to_string());
+        }
+        Ok(if attributes.lowercase {
+            value.to_lowercase()
+        } else if attributes.uppercase {
+            value.to_uppercase()
+        } else {
+            value.to_owned()
+        })
+    }
+
+    fn writable(&self, name: &str) -> Result<Attributes, String> {
+        let attributes: Attributes = self.lookup(name).map(|variable| variable.attributes).unwrap_or_default();
+        if attributes.readonly {
+            return Err(format!("{}: readonly variable", name));
+        }
+        Ok(attributes)
+    }
+
+    /// Sets a variable in the innermost function that declared it local, or globally.
+    /// On an array this sets element 0.
+    pub fn set_var(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let name: String = self.resolve_name(name);
+        if self.lookup(&name).and_then(|variable| variable.value.as_ref()).is_some_and(Value::is_array) {
+            return self.set_element(&name, "0", value);
+        }
+        self.set_value(&name, Value::Scalar(value.to_owned()))
+    }
+
+    /// Replaces the whole value of a variable, in the same scope as set_var.
+    pub fn set_value(&mut self, name: &str, mut value: Value) -> Result<(), String> {
+        let name: String = self.resolve_name(name);
+        let attributes: Attributes = self.writable(&name)?;
+        match &mut value {
+            Value::Scalar(scalar) => *scalar = self.convert(attributes, scalar)?,
+            Value::Indexed(elements) => {
+                for element in elements.values_mut() {
+                    *element = self.convert(attributes, element)?;
+                }
+            }
+            Value::Associative(elements) => {
+                for element in elements.values_mut() {
+                    *element = self.convert(attributes, element)?;
+                }
+            }
+        }
+        match self.lookup_mut(&name) {
+            Some(variable) => variable.value = Some(value),
+            None => {
+                self.variables.insert(name, Variable { value: Some(value), attributes });
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets one element of an array, creating the array if needed.
+    pub fn set_element(&mut self, name: &str, key: &str, element: &str) -> Result<(), String> {
+        let name: String = self.resolve_name(name);
+        let attributes: Attributes = self.writable(&name)?;
+        let element: String = self.convert(attributes, element)?;
+        match self.lookup_mut(&name) {
+            Some(Variable { value: Some(value), .. }) => value.set(key, &element),
+            Some(variable) => {
+                let mut value: Value = Value::Indexed(BTreeMap::new());
+                value.set(key, &element);
+                variable.value = Some(value);
+            }
+            None => {
+                let mut value: Value = Value::Indexed(BTreeMap::new());
+                value.set(key, &element);
+                self.variables.insert(name, Variable { value: Some(value), attributes });
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a variable along with its attributes.
+    /// With `nameref` a nameref itself is removed rather than the variable it refers to.
+    pub fn unset_var(&mut self, name: &str, nameref: bool) -> Result<(), String> {
+        let name: String = if nameref { name.to_owned() } else { self.resolve_name(name) };
+        self.writable(&name).map_err(|_| format!("{}: cannot unset: readonly variable", name))?;
+        // A local stays declared, so the variable it hides doesn't reappear.
+        for frame in self.call_stack.iter_mut().rev() {
+            if let Some(local) = frame.locals.get_mut(&name) {
+                *local = Variable::default();
+                return Ok(());
+            }
+        }
+        self.variables.remove(&name);
+        Ok(())
+    }
+
+    pub fn unset_element(&mut self, name: &str, key: &str) -> Result<(), String> {
+        let name: String = self.resolve_name(name);
+        self.writable(&name).map_err(|_| format!("{}: cannot unset: readonly variable", name))?;
+        if let Some(Variable { value: Some(value), .. }) = self.lookup_mut(&name) {
+            value.remove(key);
+        }
+        Ok(())
+    }
+
+    /// Every variable in scope, with locals hiding the global ones they shadow.
+    pub fn visible_variables(&self) -> BTreeMap<&str, &Variable> {
+        let mut visible: BTreeMap<&str, &Variable> = self.variables.iter().map(|(name, variable)| (name.as_str(), variable)).collect();
+        for frame in &self.call_stack {
+            visible.extend(frame.locals.iter().map(|(name, variable)| (name.as_str(), variable)));
+        }
+        visible
+    }
+
+    /// The environment of a child process: every exported variable with a value, then `assignments`.
+    pub fn environment(&self, assignments: &[(String, String)]) -> Vec<(String, String)> {
+        let mut environment: BTreeMap<String, String> = self
+            .visible_variables()
+            .into_iter()
+            .filter(|(_, variable)| variable.attributes.exported)
+            .filter_map(|(name, variable)| match &variable.value {
+                Some(Value::Scalar(value)) => Some((name.to_owned(), value.clone())),
+                _ => None,
+            })
+            .collect();
+        environment.extend(assignments.iter().cloned());
+        environment.into_iter().collect()
+    }
+
+    pub fn get_value(&self, name: &str) -> Option<Value> {
+        self.variable(name).and_then(|variable| variable.value.clone())
+    }
+
+    pub fn get_element(&self, name: &str, key: &str) -> Option<String> {
+        self.variable(name).and_then(|variable| variable.value.as_ref()).and_then(|value| value.get(key))
+    }
+
+    pub fn is_associative(&self, name: &str) -> bool {
+        matches!(self.variable(name), Some(Variable { value: Some(Value::Associative(_)), .. }))
+    }
+
+    pub fn next_index(&self, name: &str) -> usize {
+        self.variable(name).and_then(|variable| variable.value.as_ref()).map_or(0, Value::next_index)
+    }
+
+    /// What "$*" and "${name[*]}" join their values with: the first character of IFS.
+    pub fn star_separator(&self) -> String {
+        match self.get_var("IFS") {
+            Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+            None => " ".to_owned(),
+        }
     }
 
     pub fn get_var(&self, name: &str) -> Option<String> {
-        self.local_vars.get(name).cloned().or_else(|| env::var(name).ok())
+        if name == "?" {
+            return Some(self.last_status.to_string());
+        }
+        if name == "$" {
+            return Some(std::process::id().to_string());
+        }
+        if name == "!" {
+            return self.last_background_pid.map(|pid| pid.to_string());
+        }
+        match name {
+            "#" => return Some(self.positional.len().to_string()),
+            "@" => return Some(self.positional.join(" ")),
+            "*" => return Some(self.positional.join(&self.star_separator())),
+            _ => {}
+        }
+        if let Ok(index) = name.parse::<usize>() {
+            if index > 0 {
+                return self.positional.get(index - 1).cloned();
+            }
+        }
+        self.variable(name).and_then(|variable| variable.value.as_ref()).and_then(Value::as_scalar)
     }
 }
 
+/// The shell's variables when it starts: the environment it was given, all exported.
+pub fn import_environment() -> HashMap<String, Variable> {
+    env::vars()
+        .map(|(name, value)| {
+            let attributes: Attributes = Attributes { exported: true, ..Attributes::default() };
+            (name, Variable { value: Some(Value::Scalar(value)), attributes })
+        })
+        .collect()
+}
 
 pub const NO_RESULT: &str = "";
 
@@ -80,15 +475,11 @@ impl GUIMenu {
 
         for c in stdin.keys() {
             match c? {
-                Key::Up => {
-                    if self.selected > 0 {
-                        self.selected -= 1;
-                    }
+                Key::Up if self.selected > 0 => {
+                    self.selected -= 1;
                 }
-                Key::Down => {
-                    if self.selected < self.entries.len() - 1 {
-                        self.selected += 1;
-                    }
+                Key::Down if self.selected < self.entries.len() - 1 => {
+                    self.selected += 1;
                 }
                 Key::Char('\n') => {
                     self.edit_selected_entry(&mut stdout)?;
@@ -149,21 +540,17 @@ impl GUIMenu {
                                 write!(stdout, "{}", c)?;
                                 stdout.flush()?;
                             },
-                            Key::Backspace => {
-                                if !new_value.is_empty() {
-                                    new_value.pop();
-                                    write!(stdout, "{} {}", termion::cursor::Left(1), termion::cursor::Left(1))?;
-                                    stdout.flush()?;
-                                }
+                            Key::Backspace if !new_value.is_empty() => {
+                                new_value.pop();
+                                write!(stdout, "{} {}", termion::cursor::Left(1), termion::cursor::Left(1))?;
+                                stdout.flush()?;
                             },
                             _ => {}
                         }
                     }
                 }
                 
-                if entry.field_type == "int" && new_value.parse::<i64>().is_ok() {
-                    entry.value = new_value;
-                } else if entry.field_type == "text" {
+                if entry.field_type == "text" || (entry.field_type == "int" && new_value.parse::<i64>().is_ok()) {
                     entry.value = new_value;
                 }
                 
@@ -175,3 +562,32 @@ impl GUIMenu {
     }     
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scalar_becomes_array_on_element_assignment() {
+        let mut value: Value = Value::Scalar("a".to_owned());
+        value.set("0", "b");
+        assert_eq!(value, Value::Scalar("b".to_owned()));
+        value.set("2", "c");
+        assert_eq!(value, Value::Indexed(BTreeMap::from([(0, "b".to_owned()), (2, "c".to_owned())])));
+        assert_eq!(value.as_scalar(), Some("b".to_owned()));
+        assert_eq!(value.get("1"), None);
+        assert_eq!(value.keys(), vec!["0", "2"]);
+        assert_eq!(value.next_index(), 3);
+    }
+
+    #[test]
+    fn associative_arrays_keep_string_keys() {
+        let mut value: Value = Value::Associative(BTreeMap::new());
+        value.set("b", "2");
+        value.set("a", "1");
+        assert_eq!(value.keys(), vec!["a", "b"]);
+        assert_eq!(value.values(), vec!["1", "2"]);
+        assert_eq!(value.as_scalar(), None);
+        value.set("0", "zero");
+        assert_eq!(value.as_scalar(), Some("zero".to_owned()));
+    }
+}
@@ -1,5 +1,5 @@
 
-use crate::{globals::*, jobs::JobControl};
+use crate::{globals::*, jobs::{Job, JobControl}};
 use std::{collections::HashMap, fs, path::PathBuf, env};
 use libc;
 use chrono;
@@ -74,31 +74,74 @@ pub fn read_prompt_from_file() -> String {
             // Extract the prompt format from the PS1 assignment
             let ps1: String = content.strip_prefix("PS1=\"").and_then(|s| s.strip_suffix("\"")).map(|s| s.to_string()).unwrap_or_else(|| "[\\u@\\h \\w]> ".to_string());
             env::set_var("PS1", ps1.clone());
-            return ps1;
+            ps1
         },
         Err(_) => "[\\u@\\h \\w]> ".to_string(),
     }
 }
 
 pub fn parse_job_specifier(spec: &str, job_control: &JobControl) -> Result<libc::pid_t, String> {
-    if spec.starts_with('%') {
-        // Job number specified with %
-        match spec[1..].parse::<usize>() {
-            Ok(job_num) => {
-                let jobs: Vec<&crate::jobs::Job> = job_control.list_jobs();
-                if job_num < 1
-                {
-                    return std::result::Result::Err("No such job".to_owned());
+    let Some(job_spec) = spec.strip_prefix('%') else {
+        // Direct PID
+        return spec.parse::<libc::pid_t>().map_err(|_| format!("{}: not a pid or valid job spec", spec));
+    };
+
+    let job: Option<&Job> = match job_spec {
+        "" | "+" | "%" => job_control.get_current_job(),
+        "-" => job_control.get_previous_job(),
+        _ => match job_spec.parse::<usize>() {
+            Ok(job_num) => job_control.get_job(job_num),
+            Err(_) => {
+                // %?string matches a job whose command contains string, %string one whose command starts with it
+                let matches: Vec<&Job> = match job_spec.strip_prefix('?') {
+                    Some(text) => job_control.list_jobs().into_iter().filter(|job| job.command.contains(text)).collect(),
+                    None => job_control.list_jobs().into_iter().filter(|job| job.command.starts_with(job_spec)).collect(),
+                };
+                if matches.len() > 1 {
+                    return Err(format!("{}: ambiguous job spec", spec));
                 }
-                jobs.get(job_num - 1)
-                    .map(|job| job.pid)
-                    .ok_or_else(|| "No such job".to_string())
+                matches.first().copied()
             }
-            Err(_) => Err("Invalid job number".to_string()),
+        },
+    };
+    job.map(|job| job.pgid).ok_or_else(|| format!("{}: no such job", spec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job_table(commands: &[&str]) -> JobControl {
+        let mut job_control: JobControl = JobControl::new();
+        for (i, command) in commands.iter().enumerate() {
+            let pgid: libc::pid_t = 100 * (i as libc::pid_t + 1);
+            job_control.add_job(pgid, command.to_string(), vec![(pgid, command.to_string())]);
         }
-    } else {
-        // Direct PID
-        spec.parse::<libc::pid_t>()
-            .map_err(|_| "Invalid process ID".to_string())
+        job_control
+    }
+
+    #[test]
+    fn job_specifiers() {
+        let job_control: JobControl = job_table(&["sleep 10", "make all", "sleep 20 | cat"]);
+        assert_eq!(parse_job_specifier("%1", &job_control), Ok(100));
+        assert_eq!(parse_job_specifier("%", &job_control), Ok(300));
+        assert_eq!(parse_job_specifier("%%", &job_control), Ok(300));
+        assert_eq!(parse_job_specifier("%+", &job_control), Ok(300));
+        assert_eq!(parse_job_specifier("%-", &job_control), Ok(200));
+        assert_eq!(parse_job_specifier("%make", &job_control), Ok(200));
+        assert_eq!(parse_job_specifier("%?cat", &job_control), Ok(300));
+        assert_eq!(parse_job_specifier("1234", &job_control), Ok(1234));
+        assert_eq!(parse_job_specifier("%4", &job_control), Err("%4: no such job".to_owned()));
+        assert_eq!(parse_job_specifier("%cat", &job_control), Err("%cat: no such job".to_owned()));
+        assert_eq!(parse_job_specifier("x", &job_control), Err("x: not a pid or valid job spec".to_owned()));
+    }
+
+    #[test]
+    fn ambiguous_job_specifiers() {
+        let job_control: JobControl = job_table(&["sleep 10", "make all", "sleep 20 | cat"]);
+        assert_eq!(parse_job_specifier("%sleep", &job_control), Err("%sleep: ambiguous job spec".to_owned()));
+        assert_eq!(parse_job_specifier("%?a", &job_control), Err("%?a: ambiguous job spec".to_owned()));
+        assert_eq!(parse_job_specifier("%sleep 2", &job_control), Ok(300));
+        assert_eq!(parse_job_specifier("%?all", &job_control), Ok(200));
     }
 }
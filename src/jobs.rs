@@ -1,12 +1,144 @@
 use libc::{pid_t, SIGCONT, SIGTSTP};
-use std::collections::HashMap;
-use std::io::{Error, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, Result, Write};
 use nix::sys::signal::{self, SigAction, SigHandler, Signal};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 pub static RECEIVED_SIGTSTP: AtomicBool = AtomicBool::new(false);
+/// Set once the shell has been hung up on; it saves its state and exits as soon as it can.
+pub static RECEIVED_SIGHUP: AtomicBool = AtomicBool::new(false);
+/// The process group that has the terminal, or 0 while the shell has it. SIGINT is passed on to it.
+static FOREGROUND_PGID: AtomicI32 = AtomicI32::new(0);
+/// Set while the line editor is waiting for input, the only time set -b may print over the prompt.
+pub static AT_PROMPT: AtomicBool = AtomicBool::new(false);
+/// The write end of the pipe SIGCHLD wakes the job watcher through, or -1 before it is started.
+static SIGCHLD_PIPE: AtomicI32 = AtomicI32::new(-1);
 
-// TODO: bg jobs with &
+pub extern "C" fn handle_sigint(_: i32) {
+    let pgid: pid_t = FOREGROUND_PGID.load(Ordering::SeqCst);
+    if pgid > 0 {
+        unsafe {
+            libc::kill(-pgid, libc::SIGINT);
+        }
+    }
+}
+
+pub extern "C" fn handle_sighup(_: i32) {
+    RECEIVED_SIGHUP.store(true, Ordering::SeqCst);
+    // The prompt reads end of input from here on, which ends the read loop
+    unsafe {
+        let null: i32 = libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY);
+        if null != -1 {
+            libc::dup2(null, libc::STDIN_FILENO);
+            libc::close(null);
+        }
+    }
+}
+
+pub extern "C" fn handle_sigchld(_: i32) {
+    let fd: i32 = SIGCHLD_PIPE.load(Ordering::SeqCst);
+    if fd != -1 {
+        unsafe {
+            // write may clobber errno under whatever the signal interrupted
+            let errno: i32 = *libc::__errno_location();
+            libc::write(fd, [0u8].as_ptr() as *const libc::c_void, 1);
+            *libc::__errno_location() = errno;
+        }
+    }
+}
+
+/// Locks the job table, even if a panic while it was held left it poisoned.
+pub fn lock_jobs(jobs: &Mutex<JobControl>) -> MutexGuard<'_, JobControl> {
+    jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Starts a thread that announces background jobs as soon as they change state under set -b,
+/// even while the line editor on `editor` is blocked waiting for input.
+pub fn watch_children(jobs: Arc<Mutex<JobControl>>, editor: libc::pthread_t) -> Result<()> {
+    let mut fds: [i32; 2] = [0; 2];
+    unsafe {
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) == -1 {
+            return Err(Error::last_os_error());
+        }
+        // A full pipe already means the watcher has something to do
+        libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK);
+    }
+    SIGCHLD_PIPE.store(fds[1], Ordering::SeqCst);
+
+    // With SA_RESTART, so that the shell's own waits and reads carry on
+    unsafe {
+        let sigchld_action: SigAction = SigAction::new(
+            SigHandler::Handler(handle_sigchld),
+            signal::SaFlags::SA_RESTART,
+            signal::SigSet::empty(),
+        );
+        signal::sigaction(Signal::SIGCHLD, &sigchld_action).map_err(Error::from)?;
+    }
+
+    let read_end: i32 = fds[0];
+    std::thread::spawn(move || {
+        let mut buffer: [u8; 64] = [0; 64];
+        loop {
+            let read: isize = unsafe { libc::read(read_end, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if read == -1 && Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            if read <= 0 {
+                break;
+            }
+            let mut job_control: MutexGuard<'_, JobControl> = lock_jobs(&jobs);
+            // Otherwise the shell is running a command, and waits for it or reaps before the next prompt
+            if !job_control.notify || !AT_PROMPT.load(Ordering::SeqCst) {
+                continue;
+            }
+            job_control.notify = false;
+            job_control.reap_jobs();
+            job_control.notify = true;
+            let notices: Vec<String> = job_control.take_notices();
+            if notices.is_empty() {
+                continue;
+            }
+            // Clear the prompt line, and have the line editor draw it again below the notices
+            print!("\r\x1b[K");
+            for notice in notices {
+                println!("{}", notice);
+            }
+            let _ = std::io::stdout().flush();
+            unsafe {
+                libc::pthread_kill(editor, libc::SIGWINCH);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The signals kill knows by name, in the order kill -l lists them.
+pub const SIGNALS: [(&str, i32); 30] = [
+    ("HUP", libc::SIGHUP), ("INT", libc::SIGINT), ("QUIT", libc::SIGQUIT), ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP), ("ABRT", libc::SIGABRT), ("BUS", libc::SIGBUS), ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL), ("USR1", libc::SIGUSR1), ("SEGV", libc::SIGSEGV), ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE), ("ALRM", libc::SIGALRM), ("TERM", libc::SIGTERM), ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT), ("STOP", libc::SIGSTOP), ("TSTP", libc::SIGTSTP), ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU), ("URG", libc::SIGURG), ("XCPU", libc::SIGXCPU), ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM), ("PROF", libc::SIGPROF), ("WINCH", libc::SIGWINCH), ("IO", libc::SIGIO),
+    ("PWR", libc::SIGPWR), ("SYS", libc::SIGSYS),
+];
+
+/// A signal given as a number or a name, with or without SIG and in any case.
+pub fn signal_number(spec: &str) -> Option<i32> {
+    if let Ok(number) = spec.parse::<i32>() {
+        return (number == 0 || signal_name(number).is_some()).then_some(number);
+    }
+    let name: String = spec.to_uppercase();
+    let name: &str = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS.iter().find(|(known, _)| *known == name).map(|(_, number)| *number)
+}
+
+/// The name of a signal without its SIG prefix.
+pub fn signal_name(number: i32) -> Option<&'static str> {
+    SIGNALS.iter().find(|(_, known)| *known == number).map(|(name, _)| *name)
+}
 
 pub fn setup_signal_handlers() -> std::result::Result<(), nix::Error> {
     // Setup SIGTSTP (Ctrl+Z) handler
@@ -19,6 +151,26 @@ pub fn setup_signal_handlers() -> std::result::Result<(), nix::Error> {
         signal::sigaction(Signal::SIGTSTP, &sigtstp_action)?;
     }
 
+    // Ctrl-C at the prompt is handled by the line editor; at any other time it is only meant for the foreground job
+    unsafe {
+        let sigint_action: SigAction = SigAction::new(
+            SigHandler::Handler(handle_sigint),
+            signal::SaFlags::SA_RESTART,
+            signal::SigSet::empty(),
+        );
+        signal::sigaction(Signal::SIGINT, &sigint_action)?;
+    }
+
+    // Without SA_RESTART, so that a blocked read of the prompt notices the hangup
+    unsafe {
+        let sighup_action: SigAction = SigAction::new(
+            SigHandler::Handler(handle_sighup),
+            signal::SaFlags::empty(),
+            signal::SigSet::empty(),
+        );
+        signal::sigaction(Signal::SIGHUP, &sighup_action)?;
+    }
+
     // Ignore SIGTTOU and SIGTTIN to prevent the shell from stopping
     // when it tries to access the terminal
     unsafe {
@@ -29,6 +181,7 @@ pub fn setup_signal_handlers() -> std::result::Result<(), nix::Error> {
         );
         signal::sigaction(Signal::SIGTTOU, &sig_ign)?;
         signal::sigaction(Signal::SIGTTIN, &sig_ign)?;
+        signal::sigaction(Signal::SIGQUIT, &sig_ign)?;
     }
 
     Ok(())
@@ -41,11 +194,31 @@ pub extern "C" fn handle_sigtstp(_: i32) {
     }
 }
 
+/// A job: one pipeline (or a single command) running in a process group of its own.
 #[derive(Debug, Clone)]
 pub struct Job {
+    /// The job number, as in %1.
+    pub id: usize,
+    /// The process group all its processes are in, which is the pid of the first one.
+    /// Without job control they stay in the shell's group, and this is just the first pid.
+    pub pgid: pid_t,
+    pub command: String,
+    /// One per pipeline stage, in pipeline order.
+    pub processes: Vec<Process>,
+    /// False when the job changed state in the background and that hasn't been announced yet.
+    pub reported: bool,
+    /// Set by disown -h: the job is not sent SIGHUP when the shell is hung up on.
+    pub nohup: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Process {
     pub pid: pid_t,
+    /// The stage of the pipeline this process runs.
     pub command: String,
     pub status: JobStatus,
+    /// The exit code once the process is done.
+    pub exit_code: i32,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -55,192 +228,490 @@ pub enum JobStatus {
     Done,
 }
 
+impl Job {
+    /// Stopped as soon as one process is, done once all of them are.
+    pub fn status(&self) -> JobStatus {
+        if self.processes.iter().any(|process| process.status == JobStatus::Stopped) {
+            JobStatus::Stopped
+        } else if self.processes.iter().all(|process| process.status == JobStatus::Done) {
+            JobStatus::Done
+        } else {
+            JobStatus::Running
+        }
+    }
+
+    /// The exit code of the last process, which is the pipeline's.
+    pub fn exit_code(&self) -> i32 {
+        self.processes.last().map_or(0, |process| process.exit_code)
+    }
+
+    /// The processes that haven't finished yet.
+    pub fn live_pids(&self) -> Vec<pid_t> {
+        self.processes.iter().filter(|process| process.status != JobStatus::Done).map(|process| process.pid).collect()
+    }
+}
+
+/// How a job's state is shown: Running, Stopped, Done, or Exit n for a failed one.
+pub fn status_text(status: &JobStatus, exit_code: i32) -> String {
+    match status {
+        JobStatus::Running => "Running".to_owned(),
+        JobStatus::Stopped => "Stopped".to_owned(),
+        JobStatus::Done if exit_code == 0 => "Done".to_owned(),
+        JobStatus::Done => format!("Exit {}", exit_code),
+    }
+}
+
 pub struct JobControl {
-    pub jobs: HashMap<pid_t, Job>,
-    current_job: Option<pid_t>,
+    /// Keyed by job number.
+    pub jobs: BTreeMap<usize, Job>,
+    current_job: Option<usize>,
+    /// The job that was current before it, as in %-.
+    previous_job: Option<usize>,
+    /// Exit codes of the processes of background jobs that finished and were dropped from the list,
+    /// by pid, so that wait can still report them.
+    finished: HashMap<pid_t, i32>,
+    /// Whether commands get process groups of their own and become jobs. Off in forked children,
+    /// such as background jobs and pipeline stages, whose commands stay in the child's process group.
+    pub monitor: bool,
+    /// Announce background jobs as soon as they finish (set -b) rather than before the next prompt.
+    pub notify: bool,
+}
+
+impl Default for JobControl {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl JobControl {
     pub fn new() -> Self {
         JobControl {
-            jobs: HashMap::new(),
+            jobs: BTreeMap::new(),
             current_job: None,
+            previous_job: None,
+            finished: HashMap::new(),
+            monitor: true,
+            notify: false,
         }
     }
 
-    /// Add a new job to the job list
-    pub fn add_job(&mut self, pid: pid_t, command: String) {
+    /// Add a new job made of `processes` (pid and command of each stage) to the job list
+    /// and return its job number, one more than the highest in use.
+    pub fn add_job(&mut self, pgid: pid_t, command: String, processes: Vec<(pid_t, String)>) -> usize {
+        let id: usize = self.jobs.keys().next_back().map_or(1, |last| last + 1);
+        let processes: Vec<Process> = processes
+            .into_iter()
+            .map(|(pid, command)| Process { pid, command, status: JobStatus::Running, exit_code: 0 })
+            .collect();
         let job: Job = Job {
-            pid,
+            id,
+            pgid,
             command,
-            status: JobStatus::Running,
+            processes,
+            reported: true,
+            nohup: false,
         };
-        self.jobs.insert(pid, job);
-        self.current_job = Some(pid);
+        self.jobs.insert(id, job);
+        self.make_current(id);
+        id
+    }
+
+    pub fn remove_job(&mut self, pgid: pid_t) {
+        if let Some(id) = self.find_job(pgid).map(|job| job.id) {
+            self.forget(id);
+        }
     }
 
-    pub fn remove_job(&mut self, pid: pid_t) {
-        self.jobs.remove(&pid);
-        if Some(pid) == self.current_job {
-            self.current_job = None;
+    /// Makes a job the current one (%+), and the one that was the previous one (%-).
+    fn make_current(&mut self, id: usize) {
+        if self.current_job != Some(id) {
+            self.previous_job = self.current_job;
+            self.current_job = Some(id);
+        }
+    }
+
+    /// Drops a job from the list. The previous job takes over as the current one,
+    /// and the most recent remaining jobs fill in for whichever is missing.
+    fn forget(&mut self, id: usize) {
+        self.jobs.remove(&id);
+        if self.current_job == Some(id) {
+            self.current_job = self.previous_job.take();
+        }
+        if self.previous_job == Some(id) {
+            self.previous_job = None;
+        }
+        if self.current_job.is_none() {
+            self.current_job = self.jobs.keys().next_back().copied();
+        }
+        if self.previous_job.is_none() {
+            self.previous_job = self.jobs.keys().rev().copied().find(|&other| Some(other) != self.current_job);
         }
     }
 
+    /// The job whose process group is `pgid`.
+    pub fn find_job(&self, pgid: pid_t) -> Option<&Job> {
+        self.jobs.values().find(|job| job.pgid == pgid)
+    }
+
+    fn find_job_mut(&mut self, pgid: pid_t) -> Option<&mut Job> {
+        self.jobs.values_mut().find(|job| job.pgid == pgid)
+    }
+
+    /// The job numbered `id`, as in %id.
+    pub fn get_job(&self, id: usize) -> Option<&Job> {
+        self.jobs.get(&id)
+    }
+
+    /// The job one of whose processes is `pid`.
+    fn job_of(&self, pid: pid_t) -> Option<&Job> {
+        self.jobs.values().find(|job| job.processes.iter().any(|process| process.pid == pid))
+    }
+
+    /// Sends `signal` to every process of a job: to its process group, or without job control to each live process.
+    fn signal_job(&self, pgid: pid_t, signal: i32) -> Result<()> {
+        let job: &Job = match self.find_job(pgid) {
+            Some(job) => job,
+            None => return Err(Error::new(std::io::ErrorKind::NotFound, "Job not found")),
+        };
+        let targets: Vec<pid_t> = if self.monitor { vec![-pgid] } else { job.live_pids() };
+        for target in targets {
+            if unsafe { libc::kill(target, signal) } == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
     /// Stop a running job
-    pub fn stop_job(&mut self, pid: pid_t) -> Result<()> {
-        if let Some(job) = self.jobs.get_mut(&pid) {
-            unsafe {
-                if libc::kill(-pid, SIGTSTP) == -1 {
-                    return Err(Error::last_os_error());
-                }
+    pub fn stop_job(&mut self, pgid: pid_t) -> Result<()> {
+        self.signal_job(pgid, SIGTSTP)?;
+        if let Some(job) = self.find_job_mut(pgid) {
+            for process in job.processes.iter_mut().filter(|process| process.status == JobStatus::Running) {
+                process.status = JobStatus::Stopped;
             }
-            job.status = JobStatus::Stopped;
-            Ok(())
-        } else {
-            Err(Error::new(std::io::ErrorKind::NotFound, "Job not found"))
         }
-    }    
+        Ok(())
+    }
 
-    /// Continue a stopped job
-    pub fn continue_job(&mut self, pid: pid_t) -> Result<()> {
-        if let Some(job) = self.jobs.get_mut(&pid) {
-            unsafe {
-                if libc::kill(pid, SIGCONT) == -1 {
-                    return Err(Error::last_os_error());
-                }
+    /// Continue a stopped job, making it the current one
+    pub fn continue_job(&mut self, pgid: pid_t) -> Result<()> {
+        self.signal_job(pgid, SIGCONT)?;
+        if let Some(job) = self.find_job_mut(pgid) {
+            for process in job.processes.iter_mut().filter(|process| process.status == JobStatus::Stopped) {
+                process.status = JobStatus::Running;
+            }
+            let id: usize = job.id;
+            self.make_current(id);
+        }
+        Ok(())
+    }
+
+    /// Sends `signal` to a job, as kill %job does. A stopped job is also continued
+    /// for signals that should end it, since it would not act on them otherwise.
+    pub fn kill_job(&mut self, pgid: pid_t, signal: i32) -> Result<()> {
+        self.signal_job(pgid, signal)?;
+        let stopped: bool = self.find_job(pgid).is_some_and(|job| job.status() == JobStatus::Stopped);
+        if stopped && (signal == libc::SIGTERM || signal == libc::SIGHUP) {
+            self.signal_job(pgid, SIGCONT)?;
+        }
+        Ok(())
+    }
+
+    /// Removes a job from the list without touching its processes, as disown does.
+    /// With `nohup` it stays listed and is only spared the SIGHUP sent when the shell is hung up on.
+    pub fn disown(&mut self, pgid: pid_t, nohup: bool) {
+        if nohup {
+            if let Some(job) = self.find_job_mut(pgid) {
+                job.nohup = true;
             }
-            job.status = JobStatus::Running;
-            self.current_job = Some(pid);
-            Ok(())
         } else {
-            Err(Error::new(
-                std::io::ErrorKind::NotFound,
-                "Job not found",
-            ))
+            self.remove_job(pgid);
         }
     }
 
-    pub fn resume_job(&mut self, pid: libc::pid_t, foreground: bool) -> Result<()> {
-        let job_count: usize = self.jobs.len();
-        if let Some(job) = self.jobs.get_mut(&pid) {
-            unsafe {
-                // Continue the process
-                if libc::kill(-pid, libc::SIGCONT) == -1 {
-                    return Err(std::io::Error::last_os_error());
-                }
-    
-                if foreground {
-                    // Give terminal control to the process group
-                    if libc::tcsetpgrp(libc::STDIN_FILENO, pid) == -1 {
-                        return Err(std::io::Error::last_os_error());
-                    }
-    
-                    // Wait for the job
-                    let mut status: libc::c_int = 0;
-                    if libc::waitpid(pid, &mut status, libc::WUNTRACED) == -1 {
-                        return Err(std::io::Error::last_os_error());
-                    }
-    
-                    // Take back terminal control
-                    let shell_pgid = libc::getpgrp();
-                    if libc::tcsetpgrp(libc::STDIN_FILENO, shell_pgid) == -1 {
-                        return Err(std::io::Error::last_os_error());
-                    }
-    
-                    if libc::WIFSTOPPED(status) {
-                        job.status = JobStatus::Stopped;
-                        println!("\n[{}]+  Stopped                 {}",job_count, job.command);
-                    } else {
-                        self.remove_job(pid);
-                    }
-                } else {
-                    job.status = JobStatus::Running;
-                }
+    /// Continues a job, and in the foreground gives it the terminal and waits for it.
+    /// Returns the job's exit code, or 0 when it was resumed in the background.
+    pub fn resume_job(&mut self, pgid: pid_t, foreground: bool) -> Result<i32> {
+        if foreground {
+            // Hand over the terminal before the job wakes up and tries to use it
+            give_terminal_to(pgid)?;
+        }
+        if let Err(e) = self.continue_job(pgid) {
+            let _ = give_terminal_to(unsafe { libc::getpgrp() });
+            return Err(e);
+        }
+        if !foreground {
+            return Ok(0);
+        }
+
+        let status: Result<(JobStatus, i32)> = self.wait_for_job(pgid);
+        // Take back terminal control
+        give_terminal_to(unsafe { libc::getpgrp() })?;
+
+        let (status, code): (JobStatus, i32) = status?;
+        if status == JobStatus::Stopped {
+            if let Some(job) = self.find_job(pgid) {
+                println!("\n{}", self.describe_job(job));
             }
-            
-            self.current_job = Some(pid);
-            Ok(())
         } else {
-            Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Job not found"))
+            self.remove_job(pgid);
         }
-    }    
+        Ok(code)
+    }
+
+    /// Waits for every unfinished process of a job, until they are all done or one of them stops.
+    pub fn wait_for_job(&mut self, pgid: pid_t) -> Result<(JobStatus, i32)> {
+        let pids: Vec<pid_t> = match self.find_job(pgid) {
+            Some(job) => job.live_pids(),
+            None => return Err(Error::new(std::io::ErrorKind::NotFound, "Job not found")),
+        };
+        let (status, code): (JobStatus, i32) = self.wait_for_pipeline(&pids)?;
+        match status {
+            JobStatus::Stopped => Ok((status, code)),
+            _ => Ok((status, self.find_job(pgid).map_or(code, Job::exit_code))),
+        }
+    }
 
-    pub fn wait_for_job(&mut self, pid: pid_t) -> Result<JobStatus> {
-        let mut status: i32 = 0;
+    /// Waits for process `pid`, and the rest of its job if it has one, then forgets it, as wait does.
+    /// Gives its exit code, or None when it is no child of this shell.
+    pub fn wait_for_pid(&mut self, pid: pid_t) -> Result<Option<i32>> {
+        if let Some(pgid) = self.job_of(pid).map(|job| job.pgid) {
+            let (status, code): (JobStatus, i32) = self.wait_for_job(pgid)?;
+            if status == JobStatus::Stopped {
+                return Ok(Some(code));
+            }
+            self.retire(pgid);
+            return Ok(self.finished.remove(&pid).or(Some(code)));
+        }
+        if let Some(code) = self.finished.remove(&pid) {
+            return Ok(Some(code));
+        }
         loop {
-            let wait_result = unsafe { libc::waitpid(pid, &mut status, libc::WUNTRACED) };
-            if wait_result == -1 {
-                return Err(Error::last_os_error());
+            let mut status: i32 = 0;
+            if unsafe { libc::waitpid(pid, &mut status, 0) } != -1 {
+                return Ok(Some(exit_code_of(status)));
             }
+            let error: Error = Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::ECHILD) => return Ok(None),
+                _ => return Err(error),
+            }
+        }
+    }
 
-            if libc::WIFSTOPPED(status) {
-                if let Some(job) = self.jobs.get_mut(&pid) {
-                    job.status = JobStatus::Stopped;
-                }
-                return Ok(JobStatus::Stopped);
-            } else if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
-                if let Some(job) = self.jobs.get_mut(&pid) {
-                    job.status = JobStatus::Done;
+    /// Waits for a whole job, as wait %job does, and forgets it once it is done. Gives the job's exit code.
+    pub fn wait_for_job_exit(&mut self, pgid: pid_t) -> Result<i32> {
+        let (status, code): (JobStatus, i32) = self.wait_for_job(pgid)?;
+        if status == JobStatus::Done {
+            self.remove_job(pgid);
+        }
+        Ok(code)
+    }
+
+    /// Waits until one of the jobs in `pgids` has finished, then forgets it and gives its process group and exit code,
+    /// as wait -n does. None when none of them is left to wait for.
+    pub fn wait_for_any(&mut self, pgids: &[pid_t]) -> Result<Option<(pid_t, i32)>> {
+        loop {
+            if let Some(job) = self.jobs.values().find(|job| pgids.contains(&job.pgid) && job.status() == JobStatus::Done) {
+                let (pgid, code): (pid_t, i32) = (job.pgid, job.exit_code());
+                self.remove_job(pgid);
+                return Ok(Some((pgid, code)));
+            }
+            if !self.jobs.values().any(|job| pgids.contains(&job.pgid)) {
+                return Ok(None);
+            }
+
+            let mut status: i32 = 0;
+            let pid: pid_t = unsafe { libc::waitpid(-1, &mut status, 0) };
+            if pid == -1 {
+                let error: Error = Error::last_os_error();
+                match error.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    Some(libc::ECHILD) => return Ok(None),
+                    _ => return Err(error),
                 }
-                return Ok(JobStatus::Done);
             }
+            let announce: bool = self.job_of(pid).is_none_or(|job| !pgids.contains(&job.pgid));
+            self.record_status(pid, status, announce);
+        }
+    }
 
-            // Check if we received SIGTSTP
-            if RECEIVED_SIGTSTP.load(Ordering::SeqCst) {
-                RECEIVED_SIGTSTP.store(false, Ordering::SeqCst);
-                if let Some(job) = self.jobs.get_mut(&pid) {
-                    job.status = JobStatus::Stopped;
+    /// Waits for every child of the shell to finish, then forgets the finished jobs without announcing them.
+    pub fn wait_for_all(&mut self) -> Result<()> {
+        loop {
+            let mut status: i32 = 0;
+            let pid: pid_t = unsafe { libc::waitpid(-1, &mut status, 0) };
+            if pid == -1 {
+                let error: Error = Error::last_os_error();
+                match error.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    Some(libc::ECHILD) => break,
+                    _ => return Err(error),
                 }
-                unsafe {
-                    libc::kill(-pid, libc::SIGTSTP);
+            }
+            self.record_status(pid, status, false);
+        }
+        let done: Vec<pid_t> = self.jobs.values().filter(|job| job.status() == JobStatus::Done).map(|job| job.pgid).collect();
+        for pgid in done {
+            self.retire(pgid);
+        }
+        Ok(())
+    }
+
+    /// Wait for every process of a pipeline. The pipeline counts as stopped as soon as one member stops.
+    /// Also returns the exit code of the last member, which is the pipeline's exit code.
+    /// Other jobs that change state meanwhile are recorded as they do.
+    pub fn wait_for_pipeline(&mut self, pids: &[pid_t]) -> Result<(JobStatus, i32)> {
+        let mut exit_code: i32 = 0;
+        let mut remaining: Vec<pid_t> = pids.to_vec();
+        while !remaining.is_empty() {
+            let mut status: i32 = 0;
+            let pid: pid_t = unsafe { libc::waitpid(-1, &mut status, libc::WUNTRACED) };
+            if pid == -1 {
+                let error: Error = Error::last_os_error();
+                if error.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
                 }
-                return Ok(JobStatus::Stopped);
+                return Err(error);
+            }
+            let waited: bool = remaining.contains(&pid);
+            // Only changes to other jobs are announced; the caller reports on this one.
+            self.record_status(pid, status, !waited);
+            if !waited {
+                continue;
+            }
+
+            if libc::WIFSTOPPED(status) {
+                return Ok((JobStatus::Stopped, exit_code_of(status)));
+            }
+            if Some(&pid) == pids.last() {
+                exit_code = exit_code_of(status);
+            }
+            remaining.retain(|&member| member != pid);
+        }
+        Ok((JobStatus::Done, exit_code))
+    }
+
+    /// Collects every child that has stopped, continued or finished, without blocking.
+    pub fn reap_jobs(&mut self) {
+        loop {
+            let mut status: i32 = 0;
+            let pid: pid_t = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED) };
+            if pid <= 0 {
+                break;
+            }
+            self.record_status(pid, status, true);
+        }
+    }
+
+    /// Applies a status from waitpid to the process it is about. If that changes the state of its job
+    /// and `announce` is set, the change is due to be reported, and with `notify` it is at once.
+    fn record_status(&mut self, pid: pid_t, status: i32, announce: bool) {
+        let notify: bool = self.notify;
+        let job: &mut Job = match self.jobs.values_mut().find(|job| job.processes.iter().any(|process| process.pid == pid)) {
+            Some(job) => job,
+            None => return,
+        };
+        let before: JobStatus = job.status();
+        if let Some(process) = job.processes.iter_mut().find(|process| process.pid == pid) {
+            if libc::WIFSTOPPED(status) {
+                process.status = JobStatus::Stopped;
+            } else if libc::WIFCONTINUED(status) {
+                process.status = JobStatus::Running;
+            } else {
+                process.status = JobStatus::Done;
+                process.exit_code = exit_code_of(status);
             }
         }
+        if !announce || job.status() == before {
+            return;
+        }
+        job.reported = false;
+
+        if notify {
+            for notice in self.take_notices() {
+                println!("{}", notice);
+            }
+        }
+    }
+
+    /// A job as it is announced when it changes state, such as "[1]+  Done                    make".
+    pub fn describe_job(&self, job: &Job) -> String {
+        format!("[{}]{}  {:<24}{}", job.id, self.marker(job), status_text(&job.status(), job.exit_code()), job.command)
+    }
+
+    /// "+" for the current job, "-" for the previous one, a space for the others.
+    pub fn marker(&self, job: &Job) -> &'static str {
+        if self.current_job == Some(job.id) {
+            "+"
+        } else if self.previous_job == Some(job.id) {
+            "-"
+        } else {
+            " "
+        }
+    }
+
+    /// Describes the jobs that changed state since they were last reported, then forgets the finished ones.
+    pub fn take_notices(&mut self) -> Vec<String> {
+        let notices: Vec<String> = self.jobs.values().filter(|job| !job.reported).map(|job| self.describe_job(job)).collect();
+        for job in self.jobs.values_mut() {
+            job.reported = true;
+        }
+        self.cleanup_jobs();
+        notices
     }
 
-    /// Remove completed jobs from the job list
+    /// Remove completed jobs from the job list, keeping their exit codes for wait
     pub fn cleanup_jobs(&mut self) {
-        self.jobs.retain(|_, job| job.status != JobStatus::Done);
+        let done: Vec<pid_t> = self.jobs.values().filter(|job| job.status() == JobStatus::Done).map(|job| job.pgid).collect();
+        for pgid in done {
+            self.retire(pgid);
+        }
+    }
+
+    /// Drops a finished job from the list, keeping the exit code of each of its processes.
+    fn retire(&mut self, pgid: pid_t) {
+        if let Some(job) = self.find_job(pgid) {
+            let id: usize = job.id;
+            let codes: Vec<(pid_t, i32)> = job.processes.iter().map(|process| (process.pid, process.exit_code)).collect();
+            self.finished.extend(codes);
+            self.forget(id);
+        }
     }
 
-    /// List all jobs
+    /// List all jobs, in job number order
     pub fn list_jobs(&self) -> Vec<&Job> {
         self.jobs.values().collect()
     }
 
     /// Get the current (most recently used) job
     pub fn get_current_job(&self) -> Option<&Job> {
-        self.current_job.and_then(|pid| self.jobs.get(&pid))
+        self.current_job.and_then(|id| self.jobs.get(&id))
     }
 
-    /// Bring a job to the foreground
-    pub fn foreground_job(&mut self, pid: pid_t) -> Result<JobStatus> {
-        // First continue the job if it was stopped
-        if let Some(job) = self.jobs.get(&pid) {
-            if job.status == JobStatus::Stopped {
-                self.continue_job(pid)?;
-            }
+    /// Get the job that was current before the current one
+    pub fn get_previous_job(&self) -> Option<&Job> {
+        self.previous_job.and_then(|id| self.jobs.get(&id))
+    }
+
+    /// Sends SIGHUP to every job not protected by disown -h, and SIGCONT after it to stopped ones
+    /// so that they get to act on it. Done when the shell is hung up on, or exits with huponexit set.
+    pub fn hang_up_jobs(&mut self) {
+        let pgids: Vec<pid_t> = self.jobs.values().filter(|job| !job.nohup && job.status() != JobStatus::Done).map(|job| job.pgid).collect();
+        for pgid in pgids {
+            let _ = self.kill_job(pgid, libc::SIGHUP);
         }
+    }
 
-        // Set as current job and wait for it
-        self.current_job = Some(pid);
-        self.wait_for_job(pid)
+    /// Bring a job to the foreground
+    pub fn foreground_job(&mut self, pgid: pid_t) -> Result<i32> {
+        self.resume_job(pgid, true)
     }
 
     /// Send a job to the background
-    pub fn background_job(&mut self, pid: pid_t) -> Result<()> {
-        if let Some(job) = self.jobs.get(&pid) {
-            if job.status == JobStatus::Stopped {
-                self.continue_job(pid)?;
-            }
-            Ok(())
-        } else {
-            Err(Error::new(
-                std::io::ErrorKind::NotFound,
-                "Job not found",
-            ))
-        }
+    pub fn background_job(&mut self, pgid: pid_t) -> Result<()> {
+        self.resume_job(pgid, false).map(|_| ())
     }
 }
 
@@ -254,12 +725,130 @@ pub fn create_process_group(pid: pid_t) -> Result<()> {
     Ok(())
 }
 
+/// Converts a raw waitpid status into a shell exit code (128 + signal number for signals).
+pub fn exit_code_of(status: i32) -> i32 {
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else if libc::WIFSTOPPED(status) {
+        128 + libc::WSTOPSIG(status)
+    } else {
+        0
+    }
+}
+
+// Restore default signal dispositions in a freshly forked child, since ignored signals survive exec
+pub fn reset_child_signals() {
+    unsafe {
+        for sig in [libc::SIGTSTP, libc::SIGTTOU, libc::SIGTTIN, libc::SIGINT, libc::SIGQUIT, libc::SIGPIPE, libc::SIGHUP, libc::SIGCHLD] {
+            libc::signal(sig, libc::SIG_DFL);
+        }
+    }
+}
+
 // Helper function to give terminal control to a process group
 pub fn give_terminal_to(pid: pid_t) -> Result<()> {
+    let shell: bool = pid == unsafe { libc::getpgrp() };
+    FOREGROUND_PGID.store(if shell { 0 } else { pid }, Ordering::SeqCst);
     unsafe {
         if libc::tcsetpgrp(libc::STDIN_FILENO, pid) == -1 {
             return Err(Error::last_os_error());
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_numbers_follow_the_highest_in_use() {
+        let mut job_control: JobControl = JobControl::new();
+        let mut add = |pgid: pid_t, command: &str| -> usize { job_control.add_job(pgid, command.to_owned(), vec![(pgid, command.to_owned())]) };
+        assert_eq!(add(100, "a"), 1);
+        assert_eq!(add(200, "b"), 2);
+        assert_eq!(add(300, "c"), 3);
+        job_control.remove_job(200);
+        assert_eq!(job_control.add_job(400, "d".to_owned(), vec![]), 4);
+        job_control.remove_job(300);
+        job_control.remove_job(400);
+        assert_eq!(job_control.add_job(500, "e".to_owned(), vec![]), 2);
+        assert_eq!(job_control.get_job(2).map(|job| job.pgid), Some(500));
+        assert_eq!(job_control.find_job(100).map(|job| job.id), Some(1));
+        assert_eq!(job_control.get_current_job().map(|job| job.id), Some(2));
+        let commands: Vec<&str> = job_control.list_jobs().iter().map(|job| job.command.as_str()).collect();
+        assert_eq!(commands, vec!["a", "e"]);
+    }
+
+    fn process(pid: pid_t, status: JobStatus, exit_code: i32) -> Process {
+        Process { pid, command: format!("stage{}", pid), status, exit_code }
+    }
+
+    #[test]
+    fn a_job_is_as_far_as_its_processes() {
+        let mut job: Job = Job {
+            id: 1,
+            pgid: 10,
+            command: "a | b".to_owned(),
+            processes: vec![process(10, JobStatus::Done, 1), process(11, JobStatus::Running, 0)],
+            reported: true,
+            nohup: false,
+        };
+        assert_eq!(job.status(), JobStatus::Running);
+        assert_eq!(job.live_pids(), vec![11]);
+        job.processes[1].status = JobStatus::Stopped;
+        assert_eq!(job.status(), JobStatus::Stopped);
+        job.processes[1] = process(11, JobStatus::Done, 2);
+        assert_eq!(job.status(), JobStatus::Done);
+        assert_eq!(job.exit_code(), 2);
+        assert!(job.live_pids().is_empty());
+        assert_eq!(status_text(&job.status(), job.exit_code()), "Exit 2");
+        assert_eq!(status_text(&JobStatus::Done, 0), "Done");
+    }
+
+    #[test]
+    fn notices_describe_unreported_jobs_once() {
+        let mut job_control: JobControl = JobControl::new();
+        job_control.add_job(10, "sleep 1".to_owned(), vec![(10, "sleep 1".to_owned())]);
+        job_control.add_job(20, "make".to_owned(), vec![(20, "make".to_owned())]);
+        let job: &mut Job = job_control.jobs.get_mut(&1).unwrap();
+        job.processes[0].status = JobStatus::Done;
+        job.reported = false;
+        assert_eq!(job_control.take_notices(), vec!["[1]-  Done                    sleep 1"]);
+        assert!(job_control.take_notices().is_empty());
+        assert_eq!(job_control.list_jobs().len(), 1);
+        assert_eq!(job_control.describe_job(job_control.get_job(2).unwrap()), "[2]+  Running                 make");
+    }
+
+    #[test]
+    fn current_and_previous_jobs() {
+        let mut job_control: JobControl = JobControl::new();
+        for pgid in [100, 200, 300] {
+            job_control.add_job(pgid, "a".to_owned(), vec![(pgid, "a".to_owned())]);
+        }
+        let current = |job_control: &JobControl| -> (Option<usize>, Option<usize>) {
+            (job_control.get_current_job().map(|job| job.id), job_control.get_previous_job().map(|job| job.id))
+        };
+        assert_eq!(current(&job_control), (Some(3), Some(2)));
+        // The previous job takes over from a current one that goes away.
+        job_control.remove_job(300);
+        assert_eq!(current(&job_control), (Some(2), Some(1)));
+        job_control.disown(100, true);
+        assert!(job_control.find_job(100).unwrap().nohup);
+        job_control.disown(100, false);
+        assert_eq!(current(&job_control), (Some(2), None));
+    }
+
+    #[test]
+    fn signal_names() {
+        assert_eq!(signal_number("TERM"), Some(libc::SIGTERM));
+        assert_eq!(signal_number("sigkill"), Some(libc::SIGKILL));
+        assert_eq!(signal_number("9"), Some(9));
+        assert_eq!(signal_number("0"), Some(0));
+        assert_eq!(signal_number("NOPE"), None);
+        assert_eq!(signal_number("99"), None);
+        assert_eq!(signal_name(libc::SIGHUP), Some("HUP"));
+    }
+}
@@ -0,0 +1,1225 @@
+use crate::ast::{ArrayElement, List, ParameterExpansion, ParameterOp, RedirectKind, ReplaceMode, Word, WordPart};
+use crate::parser::{is_valid_name, parse, split_subscript, ParseError, Parser};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Word(Word),
+    /// The digits in front of a redirection operator, as in 2>file.
+    IoNumber(i32),
+    Op(Operator),
+    Newline,
+    Eof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Semicolon,
+    /// ;; ends a case item.
+    DoubleSemicolon,
+    /// ;& falls through to the next case item.
+    SemicolonAnd,
+    /// ;;& goes on testing the next case items.
+    DoubleSemicolonAnd,
+    Pipe,
+    AndIf,
+    OrIf,
+    /// & runs the and-or list before it in the background.
+    Ampersand,
+    LParen,
+    RParen,
+    Redirect(RedirectKind),
+    /// << or, when true, <<- which strips leading tabs.
+    HereDoc(bool),
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text: &str = match self {
+            Operator::Semicolon => ";",
+            Operator::DoubleSemicolon => ";;",
+            Operator::SemicolonAnd => ";&",
+            Operator::DoubleSemicolonAnd => ";;&",
+            Operator::Pipe => "|",
+            Operator::AndIf => "&&",
+            Operator::OrIf => "||",
+            Operator::Ampersand => "&",
+            Operator::LParen => "(",
+            Operator::RParen => ")",
+            Operator::Redirect(kind) => kind.operator(),
+            Operator::HereDoc(false) => "<<",
+            Operator::HereDoc(true) => "<<-",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+pub struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    /// Set once a here-document body has been read: the newline that ends the line holding
+    /// the << operators, and where lexing resumes after it, past the bodies.
+    here_doc_skip: Option<(usize, usize)>,
+}
+
+impl Lexer {
+    pub fn new(input: &str) -> Self {
+        Lexer {
+            chars: input.chars().collect(),
+            pos: 0,
+            here_doc_skip: None,
+        }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn is_metachar(c: char) -> bool {
+        matches!(c, ' ' | '\t' | '\n' | ';' | '|' | '&' | '<' | '>' | '(' | ')')
+    }
+
+    fn skip_blanks(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') => self.pos += 1,
+                // A backslash-newline is a line continuation and disappears entirely.
+                Some('\\') if self.peek_at(1) == Some('\n') => self.pos += 2,
+                _ => break,
+            }
+        }
+    }
+
+    pub fn next_token(&mut self) -> Result<Token, ParseError> {
+        self.skip_blanks();
+
+        // Comments run to the end of the line.
+        if self.peek() == Some('#') {
+            while let Some(c) = self.peek() {
+                if c == '\n' {
+                    break;
+                }
+                self.pos += 1;
+            }
+        }
+
+        let c: char = match self.peek() {
+            Some(c) => c,
+            None => return Ok(Token::Eof),
+        };
+
+        match c {
+            '\n' => {
+                self.pos += 1;
+                if let Some((newline, resume)) = self.here_doc_skip {
+                    if newline + 1 == self.pos {
+                        self.pos = resume;
+                        self.here_doc_skip = None;
+                    }
+                }
+                Ok(Token::Newline)
+            }
+            ';' if self.peek_at(1) == Some(';') && self.peek_at(2) == Some('&') => {
+                self.pos += 3;
+                Ok(Token::Op(Operator::DoubleSemicolonAnd))
+            }
+            ';' if self.peek_at(1) == Some(';') => {
+                self.pos += 2;
+                Ok(Token::Op(Operator::DoubleSemicolon))
+            }
+            ';' if self.peek_at(1) == Some('&') => {
+                self.pos += 2;
+                Ok(Token::Op(Operator::SemicolonAnd))
+            }
+            ';' => {
+                self.pos += 1;
+                Ok(Token::Op(Operator::Semicolon))
+            }
+            '(' => {
+                self.pos += 1;
+                Ok(Token::Op(Operator::LParen))
+            }
+            ')' => {
+                self.pos += 1;
+                Ok(Token::Op(Operator::RParen))
+            }
+            '|' if self.peek_at(1) == Some('|') => {
+                self.pos += 2;
+                Ok(Token::Op(Operator::OrIf))
+            }
+            '|' => {
+                self.pos += 1;
+                Ok(Token::Op(Operator::Pipe))
+            }
+            '&' if self.peek_at(1) == Some('&') => {
+                self.pos += 2;
+                Ok(Token::Op(Operator::AndIf))
+            }
+            '&' if self.peek_at(1) == Some('>') => {
+                self.pos += 1;
+                let kind: RedirectKind = match self.read_redirect_op()? {
+                    Operator::Redirect(RedirectKind::Output) => RedirectKind::OutputBoth,
+                    Operator::Redirect(RedirectKind::Append) => RedirectKind::AppendBoth,
+                    _ => return Err(ParseError::Syntax("unexpected token after '&>'".to_owned())),
+                };
+                Ok(Token::Op(Operator::Redirect(kind)))
+            }
+            '&' => {
+                self.pos += 1;
+                Ok(Token::Op(Operator::Ampersand))
+            }
+            '<' | '>' => Ok(Token::Op(self.read_redirect_op()?)),
+            c if c.is_ascii_digit() => {
+                // Digits directly followed by a redirection operator name a file descriptor.
+                let mut end: usize = self.pos;
+                while self.chars.get(end).is_some_and(|c| c.is_ascii_digit()) {
+                    end += 1;
+                }
+                if matches!(self.chars.get(end), Some('<') | Some('>')) {
+                    let digits: String = self.chars[self.pos..end].iter().collect();
+                    if let Ok(fd) = digits.parse::<i32>() {
+                        self.pos = end;
+                        return Ok(Token::IoNumber(fd));
+                    }
+                }
+                self.read_word(false).map(Token::Word)
+            }
+            _ => self.read_word(false).map(Token::Word),
+        }
+    }
+
+    fn read_redirect_op(&mut self) -> Result<Operator, ParseError> {
+        let first: Option<char> = self.peek();
+        let second: Option<char> = self.peek_at(1);
+        let (kind, len): (RedirectKind, usize) = match (first, second) {
+            (Some('<'), Some('<')) if self.peek_at(2) == Some('<') => (RedirectKind::HereString, 3),
+            (Some('<'), Some('<')) => {
+                let strip: bool = self.peek_at(2) == Some('-');
+                self.pos += if strip { 3 } else { 2 };
+                return Ok(Operator::HereDoc(strip));
+            }
+            (Some('<'), Some('&')) => (RedirectKind::DupInput, 2),
+            (Some('<'), Some('>')) => (RedirectKind::ReadWrite, 2),
+            (Some('<'), _) => (RedirectKind::Input, 1),
+            (Some('>'), Some('>')) => (RedirectKind::Append, 2),
+            (Some('>'), Some('|')) => (RedirectKind::Clobber, 2),
+            (Some('>'), Some('&')) => (RedirectKind::DupOutput, 2),
+            _ => (RedirectKind::Output, 1),
+        };
+        self.pos += len;
+        Ok(Operator::Redirect(kind))
+    }
+
+    /// Reads the body of a here-document, which starts on the line after the current one
+    /// (or after the previous here-document on the same line) and ends at a line equal to `delimiter`.
+    pub fn read_here_doc(&mut self, delimiter: &str, strip_tabs: bool) -> Result<String, ParseError> {
+        let (newline, mut pos): (usize, usize) = match self.here_doc_skip {
+            Some(skip) => skip,
+            None => match self.find_line_end() {
+                Some(newline) => (newline, newline + 1),
+                None => return Err(ParseError::Incomplete(format!("here-document delimited by '{}' has no body", delimiter))),
+            },
+        };
+
+        let mut body: String = String::new();
+        loop {
+            if pos >= self.chars.len() {
+                return Err(ParseError::Incomplete(format!("here-document delimited by '{}' is not terminated", delimiter)));
+            }
+            let mut end: usize = pos;
+            while end < self.chars.len() && self.chars[end] != '\n' {
+                end += 1;
+            }
+            let mut line: &[char] = &self.chars[pos..end];
+            if strip_tabs {
+                while let Some(('\t', rest)) = line.split_first() {
+                    line = rest;
+                }
+            }
+            let line: String = line.iter().collect();
+            pos = end + 1;
+            if line == delimiter {
+                break;
+            }
+            body.push_str(&line);
+            body.push('\n');
+        }
+
+        self.here_doc_skip = Some((newline, pos.min(self.chars.len())));
+        Ok(body)
+    }
+
+    /// The position of the newline ending the current line, skipping over quoted and escaped newlines.
+    fn find_line_end(&self) -> Option<usize> {
+        let mut pos: usize = self.pos;
+        let mut quote: Option<char> = None;
+        while let Some(&c) = self.chars.get(pos) {
+            match (quote, c) {
+                (None, '\n') => return Some(pos),
+                (None, '\'' | '"') => quote = Some(c),
+                (Some(q), _) if c == q => quote = None,
+                (None | Some('"'), '\\') => pos += 1,
+                _ => {}
+            }
+            pos += 1;
+        }
+        None
+    }
+
+    /// Splits an unquoted here-document body into text and expansions.
+    /// Backslash only escapes '$', '`', '\\' and newline there, like in double quotes minus the quote itself.
+    pub fn read_here_doc_body(&mut self) -> Result<Vec<WordPart>, ParseError> {
+        self.read_expandable_text(false)
+    }
+
+    /// Reads the regular expression after =~ in [[ ]]. Parentheses and '|' belong to the word there,
+    /// and blanks are kept too while inside parentheses.
+    pub fn read_regex_word(&mut self) -> Result<Word, ParseError> {
+        self.skip_blanks();
+        self.read_word(true)
+    }
+
+    fn read_word(&mut self, regex: bool) -> Result<Word, ParseError> {
+        let mut parts: Vec<WordPart> = Vec::new();
+        let mut literal: String = String::new();
+        let mut depth: usize = 0;
+
+        while let Some(c) = self.peek() {
+            if regex && (c == '(' || c == '|' || (depth > 0 && c != '\n' && Self::is_metachar(c))) {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
+                self.pos += 1;
+                literal.push(c);
+                continue;
+            }
+            // NAME=( starts an array assignment, which ends the word.
+            if c == '(' && parts.is_empty() && !regex && is_assignment_prefix(&literal) {
+                self.pos += 1;
+                flush_literal(&mut parts, &mut literal);
+                parts.push(WordPart::Array(self.read_array_elements()?));
+                break;
+            }
+            // ?(...), *(...), +(...), @(...) and !(...) are extended glob patterns, not subshells.
+            if c == '(' && literal.ends_with(['?', '*', '+', '@', '!']) {
+                self.read_pattern_group(&mut literal)?;
+                continue;
+            }
+            if Self::is_metachar(c) {
+                break;
+            }
+            match c {
+                '\\' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('\n') => self.pos += 1,
+                        Some(escaped) => {
+                            self.pos += 1;
+                            flush_literal(&mut parts, &mut literal);
+                            push_quoted(&mut parts, escaped);
+                        }
+                        None => literal.push('\\'),
+                    }
+                }
+                '\'' => {
+                    self.pos += 1;
+                    flush_literal(&mut parts, &mut literal);
+                    let text: String = self.read_single_quoted()?;
+                    parts.push(WordPart::Quoted(text));
+                }
+                '"' => {
+                    self.pos += 1;
+                    flush_literal(&mut parts, &mut literal);
+                    let inner: Vec<WordPart> = self.read_double_quoted()?;
+                    parts.push(WordPart::DoubleQuoted(inner));
+                }
+                '$' if self.peek_at(1) == Some('\'') => {
+                    self.pos += 2;
+                    flush_literal(&mut parts, &mut literal);
+                    let text: String = self.read_ansi_c_quoted()?;
+                    parts.push(WordPart::Quoted(text));
+                }
+                '`' => {
+                    self.pos += 1;
+                    flush_literal(&mut parts, &mut literal);
+                    parts.push(self.read_backquoted(false)?);
+                }
+                '$' => {
+                    self.pos += 1;
+                    match self.read_dollar()? {
+                        Some(part) => {
+                            flush_literal(&mut parts, &mut literal);
+                            parts.push(part);
+                        }
+                        None => literal.push('$'),
+                    }
+                }
+                _ => {
+                    self.pos += 1;
+                    literal.push(c);
+                }
+            }
+        }
+
+        flush_literal(&mut parts, &mut literal);
+        Ok(Word::new(parts))
+    }
+
+    /// Reads the elements of an array assignment after its '(', up to and including the closing ')'.
+    fn read_array_elements(&mut self) -> Result<Vec<ArrayElement>, ParseError> {
+        let mut elements: Vec<ArrayElement> = Vec::new();
+        loop {
+            self.skip_blanks();
+            match self.peek() {
+                None => return Err(ParseError::Incomplete("unterminated array assignment".to_owned())),
+                Some(')') => {
+                    self.pos += 1;
+                    return Ok(elements);
+                }
+                Some('\n') => self.pos += 1,
+                Some('#') => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                Some(c) if Self::is_metachar(c) => return Err(ParseError::Syntax(format!("unexpected '{}' in array assignment", c))),
+                Some(_) => {
+                    let word: Word = self.read_word(false)?;
+                    elements.push(array_element(word));
+                }
+            }
+        }
+    }
+
+    /// Copies an extended glob group, from its '(' to the matching ')', into the literal text of a word.
+    fn read_pattern_group(&mut self, literal: &mut String) -> Result<(), ParseError> {
+        let mut depth: usize = 0;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            literal.push(c);
+            match c {
+                '\\' => {
+                    if let Some(escaped) = self.peek() {
+                        self.pos += 1;
+                        literal.push(escaped);
+                    }
+                }
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(ParseError::Incomplete("unterminated pattern group".to_owned()))
+    }
+
+    fn read_double_quoted(&mut self) -> Result<Vec<WordPart>, ParseError> {
+        self.read_expandable_text(true)
+    }
+
+    fn read_expandable_text(&mut self, in_quotes: bool) -> Result<Vec<WordPart>, ParseError> {
+        let mut parts: Vec<WordPart> = Vec::new();
+        let mut text: String = String::new();
+
+        loop {
+            let c: char = match self.peek() {
+                Some(c) => c,
+                None if in_quotes => return Err(ParseError::Incomplete("unterminated double quote".to_owned())),
+                None => break,
+            };
+            self.pos += 1;
+            match c {
+                '"' if in_quotes => break,
+                // Inside double quotes a backslash only escapes characters that are special there.
+                '\\' => match self.peek() {
+                    Some('\n') => self.pos += 1,
+                    Some(escaped) if matches!(escaped, '$' | '`' | '\\') || (in_quotes && escaped == '"') => {
+                        self.pos += 1;
+                        text.push(escaped);
+                    }
+                    _ => text.push('\\'),
+                },
+                '`' => {
+                    flush_quoted(&mut parts, &mut text);
+                    parts.push(self.read_backquoted(in_quotes)?);
+                }
+                '$' => match self.read_dollar()? {
+                    Some(part) => {
+                        flush_quoted(&mut parts, &mut text);
+                        parts.push(part);
+                    }
+                    None => text.push('$'),
+                },
+                _ => text.push(c),
+            }
+        }
+
+        flush_quoted(&mut parts, &mut text);
+        Ok(parts)
+    }
+
+    /// Reads the expansion after a '$' that has already been consumed.
+    /// Returns None if the '$' should be taken literally.
+    fn read_dollar(&mut self) -> Result<Option<WordPart>, ParseError> {
+        if self.peek() == Some('(') && self.peek_at(1) == Some('(') {
+            self.pos += 2;
+            return Ok(Some(WordPart::Arithmetic(self.read_arithmetic()?)));
+        }
+        if self.peek() == Some('(') {
+            self.pos += 1;
+            return Ok(Some(self.read_command_substitution()?));
+        }
+        if self.peek() == Some('{') {
+            self.pos += 1;
+            return Ok(Some(self.read_braced_parameter()?));
+        }
+        Ok(self.read_parameter())
+    }
+
+    /// Reads an arithmetic expression after "((" or "$((" up to the matching "))".
+    /// Parameters and substitutions inside are kept as parts; everything else is literal text.
+    pub fn read_arithmetic(&mut self) -> Result<Vec<WordPart>, ParseError> {
+        let mut parts: Vec<WordPart> = Vec::new();
+        let mut text: String = String::new();
+        let mut depth: usize = 0;
+
+        loop {
+            let c: char = match self.peek() {
+                Some(c) => c,
+                None => return Err(ParseError::Incomplete("unterminated arithmetic expression".to_owned())),
+            };
+            self.pos += 1;
+            match c {
+                '(' => {
+                    depth += 1;
+                    text.push(c);
+                }
+                ')' if depth > 0 => {
+                    depth -= 1;
+                    text.push(c);
+                }
+                ')' if self.peek() == Some(')') => {
+                    self.pos += 1;
+                    break;
+                }
+                ')' => return Err(ParseError::Syntax("unbalanced ')' in arithmetic expression".to_owned())),
+                '\\' => match self.peek() {
+                    Some('\n') => self.pos += 1,
+                    Some(escaped) => {
+                        self.pos += 1;
+                        text.push(escaped);
+                    }
+                    None => text.push('\\'),
+                },
+                '"' => {
+                    flush_literal(&mut parts, &mut text);
+                    let inner: Vec<WordPart> = self.read_double_quoted()?;
+                    parts.push(WordPart::DoubleQuoted(inner));
+                }
+                '`' => {
+                    flush_literal(&mut parts, &mut text);
+                    parts.push(self.read_backquoted(false)?);
+                }
+                '$' => match self.read_dollar()? {
+                    Some(part) => {
+                        flush_literal(&mut parts, &mut text);
+                        parts.push(part);
+                    }
+                    None => text.push('$'),
+                },
+                _ => text.push(c),
+            }
+        }
+
+        flush_literal(&mut parts, &mut text);
+        Ok(parts)
+    }
+
+    /// Called by the parser after a word in command position: consumes a following "()",
+    /// blanks allowed inside, and returns whether it was there, which makes the word a function name.
+    pub fn read_function_parens(&mut self) -> bool {
+        let mut pos: usize = self.pos;
+        let mut expected: char = '(';
+        loop {
+            match self.chars.get(pos) {
+                Some(' ') | Some('\t') => pos += 1,
+                Some(c) if *c == expected && expected == '(' => {
+                    expected = ')';
+                    pos += 1;
+                }
+                Some(c) if *c == expected => {
+                    self.pos = pos + 1;
+                    return true;
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    /// Called by the parser after a '(' token: if another '(' follows directly,
+    /// reads the rest of a (( ... )) command and returns its expression.
+    pub fn read_arithmetic_command(&mut self) -> Result<Option<Vec<WordPart>>, ParseError> {
+        if self.peek() != Some('(') {
+            return Ok(None);
+        }
+        self.pos += 1;
+        self.read_arithmetic().map(Some)
+    }
+
+    /// Parses the command list after "$(" with a parser of its own and continues after the closing ')'.
+    fn read_command_substitution(&mut self) -> Result<WordPart, ParseError> {
+        let rest: String = self.chars[self.pos..].iter().collect();
+        let mut parser: Parser = Parser::new(&rest);
+        let list: List = parser.parse_command_substitution()?;
+        self.pos += parser.position();
+        Ok(WordPart::CommandSubstitution(list))
+    }
+
+    /// Reads a `...` substitution after its opening backquote. Inside, a backslash only escapes
+    /// '$', '`' and '\\' (and '"' within double quotes); the rest is parsed as a command.
+    fn read_backquoted(&mut self, in_double_quotes: bool) -> Result<WordPart, ParseError> {
+        let mut text: String = String::new();
+        loop {
+            let c: char = match self.peek() {
+                Some(c) => c,
+                None => return Err(ParseError::Incomplete("unterminated backquote".to_owned())),
+            };
+            self.pos += 1;
+            match c {
+                '`' => break,
+                '\\' => match self.peek() {
+                    Some(escaped) if matches!(escaped, '$' | '`' | '\\') || (in_double_quotes && escaped == '"') => {
+                        self.pos += 1;
+                        text.push(escaped);
+                    }
+                    _ => text.push('\\'),
+                },
+                _ => text.push(c),
+            }
+        }
+        Ok(WordPart::CommandSubstitution(parse(&text)?))
+    }
+
+    /// Reads up to the closing single quote. Nothing inside is special.
+    fn read_single_quoted(&mut self) -> Result<String, ParseError> {
+        let mut text: String = String::new();
+        loop {
+            match self.peek() {
+                Some('\'') => {
+                    self.pos += 1;
+                    return Ok(text);
+                }
+                Some(c) => {
+                    self.pos += 1;
+                    text.push(c);
+                }
+                None => return Err(ParseError::Incomplete("unterminated single quote".to_owned())),
+            }
+        }
+    }
+
+    /// Reads a $'...' string, decoding the C-style escapes inside it.
+    fn read_ansi_c_quoted(&mut self) -> Result<String, ParseError> {
+        let mut text: String = String::new();
+        loop {
+            let c: char = match self.peek() {
+                Some(c) => c,
+                None => return Err(ParseError::Incomplete("unterminated $' string".to_owned())),
+            };
+            self.pos += 1;
+            match c {
+                '\'' => return Ok(text),
+                '\\' => {
+                    let escaped: char = match self.peek() {
+                        Some(escaped) => escaped,
+                        None => return Err(ParseError::Incomplete("unterminated $' string".to_owned())),
+                    };
+                    self.pos += 1;
+                    match escaped {
+                        'a' => text.push('\x07'),
+                        'b' => text.push('\x08'),
+                        'e' | 'E' => text.push('\x1b'),
+                        'f' => text.push('\x0c'),
+                        'n' => text.push('\n'),
+                        'r' => text.push('\r'),
+                        't' => text.push('\t'),
+                        'v' => text.push('\x0b'),
+                        '\\' | '\'' | '"' | '?' => text.push(escaped),
+                        '0'..='7' => {
+                            let mut value: u32 = escaped.to_digit(8).unwrap();
+                            for _ in 0..2 {
+                                match self.peek().and_then(|c| c.to_digit(8)) {
+                                    Some(digit) => {
+                                        value = value * 8 + digit;
+                                        self.pos += 1;
+                                    }
+                                    None => break,
+                                }
+                            }
+                            text.push(char::from_u32(value & 0xff).unwrap_or('?'));
+                        }
+                        'x' => self.push_hex_escape(&mut text, 2, "\\x"),
+                        'u' => self.push_hex_escape(&mut text, 4, "\\u"),
+                        'U' => self.push_hex_escape(&mut text, 8, "\\U"),
+                        'c' => match self.peek() {
+                            Some(ctrl) => {
+                                self.pos += 1;
+                                text.push(char::from_u32(ctrl.to_ascii_uppercase() as u32 & 0x1f).unwrap_or('?'));
+                            }
+                            None => text.push_str("\\c"),
+                        },
+                        // Unknown escapes are kept as written.
+                        _ => {
+                            text.push('\\');
+                            text.push(escaped);
+                        }
+                    }
+                }
+                _ => text.push(c),
+            }
+        }
+    }
+
+    /// Reads up to `max` hex digits of a \x, \u or \U escape and pushes the character they name.
+    fn push_hex_escape(&mut self, text: &mut String, max: usize, prefix: &str) {
+        let mut value: u32 = 0;
+        let mut digits: usize = 0;
+        while digits < max {
+            match self.peek().and_then(|c| c.to_digit(16)) {
+                Some(digit) => {
+                    value = value * 16 + digit;
+                    digits += 1;
+                    self.pos += 1;
+                }
+                None => break,
+            }
+        }
+        if digits == 0 {
+            text.push_str(prefix);
+        } else {
+            text.push(char::from_u32(value).unwrap_or('\u{fffd}'));
+        }
+    }
+
+    /// Reads the name after a '$'. Returns None if the '$' should be taken literally.
+    fn read_parameter(&mut self) -> Option<WordPart> {
+        match self.peek() {
+            // Special parameters and positional parameters are a single character without braces.
+            Some(c) if is_special_parameter(c) || c.is_ascii_digit() => {
+                self.pos += 1;
+                return Some(WordPart::Parameter(c.to_string()));
+            }
+            _ => {}
+        }
+        let mut name: String = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' {
+                name.push(c);
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        if name.is_empty() {
+            None
+        } else {
+            Some(WordPart::Parameter(name))
+        }
+    }
+
+    /// Reads a ${...} expansion after its opening brace.
+    fn read_braced_parameter(&mut self) -> Result<WordPart, ParseError> {
+        // ${#name} is a length, but ${#} on its own is the parameter '#'.
+        if self.peek() == Some('#') && !matches!(self.peek_at(1), Some('}') | None) {
+            self.pos += 1;
+            let name: String = self.read_parameter_name()?;
+            let index: Option<Word> = self.read_subscript(&name)?;
+            self.expect_closing_brace()?;
+            return Ok(WordPart::ParameterExpansion(Box::new(ParameterExpansion { name, index, op: ParameterOp::Length })));
+        }
+        // ${!name[@]} lists the keys of an array, but ${!} on its own is the parameter '!'.
+        if self.peek() == Some('!') && self.peek_at(1).is_some_and(|c| c.is_alphabetic() || c == '_') {
+            self.pos += 1;
+            let name: String = self.read_parameter_name()?;
+            let index: Option<Word> = self.read_subscript(&name)?;
+            if !matches!(index.as_ref().and_then(Word::as_literal), Some("@") | Some("*")) {
+                return Err(ParseError::Syntax(format!("${{!{}...}}: bad substitution", name)));
+            }
+            self.expect_closing_brace()?;
+            return Ok(WordPart::ParameterExpansion(Box::new(ParameterExpansion { name, index, op: ParameterOp::Keys })));
+        }
+
+        let name: String = self.read_parameter_name()?;
+        let index: Option<Word> = self.read_subscript(&name)?;
+        let c: char = match self.peek() {
+            Some(c) => c,
+            None => return Err(ParseError::Incomplete("unterminated parameter expansion".to_owned())),
+        };
+        self.pos += 1;
+
+        let op: ParameterOp = match c {
+            '}' if index.is_none() => return Ok(WordPart::Parameter(name)),
+            '}' => return Ok(WordPart::ParameterExpansion(Box::new(ParameterExpansion { name, index, op: ParameterOp::Value }))),
+            ':' => match self.peek() {
+                Some(op @ ('-' | '=' | '?' | '+')) => {
+                    self.pos += 1;
+                    let word: Word = self.read_brace_word(None)?;
+                    fallback_op(op, true, word)
+                }
+                _ => {
+                    let offset: Word = self.read_brace_word(Some(':'))?;
+                    let length: Option<Word> = if self.peek() == Some(':') {
+                        self.pos += 1;
+                        Some(self.read_brace_word(None)?)
+                    } else {
+                        None
+                    };
+                    ParameterOp::Substring { offset, length }
+                }
+            },
+            '-' | '=' | '?' | '+' => {
+                let word: Word = self.read_brace_word(None)?;
+                fallback_op(c, false, word)
+            }
+            '#' | '%' => {
+                let longest: bool = self.peek() == Some(c);
+                if longest {
+                    self.pos += 1;
+                }
+                let pattern: Word = self.read_brace_word(None)?;
+                if c == '#' {
+                    ParameterOp::RemovePrefix { longest, pattern }
+                } else {
+                    ParameterOp::RemoveSuffix { longest, pattern }
+                }
+            }
+            '/' => {
+                let mode: ReplaceMode = match self.peek() {
+                    Some('/') => ReplaceMode::All,
+                    Some('#') => ReplaceMode::Prefix,
+                    Some('%') => ReplaceMode::Suffix,
+                    _ => ReplaceMode::First,
+                };
+                if mode != ReplaceMode::First {
+                    self.pos += 1;
+                }
+                let pattern: Word = self.read_brace_word(Some('/'))?;
+                let replacement: Word = if self.peek() == Some('/') {
+                    self.pos += 1;
+                    self.read_brace_word(None)?
+                } else {
+                    Word::default()
+                };
+                ParameterOp::Replace { mode, pattern, replacement }
+            }
+            '^' | ',' => {
+                let all: bool = self.peek() == Some(c);
+                if all {
+                    self.pos += 1;
+                }
+                let pattern: Word = self.read_brace_word(None)?;
+                ParameterOp::Case { upper: c == '^', all, pattern }
+            }
+            _ => return Err(ParseError::Syntax(format!("${{{}{}...}}: bad substitution", name, c))),
+        };
+
+        self.expect_closing_brace()?;
+        Ok(WordPart::ParameterExpansion(Box::new(ParameterExpansion { name, index, op })))
+    }
+
+    /// Reads the [index] after a variable name inside ${...}, if there is one.
+    fn read_subscript(&mut self, name: &str) -> Result<Option<Word>, ParseError> {
+        if self.peek() != Some('[') || !is_valid_name(name) {
+            return Ok(None);
+        }
+        self.pos += 1;
+        let index: Word = self.read_brace_word(Some(']'))?;
+        if self.peek() != Some(']') {
+            return Err(ParseError::Syntax(format!("${{{}[...}}: bad substitution", name)));
+        }
+        self.pos += 1;
+        Ok(Some(index))
+    }
+
+    fn read_parameter_name(&mut self) -> Result<String, ParseError> {
+        let mut name: String = String::new();
+        match self.peek() {
+            Some(c) if is_special_parameter(c) => {
+                self.pos += 1;
+                name.push(c);
+            }
+            Some(c) if c.is_ascii_digit() => {
+                while let Some(c) = self.peek().filter(|c| c.is_ascii_digit()) {
+                    name.push(c);
+                    self.pos += 1;
+                }
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                while let Some(c) = self.peek().filter(|c| c.is_alphanumeric() || *c == '_') {
+                    name.push(c);
+                    self.pos += 1;
+                }
+            }
+            None => return Err(ParseError::Incomplete("unterminated parameter expansion".to_owned())),
+            Some(_) => return Err(ParseError::Syntax("bad substitution".to_owned())),
+        }
+        Ok(name)
+    }
+
+    fn expect_closing_brace(&mut self) -> Result<(), ParseError> {
+        match self.peek() {
+            Some('}') => {
+                self.pos += 1;
+                Ok(())
+            }
+            None => Err(ParseError::Incomplete("unterminated parameter expansion".to_owned())),
+            Some(_) => Err(ParseError::Syntax("bad substitution".to_owned())),
+        }
+    }
+
+    /// Reads the word inside ${...} after the operator, up to the closing brace or `stop`, which are left unread.
+    /// Blanks are part of the word here, and quotes and expansions work as usual.
+    fn read_brace_word(&mut self, stop: Option<char>) -> Result<Word, ParseError> {
+        let mut parts: Vec<WordPart> = Vec::new();
+        let mut literal: String = String::new();
+        let mut depth: usize = 0;
+
+        loop {
+            let c: char = match self.peek() {
+                Some(c) => c,
+                None => return Err(ParseError::Incomplete("unterminated parameter expansion".to_owned())),
+            };
+            if depth == 0 && (c == '}' || Some(c) == stop) {
+                break;
+            }
+            self.pos += 1;
+            match c {
+                '{' => {
+                    depth += 1;
+                    literal.push(c);
+                }
+                '}' => {
+                    depth -= 1;
+                    literal.push(c);
+                }
+                '\\' => match self.peek() {
+                    Some('\n') => self.pos += 1,
+                    Some(escaped) => {
+                        self.pos += 1;
+                        flush_literal(&mut parts, &mut literal);
+                        push_quoted(&mut parts, escaped);
+                    }
+                    None => literal.push('\\'),
+                },
+                '\'' => {
+                    flush_literal(&mut parts, &mut literal);
+                    let text: String = self.read_single_quoted()?;
+                    parts.push(WordPart::Quoted(text));
+                }
+                '"' => {
+                    flush_literal(&mut parts, &mut literal);
+                    let inner: Vec<WordPart> = self.read_double_quoted()?;
+                    parts.push(WordPart::DoubleQuoted(inner));
+                }
+                '`' => {
+                    flush_literal(&mut parts, &mut literal);
+                    parts.push(self.read_backquoted(false)?);
+                }
+                '$' => match self.read_dollar()? {
+                    Some(part) => {
+                        flush_literal(&mut parts, &mut literal);
+                        parts.push(part);
+                    }
+                    None => literal.push('$'),
+                },
+                _ => literal.push(c),
+            }
+        }
+
+        flush_literal(&mut parts, &mut literal);
+        Ok(Word::new(parts))
+    }
+}
+
+/// Whether `text` is NAME= or NAME+=, after which a '(' starts an array.
+fn is_assignment_prefix(text: &str) -> bool {
+    match text.strip_suffix('=') {
+        Some(name) => is_valid_name(name.strip_suffix('+').unwrap_or(name)),
+        None => false,
+    }
+}
+
+/// Splits [index]=value into its parts; anything else is a plain value.
+fn array_element(word: Word) -> ArrayElement {
+    if let Some((index, rest)) = split_subscript(&word.parts) {
+        if let Some(WordPart::Literal(text)) = rest.first() {
+            if let Some(value) = text.strip_prefix('=') {
+                let mut parts: Vec<WordPart> = Vec::new();
+                if !value.is_empty() {
+                    parts.push(WordPart::Literal(value.to_owned()));
+                }
+                parts.extend_from_slice(&rest[1..]);
+                return ArrayElement { index: Some(index), value: Word::new(parts) };
+            }
+        }
+    }
+    ArrayElement { index: None, value: word }
+}
+
+fn is_special_parameter(c: char) -> bool {
+    matches!(c, '?' | '#' | '@' | '*' | '$' | '!' | '-')
+}
+
+fn fallback_op(op: char, colon: bool, word: Word) -> ParameterOp {
+    match op {
+        '-' => ParameterOp::Default { colon, word },
+        '=' => ParameterOp::Assign { colon, word },
+        '?' => ParameterOp::Error { colon, word },
+        _ => ParameterOp::Alternative { colon, word },
+    }
+}
+
+fn flush_literal(parts: &mut Vec<WordPart>, literal: &mut String) {
+    if !literal.is_empty() {
+        parts.push(WordPart::Literal(std::mem::take(literal)));
+    }
+}
+
+fn push_quoted(parts: &mut Vec<WordPart>, c: char) {
+    if let Some(WordPart::Quoted(text)) = parts.last_mut() {
+        text.push(c);
+    } else {
+        parts.push(WordPart::Quoted(c.to_string()));
+    }
+}
+
+fn flush_quoted(parts: &mut Vec<WordPart>, text: &mut String) {
+    if !text.is_empty() {
+        parts.push(WordPart::Quoted(std::mem::take(text)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        let mut lexer: Lexer = Lexer::new(input);
+        let mut tokens: Vec<Token> = Vec::new();
+        loop {
+            match lexer.next_token().expect("lexing failed") {
+                Token::Eof => return tokens,
+                token => tokens.push(token),
+            }
+        }
+    }
+
+    fn word(parts: Vec<WordPart>) -> Token {
+        Token::Word(Word::new(parts))
+    }
+
+    fn literal(text: &str) -> WordPart {
+        WordPart::Literal(text.to_owned())
+    }
+
+    fn quoted(text: &str) -> WordPart {
+        WordPart::Quoted(text.to_owned())
+    }
+
+    #[test]
+    fn operators_inside_quotes_stay_in_the_word() {
+        assert_eq!(
+            tokens("echo \"a|b\" c\\;d e\\>f"),
+            vec![
+                word(vec![literal("echo")]),
+                word(vec![WordPart::DoubleQuoted(vec![quoted("a|b")])]),
+                word(vec![literal("c"), quoted(";"), literal("d")]),
+                word(vec![literal("e"), quoted(">"), literal("f")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_quotes_are_literal() {
+        assert_eq!(tokens("'$x \\ \"y\"'"), vec![word(vec![quoted("$x \\ \"y\"")])]);
+        assert_eq!(tokens("a'b'\"c\""), vec![word(vec![literal("a"), quoted("b"), WordPart::DoubleQuoted(vec![quoted("c")])])]);
+        assert_eq!(tokens("''"), vec![word(vec![quoted("")])]);
+    }
+
+    #[test]
+    fn double_quotes_keep_expansions() {
+        assert_eq!(
+            tokens("\"$x and \\$y\""),
+            vec![word(vec![WordPart::DoubleQuoted(vec![WordPart::Parameter("x".to_owned()), quoted(" and $y")])])]
+        );
+        assert_eq!(tokens("a\"b\"$c"), vec![word(vec![literal("a"), WordPart::DoubleQuoted(vec![quoted("b")]), WordPart::Parameter("c".to_owned())])]);
+    }
+
+    #[test]
+    fn backslashes_in_double_quotes_only_escape_special_characters() {
+        assert_eq!(tokens("\"a\\$b\\\\c\\\"d\\ne\""), vec![word(vec![WordPart::DoubleQuoted(vec![quoted("a$b\\c\"d\\ne")])])]);
+        assert_eq!(tokens("\"a\\\nb\""), vec![word(vec![WordPart::DoubleQuoted(vec![quoted("ab")])])]);
+        assert_eq!(tokens("a\\nb"), vec![word(vec![literal("a"), quoted("n"), literal("b")])]);
+    }
+
+    #[test]
+    fn ansi_c_quotes_decode_escapes() {
+        assert_eq!(tokens("$'a\\tb'"), vec![word(vec![quoted("a\tb")])]);
+        assert_eq!(tokens("$'\\x41\\101\\u00e9'"), vec![word(vec![quoted("AAé")])]);
+        assert_eq!(tokens("$'it\\'s'"), vec![word(vec![quoted("it's")])]);
+        assert_eq!(tokens("$'a\\\\b\\q'"), vec![word(vec![quoted("a\\b\\q")])]);
+        assert_eq!(tokens("$'\\cA\\e'"), vec![word(vec![quoted("\x01\x1b")])]);
+    }
+
+    #[test]
+    fn unterminated_quotes_are_incomplete() {
+        assert!(matches!(Lexer::new("'abc").next_token(), Err(ParseError::Incomplete(_))));
+        assert!(matches!(Lexer::new("\"abc").next_token(), Err(ParseError::Incomplete(_))));
+        assert!(matches!(Lexer::new("$'abc").next_token(), Err(ParseError::Incomplete(_))));
+    }
+
+    #[test]
+    fn backslash_newline_joins_lines() {
+        assert_eq!(tokens("echo a\\\nb"), vec![word(vec![literal("echo")]), word(vec![literal("ab")])]);
+        assert_eq!(tokens("echo \\\n  b"), vec![word(vec![literal("echo")]), word(vec![literal("b")])]);
+    }
+
+    #[test]
+    fn comments_run_to_the_end_of_the_line() {
+        assert_eq!(tokens("echo a # b c\nd"), vec![word(vec![literal("echo")]), word(vec![literal("a")]), Token::Newline, word(vec![literal("d")])]);
+        assert_eq!(tokens("echo a#b"), vec![word(vec![literal("echo")]), word(vec![literal("a#b")])]);
+    }
+
+    #[test]
+    fn control_operators() {
+        assert_eq!(
+            tokens("a;b&&c||d|e\nf"),
+            vec![
+                word(vec![literal("a")]),
+                Token::Op(Operator::Semicolon),
+                word(vec![literal("b")]),
+                Token::Op(Operator::AndIf),
+                word(vec![literal("c")]),
+                Token::Op(Operator::OrIf),
+                word(vec![literal("d")]),
+                Token::Op(Operator::Pipe),
+                word(vec![literal("e")]),
+                Token::Newline,
+                word(vec![literal("f")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn redirections_and_io_numbers() {
+        assert_eq!(
+            tokens("2>&1 &>f >>g <<<w 3<x <>y >|z 12y"),
+            vec![
+                Token::IoNumber(2),
+                Token::Op(Operator::Redirect(RedirectKind::DupOutput)),
+                word(vec![literal("1")]),
+                Token::Op(Operator::Redirect(RedirectKind::OutputBoth)),
+                word(vec![literal("f")]),
+                Token::Op(Operator::Redirect(RedirectKind::Append)),
+                word(vec![literal("g")]),
+                Token::Op(Operator::Redirect(RedirectKind::HereString)),
+                word(vec![literal("w")]),
+                Token::IoNumber(3),
+                Token::Op(Operator::Redirect(RedirectKind::Input)),
+                word(vec![literal("x")]),
+                Token::Op(Operator::Redirect(RedirectKind::ReadWrite)),
+                word(vec![literal("y")]),
+                Token::Op(Operator::Redirect(RedirectKind::Clobber)),
+                word(vec![literal("z")]),
+                word(vec![literal("12y")]),
+            ]
+        );
+        assert_eq!(tokens("<< <<-"), vec![Token::Op(Operator::HereDoc(false)), Token::Op(Operator::HereDoc(true))]);
+    }
+
+    #[test]
+    fn here_doc_bodies_are_read_from_the_next_line() {
+        let mut lexer: Lexer = Lexer::new("cat <<EOF; echo hi\nline one\n  EOF\nEOF\necho after");
+        assert_eq!(lexer.next_token().unwrap(), word(vec![literal("cat")]));
+        assert_eq!(lexer.next_token().unwrap(), Token::Op(Operator::HereDoc(false)));
+        assert_eq!(lexer.next_token().unwrap(), word(vec![literal("EOF")]));
+        assert_eq!(lexer.read_here_doc("EOF", false).unwrap(), "line one\n  EOF\n");
+        // The rest of the line comes first, then lexing resumes after the body.
+        assert_eq!(lexer.next_token().unwrap(), Token::Op(Operator::Semicolon));
+        assert_eq!(lexer.next_token().unwrap(), word(vec![literal("echo")]));
+        assert_eq!(lexer.next_token().unwrap(), word(vec![literal("hi")]));
+        assert_eq!(lexer.next_token().unwrap(), Token::Newline);
+        assert_eq!(lexer.next_token().unwrap(), word(vec![literal("echo")]));
+        assert_eq!(lexer.next_token().unwrap(), word(vec![literal("after")]));
+    }
+
+    #[test]
+    fn here_doc_strips_tabs_with_dash() {
+        let mut lexer: Lexer = Lexer::new("cat <<-END\n\tone\n\t\ttwo\n\tEND\n");
+        for _ in 0..3 {
+            lexer.next_token().unwrap();
+        }
+        assert_eq!(lexer.read_here_doc("END", true).unwrap(), "one\ntwo\n");
+    }
+
+    #[test]
+    fn here_doc_without_body_is_incomplete() {
+        let mut lexer: Lexer = Lexer::new("cat <<EOF\nno end");
+        for _ in 0..3 {
+            lexer.next_token().unwrap();
+        }
+        assert!(matches!(lexer.read_here_doc("EOF", false), Err(ParseError::Incomplete(_))));
+        assert!(matches!(Lexer::new("cat <<EOF").read_here_doc("EOF", false), Err(ParseError::Incomplete(_))));
+    }
+
+    #[test]
+    fn unquoted_here_doc_bodies_expand() {
+        let mut lexer: Lexer = Lexer::new("a \"$x\" \\$y \\\nb\n");
+        assert_eq!(lexer.read_here_doc_body().unwrap(), vec![quoted("a \""), WordPart::Parameter("x".to_owned()), quoted("\" $y b\n")]);
+    }
+
+    #[test]
+    fn command_substitutions_nest() {
+        match &tokens("x$(echo \"$(date)\" ')')`pwd`")[0] {
+            Token::Word(word) => {
+                assert_eq!(word.parts.len(), 3);
+                assert!(matches!(&word.parts[1], WordPart::CommandSubstitution(list) if list.to_string() == "echo \"$(date)\" ')'"));
+                assert!(matches!(&word.parts[2], WordPart::CommandSubstitution(list) if list.to_string() == "pwd"));
+            }
+            token => panic!("expected a word, got {:?}", token),
+        }
+        assert!(matches!(Lexer::new("$(echo").next_token(), Err(ParseError::Incomplete(_))));
+        assert!(matches!(Lexer::new("`echo").next_token(), Err(ParseError::Incomplete(_))));
+    }
+
+    #[test]
+    fn parameter_expansions_are_parts_of_words() {
+        match &tokens("x${y:-z}${#y}$((1+2))$(echo hi)")[0] {
+            Token::Word(word) => {
+                assert_eq!(word.parts.len(), 5);
+                assert_eq!(word.parts[0], literal("x"));
+                assert!(matches!(&word.parts[1], WordPart::ParameterExpansion(expansion) if expansion.name == "y"));
+                assert!(matches!(&word.parts[2], WordPart::ParameterExpansion(expansion) if expansion.op == ParameterOp::Length));
+                assert_eq!(word.parts[3], WordPart::Arithmetic(vec![literal("1+2")]));
+                assert!(matches!(&word.parts[4], WordPart::CommandSubstitution(list) if list.to_string() == "echo hi"));
+            }
+            token => panic!("expected a word, got {:?}", token),
+        }
+        assert!(matches!(Lexer::new("${x").next_token(), Err(ParseError::Incomplete(_))));
+        assert!(Lexer::new("${x!}").next_token().is_err());
+    }
+}
@@ -1,6 +1,6 @@
 // MAJOR TODOs: export for env vars; wildcards/regex (*, ?, []); prompt customization with PS1, PS2, etc.
-// HUGE TODOs: Scripting (if, elif, else, fi, for, while, funcs, variables); [[ expression ]] and (( expression ))
-// TODO: Quotes and escaping?; $(command)/command substitution; process substitution; -c for commands; file descriptor stuff; pushd/popd/dirs
+// HUGE TODOs: Scripting (if, elif, else, fi, for, while, funcs, variables)
+// TODO: Quotes and escaping?; process substitution; -c for commands; file descriptor stuff; pushd/popd/dirs
 
 // Current TODO focus: prompt customization
 // Most recent update: export/ normal environment variable assignment differentiation
@@ -14,26 +14,32 @@ pub mod helpers;
 pub mod command_parsing;
 pub mod jobs;
 pub mod script;
+pub mod ast;
+pub mod lexer;
+pub mod parser;
+pub mod arithmetic;
+pub mod pattern;
+pub mod conditional;
 
 #[cfg(feature = "use-libc")]
 extern crate libc;
 
-use crate::editing::{CommandHinter, AutoCompleter, LineHighlighter};
+use crate::editing::{CommandHinter, AutoCompleter, InputValidator, LineHighlighter};
 use crate::config::Config;
 use crate::evaluation::eval;
-use crate::globals::ShellState;
+use crate::globals::{Attributes, ControlFlow, ShellState, Value, Variable};
 use crate::helpers::{get_history_file_path, read_prompt_from_file};
 use arguments::parse_arg_vec;
 use dirs::home_dir;
-use globals::get_nash_dir;
-use crate::jobs::{JobControl, RECEIVED_SIGTSTP, setup_signal_handlers};
+use globals::{get_nash_dir, import_environment};
+use crate::jobs::{lock_jobs, setup_signal_handlers, watch_children, JobControl, AT_PROMPT, RECEIVED_SIGHUP, RECEIVED_SIGTSTP};
 use crate::script::ScriptExecutor;
 use rustyline::{
     completion::{Completer, Pair},
     error::ReadlineError,
     highlight::Highlighter,
     hint::Hinter,
-    validate::{MatchingBracketValidator, ValidationContext, ValidationResult, Validator},
+    validate::{ValidationContext, ValidationResult, Validator},
     Context, Editor,
 };
 use rustyline_derive::Helper;
@@ -45,13 +51,12 @@ use std::{
     io::{BufReader, BufRead, Write},
     path::{Path, PathBuf},
     process::{exit, Command},
-    sync::atomic::Ordering
+    sync::{atomic::Ordering, Arc, Mutex, MutexGuard}
 };
 use tokio::runtime::Runtime;
 use whoami::fallible;
 
 fn main() {
-    env::set_var("0", "nash"); // In case something goes wrong.
     let runtime: Runtime = Runtime::new().unwrap();
     let mut conf: Config = match Config::new()
     {
@@ -69,10 +74,22 @@ fn main() {
         username: fallible::username().unwrap(),
         history_limit: 500,
         ps1_prompt: read_prompt_from_file(),
-        local_vars: HashMap::new()
+        variables: import_environment(),
+        last_status: 0,
+        last_background_pid: None,
+        builtin_status: None,
+        subshell: false,
+        positional: Vec::new(),
+        loop_depth: 0,
+        control_flow: None,
+        functions: HashMap::new(),
+        call_stack: Vec::new()
     };
     
+    set_shell_name(&mut state, &env::args().next().unwrap_or("nash".to_owned()));
+
     let job_control: &mut JobControl = &mut JobControl::new();
+    job_control.notify = conf.is_enabled("notify");
     eval(&mut state, &mut conf, job_control, "SHELL=/usr/bin/nash".to_owned(), true);
     eval(&mut state, &mut conf, job_control, format!("NASH={}", get_nash_dir().display()).to_owned(), true);
     runtime.block_on(async {
@@ -91,7 +108,7 @@ struct ShellHelper {
     completer: AutoCompleter,
     highlighter: LineHighlighter,
     hinter: CommandHinter,
-    validator: MatchingBracketValidator,
+    validator: InputValidator,
 }
 
 impl Completer for ShellHelper {
@@ -152,36 +169,66 @@ fn repl(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl)
     }
 
     let helper: ShellHelper = ShellHelper {
-        completer: AutoCompleter::new(PathBuf::from(env::current_dir().unwrap_or(PathBuf::from("/")))),
+        completer: AutoCompleter::new(env::current_dir().unwrap_or(PathBuf::from("/"))),
         highlighter: LineHighlighter::new(),
         hinter: CommandHinter::new(rl.history()),
-        validator: MatchingBracketValidator::new(),
+        validator: InputValidator,
     };
     rl.set_helper(Some(helper));
 
-    // Setup signal handlers
-    if let Err(e) = setup_signal_handlers() {
-        eprintln!("Warning: Failed to setup signal handlers: {}", e);
+    // Reading commands from a pipe or file is not interactive: no job control, and signals keep their default actions
+    let interactive: bool = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    if interactive {
+        if let Err(e) = setup_signal_handlers() {
+            eprintln!("Warning: Failed to setup signal handlers: {}", e);
+        }
+    } else {
+        job_control.monitor = false;
+    }
+
+    // The job table is shared with the thread that announces jobs at once under set -b
+    let jobs: Arc<Mutex<JobControl>> = Arc::new(Mutex::new(std::mem::take(job_control)));
+    if interactive {
+        if let Err(e) = watch_children(Arc::clone(&jobs), unsafe { libc::pthread_self() }) {
+            eprintln!("Warning: Failed to watch background jobs: {}", e);
+        }
     }
 
     loop {
+        if RECEIVED_SIGHUP.load(Ordering::SeqCst) {
+            break;
+        }
         let prompt: String = format!("[{}@{} {}]> ", state.username, state.hostname, env::current_dir().unwrap_or(PathBuf::from("/")).display());
         
-        // Check if we received SIGTSTP
-        if RECEIVED_SIGTSTP.load(Ordering::SeqCst) {
-            RECEIVED_SIGTSTP.store(false, Ordering::SeqCst);
-            
-            // If there's a current foreground job, stop it
-            if let Some(current_job) = job_control.get_current_job() {
-                if let Err(e) = job_control.stop_job(current_job.pid) {
-                    eprintln!("Failed to stop job: {}", e);
+        {
+            let mut job_control: MutexGuard<'_, JobControl> = lock_jobs(&jobs);
+
+            // Check if we received SIGTSTP
+            if RECEIVED_SIGTSTP.load(Ordering::SeqCst) {
+                RECEIVED_SIGTSTP.store(false, Ordering::SeqCst);
+                
+                // If there's a current foreground job, stop it
+                if let Some(pgid) = job_control.get_current_job().map(|job| job.pgid) {
+                    if let Err(e) = job_control.stop_job(pgid) {
+                        eprintln!("Failed to stop job: {}", e);
+                    }
+                    continue;
                 }
-                continue;
+            }
+
+            // Finished and stopped background jobs are announced before the prompt
+            job_control.reap_jobs();
+            for notice in job_control.take_notices() {
+                println!("{}", notice);
             }
         }
 
-        match rl.readline(&prompt) {
+        AT_PROMPT.store(true, Ordering::SeqCst);
+        let input: Result<String, ReadlineError> = rl.readline(&prompt);
+        AT_PROMPT.store(false, Ordering::SeqCst);
+        match input {
             Ok(line) => {
+                let mut job_control: MutexGuard<'_, JobControl> = lock_jobs(&jobs);
                 if rl.history().len() >= state.history_limit {
                     let temp_file: PathBuf = history_file.with_extension("temp");
                     {
@@ -201,16 +248,20 @@ fn repl(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl)
                 rl.save_history(&history_file).unwrap();
                 
                 // Before evaluating, ensure we're in the foreground
-                unsafe {
-                    let shell_pgid = libc::getpgrp();
-                    if libc::tcsetpgrp(libc::STDIN_FILENO, shell_pgid) == -1 {
-                        eprintln!("Warning: Failed to take terminal control");
+                if job_control.monitor {
+                    unsafe {
+                        let shell_pgid = libc::getpgrp();
+                        if libc::tcsetpgrp(libc::STDIN_FILENO, shell_pgid) == -1 {
+                            eprintln!("Warning: Failed to take terminal control");
+                        }
                     }
                 }
                 //println!("main Made it -2 (call eval)");
-                let result: String = eval(state, conf, job_control, line, false);
-                print(result);
-                //println!("main Made it -1 (printed result)");
+                eval(state, conf, &mut job_control, line, false);
+                // A fatal error only ends the line it happened in
+                if let Some(ControlFlow::Exit(_)) = state.control_flow {
+                    state.control_flow = None;
+                }
                 
                 //println!("main Made it -0.5 (reached if)");
                 if RECEIVED_SIGTSTP.load(Ordering::SeqCst) {
@@ -220,12 +271,10 @@ fn repl(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl)
                     println!("\nJob stopped");
                     //println!("main Made it 2");
                     if let Some(job) = job_control.get_current_job() {
-                        println!("[{}] Stopped    {}", job.pid, job.command);
+                        println!("[{}] Stopped    {}", job.id, job.command);
                     }
                 }
                 //println!("main Made it 3 (passed if)");
-                job_control.cleanup_jobs();   
-                //println!("main Made it 4 (passed cleanup)");             
                 
                 // Get the history entries before getting the mutable helper
                 let history_entries: Vec<String> = rl
@@ -245,12 +294,14 @@ fn repl(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl)
                 }                                               
             }
             Err(ReadlineError::Interrupted) => {
-                // TODO: actually handle as SIGINT
+                // Ctrl-C throws away the line being typed
                 println!("^C");
-                break;
+                continue;
             }
             Err(ReadlineError::Eof) => {
-                println!("^D");
+                if !RECEIVED_SIGHUP.load(Ordering::SeqCst) {
+                    println!("^D");
+                }
                 break;
             }
             Err(err) => {
@@ -259,7 +310,16 @@ fn repl(state: &mut ShellState, conf: &mut Config, job_control: &mut JobControl)
             }
         }
     }
+
+    let hung_up: bool = RECEIVED_SIGHUP.load(Ordering::SeqCst);
+    if hung_up || conf.is_enabled("huponexit") {
+        lock_jobs(&jobs).hang_up_jobs();
+    }
+    let _ = rl.save_history(&history_file);
     conf.save_rules();
+    if hung_up {
+        exit(128 + libc::SIGHUP);
+    }
 }
 
 async fn handle_nash_args(conf: &mut Config, job_control: &mut JobControl, args: Vec<String>) {
@@ -270,20 +330,34 @@ async fn handle_nash_args(conf: &mut Config, job_control: &mut JobControl, args:
 
     // Check if arg 1 is a path, if so, run it as a series of commands (like bash's .sh running impl) (scripting)
     // PLACEHOLDER, WILL NOT WORK LIKE INTENDED!!
-    if main_args.len() > 0 && Path::new(&main_args[0]).exists() {
+    if !main_args.is_empty() && Path::new(&main_args[0]).exists() {
         let script_path: &Path = Path::new(&main_args[0]);
         let mut state: ShellState = ShellState {
             hostname: fallible::hostname().unwrap(),
             username: fallible::username().unwrap(),
             history_limit: 500,
             ps1_prompt: read_prompt_from_file(),
-            local_vars: HashMap::new()
+            variables: import_environment(),
+            last_status: 0,
+            last_background_pid: None,
+            builtin_status: None,
+            subshell: false,
+            // Everything after the script path becomes $1, $2, ...
+            positional: args.iter().skip(1).skip_while(|arg| **arg != main_args[0]).skip(1).cloned().collect(),
+            loop_depth: 0,
+            control_flow: None,
+            functions: HashMap::new(),
+            call_stack: Vec::new()
         };
+        set_shell_name(&mut state, &main_args[0]);
         let mut executor: ScriptExecutor<'_> = ScriptExecutor::new(&mut state, conf, job_control);
-        if let Err(e) = executor.execute_script(script_path) {
-            eprintln!("Failed to execute script: {}", e);
+        match executor.execute_script(script_path) {
+            Ok(status) => exit(status),
+            Err(e) => {
+                eprintln!("Failed to execute script: {}", e);
+                exit(1);
+            }
         }
-        return;
     }
 
     // Handle other command-line arguments
@@ -299,11 +373,11 @@ async fn handle_nash_args(conf: &mut Config, job_control: &mut JobControl, args:
             {
                 if force
                 {
-                    println!("Update command exited with status: {}", Command::new("nbm").args(["--update", "--force"]).status().unwrap_or(Default::default()).code().unwrap_or(1));
+                    println!("Update command exited with status: {}", Command::new("nbm").args(["--update", "--force"]).status().unwrap_or_default().code().unwrap_or(1));
                 }
                 else
                 {
-                    println!("Update command exited with status: {}", Command::new("nbm").args(["--update"]).status().unwrap_or(Default::default()).code().unwrap_or(1));
+                    println!("Update command exited with status: {}", Command::new("nbm").args(["--update"]).status().unwrap_or_default().code().unwrap_or(1));
                 }
             }
             else
@@ -323,6 +397,11 @@ async fn handle_nash_args(conf: &mut Config, job_control: &mut JobControl, args:
     print_usage();
 }
 
+/// Sets $0, which is a shell variable of its own rather than one passed on to commands.
+fn set_shell_name(state: &mut ShellState, name: &str) {
+    state.variables.insert("0".to_owned(), Variable { value: Some(Value::Scalar(name.to_owned())), attributes: Attributes::default() });
+}
+
 fn print_usage() {
     println!("Usage: nash [OPTION] [SCRIPT]");
     println!("Options:");
@@ -331,10 +410,3 @@ fn print_usage() {
     println!("  -f, --force   Force the operation (if used with --update, update even if no new version is detected)");
     println!("  <script>      Run the specified script file (heavily discouraged, unstable)");
 }
-
-// What was I on when I made this function?? It's so useless...
-fn print(result: String) {
-    if !result.is_empty() {
-        println!("{}", result);
-    }
-}
@@ -0,0 +1,915 @@
+use crate::ast::*;
+use crate::conditional::{is_binary_operator, is_unary_operator};
+use crate::lexer::{Lexer, Operator, Token};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The input is malformed.
+    Syntax(String),
+    /// The input ended before the construct was closed (open quote, trailing pipe, ...).
+    Incomplete(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Syntax(msg) => write!(f, "Syntax error: {}", msg),
+            ParseError::Incomplete(msg) => write!(f, "Syntax error: {}", msg),
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<List, ParseError> {
+    let mut parser: Parser = Parser::new(input);
+    parser.parse_program()
+}
+
+pub struct Parser {
+    lexer: Lexer,
+    peeked: Option<Token>,
+}
+
+impl Parser {
+    pub fn new(input: &str) -> Self {
+        Parser {
+            lexer: Lexer::new(input),
+            peeked: None,
+        }
+    }
+
+    fn peek(&mut self) -> Result<&Token, ParseError> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lexer.next_token()?);
+        }
+        Ok(self.peeked.as_ref().unwrap())
+    }
+
+    fn next(&mut self) -> Result<Token, ParseError> {
+        match self.peeked.take() {
+            Some(token) => Ok(token),
+            None => self.lexer.next_token(),
+        }
+    }
+
+    fn skip_newlines(&mut self) -> Result<(), ParseError> {
+        while *self.peek()? == Token::Newline {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    pub fn parse_program(&mut self) -> Result<List, ParseError> {
+        self.parse_list_until(&|_| false)
+    }
+
+    /// Parses the inside of $( ... ) up to and including the closing parenthesis.
+    pub fn parse_command_substitution(&mut self) -> Result<List, ParseError> {
+        let list: List = self.parse_list_until(&|token| *token == Token::Op(Operator::RParen))?;
+        match self.next()? {
+            Token::Op(Operator::RParen) => Ok(list),
+            Token::Eof => Err(ParseError::Incomplete("unterminated command substitution".to_owned())),
+            token => Err(unexpected(&token)),
+        }
+    }
+
+    /// How far into the input the parser has consumed, not counting a peeked token.
+    pub fn position(&self) -> usize {
+        self.lexer.position()
+    }
+
+    /// Parses and-or lists separated by ';' or newlines until end of input or a token accepted by `is_end`,
+    /// which is left unconsumed.
+    fn parse_list_until(&mut self, is_end: &dyn Fn(&Token) -> bool) -> Result<List, ParseError> {
+        let mut list: List = List::default();
+
+        loop {
+            self.skip_newlines()?;
+            let token: &Token = self.peek()?;
+            if *token == Token::Eof || is_end(token) {
+                break;
+            }
+            if let Token::Op(op) = token {
+                if !matches!(op, Operator::Redirect(_) | Operator::HereDoc(_) | Operator::LParen) {
+                    return Err(ParseError::Syntax(format!("unexpected token '{}'", op)));
+                }
+            }
+            if is_keyword(token, CLOSING_KEYWORDS) {
+                let token: Token = self.next()?;
+                return Err(unexpected(&token));
+            }
+            list.items.push(self.parse_and_or()?);
+
+            match self.peek()? {
+                Token::Op(Operator::Semicolon) | Token::Newline => {
+                    self.next()?;
+                }
+                Token::Op(Operator::Ampersand) => {
+                    self.next()?;
+                    if let Some(item) = list.items.last_mut() {
+                        item.background = true;
+                    }
+                    // "a &;" is allowed, as printed lists end that way before a closing "; }" or "; done".
+                    if *self.peek()? == Token::Op(Operator::Semicolon) {
+                        self.next()?;
+                    }
+                }
+                Token::Eof => break,
+                token if is_end(token) => break,
+                _ => {
+                    let token: Token = self.next()?;
+                    return Err(unexpected(&token));
+                }
+            }
+        }
+
+        Ok(list)
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOr, ParseError> {
+        let mut and_or: AndOr = AndOr {
+            first: self.parse_pipeline()?,
+            rest: Vec::new(),
+            background: false,
+        };
+
+        loop {
+            let op: AndOrOp = match self.peek()? {
+                Token::Op(Operator::AndIf) => AndOrOp::And,
+                Token::Op(Operator::OrIf) => AndOrOp::Or,
+                _ => break,
+            };
+            self.next()?;
+            self.skip_newlines()?;
+            if *self.peek()? == Token::Eof {
+                return Err(ParseError::Incomplete(format!("expected a command after '{}'", if op == AndOrOp::And { "&&" } else { "||" })));
+            }
+            and_or.rest.push((op, self.parse_pipeline()?));
+        }
+
+        Ok(and_or)
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let mut pipeline: Pipeline = Pipeline::default();
+
+        if let Token::Word(word) = self.peek()? {
+            if word.as_literal() == Some("!") {
+                self.next()?;
+                pipeline.negated = true;
+            }
+        }
+
+        pipeline.commands.push(self.parse_command()?);
+
+        while *self.peek()? == Token::Op(Operator::Pipe) {
+            self.next()?;
+            self.skip_newlines()?;
+            if *self.peek()? == Token::Eof {
+                return Err(ParseError::Incomplete("expected a command after '|'".to_owned()));
+            }
+            pipeline.commands.push(self.parse_command()?);
+        }
+
+        Ok(pipeline)
+    }
+
+    fn parse_command(&mut self) -> Result<Command, ParseError> {
+        let keyword: Option<String> = match self.peek()? {
+            Token::Word(word) => word.as_literal().map(str::to_owned),
+            _ => None,
+        };
+        if keyword.as_deref() == Some("function") {
+            return self.parse_function();
+        }
+        if let Some(name) = keyword.as_deref().filter(|name| !is_reserved_word(name) && !name.contains('=')) {
+            // The word has been read but not consumed yet, so the lexer is just past it.
+            if self.lexer.read_function_parens() {
+                let name: String = name.to_owned();
+                self.next()?;
+                return self.parse_function_body(name);
+            }
+        }
+
+        let compound: Option<CompoundCommand> = match keyword.as_deref() {
+            Some("if") => Some(self.parse_if()?),
+            Some("while") | Some("until") => Some(self.parse_while()?),
+            Some("for") => Some(self.parse_for()?),
+            Some("case") => Some(self.parse_case()?),
+            Some("[[") => Some(self.parse_conditional()?),
+            Some("{") => Some(self.parse_brace_group()?),
+            // The lexer has only read the first '(' so far; a second one makes this (( ... )).
+            _ if *self.peek()? == Token::Op(Operator::LParen) => match self.lexer.read_arithmetic_command()? {
+                Some(expression) => {
+                    self.peeked = None;
+                    Some(CompoundCommand::Arithmetic(expression))
+                }
+                None => Some(self.parse_subshell()?),
+            },
+            _ => None,
+        };
+
+        match compound {
+            Some(compound) => {
+                let redirects: Vec<Redirect> = self.parse_trailing_redirects()?;
+                Ok(Command::Compound(compound, redirects))
+            }
+            None => Ok(Command::Simple(self.parse_simple_command()?)),
+        }
+    }
+
+    /// function name [()] compound-command
+    fn parse_function(&mut self) -> Result<Command, ParseError> {
+        self.expect_keyword("function")?;
+        let name: String = match self.peek()? {
+            Token::Word(word) => match word.as_literal() {
+                Some(name) if !is_reserved_word(name) => name.to_owned(),
+                _ => return Err(ParseError::Syntax(format!("'{}': not a valid function name", word))),
+            },
+            Token::Eof => return Err(ParseError::Incomplete("expected a function name".to_owned())),
+            _ => {
+                let token: Token = self.next()?;
+                return Err(unexpected(&token));
+            }
+        };
+        self.lexer.read_function_parens();
+        self.next()?;
+        self.parse_function_body(name)
+    }
+
+    /// The body of a function definition, after its name and parentheses.
+    fn parse_function_body(&mut self, name: String) -> Result<Command, ParseError> {
+        self.skip_newlines()?;
+        if *self.peek()? == Token::Eof {
+            return Err(ParseError::Incomplete("expected a function body".to_owned()));
+        }
+        let body: Command = match self.parse_command()? {
+            body @ Command::Compound(..) => body,
+            _ => return Err(ParseError::Syntax(format!("expected a compound command after '{}()'", name))),
+        };
+        Ok(Command::FunctionDefinition(FunctionDefinition { name, body: Box::new(body) }))
+    }
+
+    /// { ...; }
+    fn parse_brace_group(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_keyword("{")?;
+        let list: List = self.parse_compound_list(&["}"])?;
+        self.expect_keyword("}")?;
+        Ok(CompoundCommand::BraceGroup(list))
+    }
+
+    /// ( ... )
+    fn parse_subshell(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.next()?;
+        let list: List = self.parse_list_until(&|token| *token == Token::Op(Operator::RParen))?;
+        match self.next()? {
+            Token::Op(Operator::RParen) if !list.items.is_empty() => Ok(CompoundCommand::Subshell(list)),
+            Token::Eof => Err(ParseError::Incomplete("expected ')'".to_owned())),
+            token => Err(unexpected(&token)),
+        }
+    }
+
+    /// Consumes the reserved word `keyword`, which must come next.
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        match self.next()? {
+            token if is_keyword(&token, &[keyword]) => Ok(()),
+            Token::Eof => Err(ParseError::Incomplete(format!("expected '{}'", keyword))),
+            Token::Word(word) => Err(ParseError::Syntax(format!("expected '{}' before '{}'", keyword, word))),
+            token => Err(unexpected(&token)),
+        }
+    }
+
+    /// A non-empty list of commands ending before one of the reserved words in `ends`.
+    fn parse_compound_list(&mut self, ends: &[&str]) -> Result<List, ParseError> {
+        let list: List = self.parse_list_until(&|token| is_keyword(token, ends))?;
+        if list.items.is_empty() {
+            let token: Token = self.next()?;
+            return Err(unexpected(&token));
+        }
+        Ok(list)
+    }
+
+    fn parse_if(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_keyword("if")?;
+        let mut branches: Vec<(List, List)> = Vec::new();
+        let mut else_branch: Option<List> = None;
+
+        loop {
+            let condition: List = self.parse_compound_list(&["then"])?;
+            self.expect_keyword("then")?;
+            let body: List = self.parse_compound_list(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+
+            match self.next()? {
+                token if is_keyword(&token, &["elif"]) => continue,
+                token if is_keyword(&token, &["else"]) => {
+                    else_branch = Some(self.parse_compound_list(&["fi"])?);
+                    self.expect_keyword("fi")?;
+                    break;
+                }
+                token if is_keyword(&token, &["fi"]) => break,
+                Token::Eof => return Err(ParseError::Incomplete("expected 'fi'".to_owned())),
+                token => return Err(unexpected(&token)),
+            }
+        }
+
+        Ok(CompoundCommand::If { branches, else_branch })
+    }
+
+    fn parse_while(&mut self) -> Result<CompoundCommand, ParseError> {
+        let until: bool = match self.next()? {
+            Token::Word(word) => word.as_literal() == Some("until"),
+            _ => false,
+        };
+        let condition: List = self.parse_compound_list(&["do"])?;
+        let body: List = self.parse_do_group()?;
+        Ok(CompoundCommand::While { until, condition, body })
+    }
+
+    /// do ...; done
+    fn parse_do_group(&mut self) -> Result<List, ParseError> {
+        self.expect_keyword("do")?;
+        let body: List = self.parse_compound_list(&["done"])?;
+        self.expect_keyword("done")?;
+        Ok(body)
+    }
+
+    fn parse_for(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_keyword("for")?;
+
+        if *self.peek()? == Token::Op(Operator::LParen) {
+            let expression: Vec<WordPart> = match self.lexer.read_arithmetic_command()? {
+                Some(expression) => expression,
+                None => return Err(ParseError::Syntax("expected '((' after 'for'".to_owned())),
+            };
+            self.peeked = None;
+            let mut sections: Vec<Vec<WordPart>> = split_arithmetic(expression);
+            if sections.len() != 3 {
+                return Err(ParseError::Syntax("expected three expressions in 'for ((...))'".to_owned()));
+            }
+            if *self.peek()? == Token::Op(Operator::Semicolon) {
+                self.next()?;
+            }
+            self.skip_newlines()?;
+            let body: List = self.parse_do_group()?;
+            let update: Vec<WordPart> = sections.pop().unwrap_or_default();
+            let condition: Vec<WordPart> = sections.pop().unwrap_or_default();
+            let init: Vec<WordPart> = sections.pop().unwrap_or_default();
+            return Ok(CompoundCommand::ArithmeticFor { init, condition, update, body });
+        }
+
+        let name: String = match self.next()? {
+            Token::Word(word) => match word.as_literal() {
+                Some(name) if is_valid_name(name) => name.to_owned(),
+                _ => return Err(ParseError::Syntax(format!("'{}': not a valid identifier", word))),
+            },
+            token => return Err(unexpected(&token)),
+        };
+
+        self.skip_newlines()?;
+        let mut words: Option<Vec<Word>> = None;
+        if is_keyword(self.peek()?, &["in"]) {
+            self.next()?;
+            let mut list: Vec<Word> = Vec::new();
+            while let Token::Word(_) = self.peek()? {
+                if let Token::Word(word) = self.next()? {
+                    list.push(word);
+                }
+            }
+            words = Some(list);
+            match self.next()? {
+                Token::Op(Operator::Semicolon) | Token::Newline => {}
+                token => return Err(unexpected(&token)),
+            }
+        } else if *self.peek()? == Token::Op(Operator::Semicolon) {
+            self.next()?;
+        }
+        self.skip_newlines()?;
+
+        let body: List = self.parse_do_group()?;
+        Ok(CompoundCommand::For { name, words, body })
+    }
+
+    fn parse_case(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_keyword("case")?;
+        let word: Word = match self.next()? {
+            Token::Word(word) => word,
+            token => return Err(unexpected(&token)),
+        };
+        self.skip_newlines()?;
+        self.expect_keyword("in")?;
+        self.skip_newlines()?;
+
+        let mut items: Vec<CaseItem> = Vec::new();
+        loop {
+            if is_keyword(self.peek()?, &["esac"]) {
+                self.next()?;
+                break;
+            }
+            if *self.peek()? == Token::Op(Operator::LParen) {
+                self.next()?;
+            }
+
+            let mut patterns: Vec<Word> = Vec::new();
+            loop {
+                match self.next()? {
+                    Token::Word(pattern) => patterns.push(pattern),
+                    token => return Err(unexpected(&token)),
+                }
+                match self.next()? {
+                    Token::Op(Operator::Pipe) => continue,
+                    Token::Op(Operator::RParen) => break,
+                    token => return Err(unexpected(&token)),
+                }
+            }
+
+            let body: List = self.parse_list_until(&|token| {
+                matches!(token, Token::Op(Operator::DoubleSemicolon) | Token::Op(Operator::SemicolonAnd) | Token::Op(Operator::DoubleSemicolonAnd))
+                    || is_keyword(token, &["esac"])
+            })?;
+            let terminator: CaseTerminator = match self.peek()? {
+                Token::Op(Operator::SemicolonAnd) => CaseTerminator::FallThrough,
+                Token::Op(Operator::DoubleSemicolonAnd) => CaseTerminator::Continue,
+                _ => CaseTerminator::Break,
+            };
+            if matches!(self.peek()?, Token::Op(_)) {
+                self.next()?;
+            }
+            self.skip_newlines()?;
+            items.push(CaseItem { patterns, body, terminator });
+        }
+
+        Ok(CompoundCommand::Case { word, items })
+    }
+
+    /// [[ expression ]], where || binds looser than && and ! applies to the term after it.
+    fn parse_conditional(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_keyword("[[")?;
+        let expression: CondExpr = self.parse_cond_or()?;
+        self.skip_newlines()?;
+        match self.next()? {
+            token if is_keyword(&token, &["]]"]) => Ok(CompoundCommand::Conditional(expression)),
+            Token::Eof => Err(ParseError::Incomplete("expected ']]'".to_owned())),
+            token => Err(unexpected(&token)),
+        }
+    }
+
+    fn parse_cond_or(&mut self) -> Result<CondExpr, ParseError> {
+        let mut expression: CondExpr = self.parse_cond_and()?;
+        while *self.peek()? == Token::Op(Operator::OrIf) {
+            self.next()?;
+            let right: CondExpr = self.parse_cond_and()?;
+            expression = CondExpr::Or(Box::new(expression), Box::new(right));
+        }
+        Ok(expression)
+    }
+
+    fn parse_cond_and(&mut self) -> Result<CondExpr, ParseError> {
+        let mut expression: CondExpr = self.parse_cond_term()?;
+        while *self.peek()? == Token::Op(Operator::AndIf) {
+            self.next()?;
+            let right: CondExpr = self.parse_cond_term()?;
+            expression = CondExpr::And(Box::new(expression), Box::new(right));
+        }
+        Ok(expression)
+    }
+
+    fn parse_cond_term(&mut self) -> Result<CondExpr, ParseError> {
+        self.skip_newlines()?;
+        let word: Word = match self.next()? {
+            Token::Op(Operator::LParen) => {
+                let expression: CondExpr = self.parse_cond_or()?;
+                self.skip_newlines()?;
+                return match self.next()? {
+                    Token::Op(Operator::RParen) => Ok(CondExpr::Group(Box::new(expression))),
+                    Token::Eof => Err(ParseError::Incomplete("expected ')' in conditional expression".to_owned())),
+                    token => Err(unexpected(&token)),
+                };
+            }
+            token if is_keyword(&token, &["!"]) => return Ok(CondExpr::Not(Box::new(self.parse_cond_term()?))),
+            token if is_keyword(&token, &["]]"]) => return Err(ParseError::Syntax("expected an expression before ']]'".to_owned())),
+            Token::Word(word) => word,
+            Token::Eof => return Err(ParseError::Incomplete("expected ']]'".to_owned())),
+            token => return Err(unexpected(&token)),
+        };
+
+        if let Some(op) = word.as_literal().filter(|op| is_unary_operator(op)) {
+            if matches!(self.peek()?, Token::Word(_)) && !is_keyword(self.peek()?, &["]]"]) {
+                let op: String = op.to_owned();
+                let operand: Word = self.parse_cond_operand()?;
+                return Ok(CondExpr::Unary(op, operand));
+            }
+        }
+
+        // < and > are comparisons here, not redirections.
+        let op: Option<String> = match self.peek()? {
+            Token::Word(op) => op.as_literal().filter(|op| is_binary_operator(op)).map(str::to_owned),
+            Token::Op(Operator::Redirect(RedirectKind::Input)) => Some("<".to_owned()),
+            Token::Op(Operator::Redirect(RedirectKind::Output)) => Some(">".to_owned()),
+            _ => None,
+        };
+        let op: String = match op {
+            Some(op) => op,
+            None => return Ok(CondExpr::Word(word)),
+        };
+        self.next()?;
+        let right: Word = if op == "=~" {
+            // Nothing is peeked now, so the lexer is just past the operator.
+            match self.lexer.read_regex_word()? {
+                right if right.parts.is_empty() => return Err(ParseError::Syntax("expected a regular expression after '=~'".to_owned())),
+                right => right,
+            }
+        } else {
+            self.parse_cond_operand()?
+        };
+        Ok(CondExpr::Binary(word, op, right))
+    }
+
+    /// The word after an operator in [[ ]].
+    fn parse_cond_operand(&mut self) -> Result<Word, ParseError> {
+        match self.next()? {
+            token if is_keyword(&token, &["]]"]) => Err(ParseError::Syntax("expected an operand before ']]'".to_owned())),
+            Token::Word(word) => Ok(word),
+            Token::Eof => Err(ParseError::Incomplete("expected ']]'".to_owned())),
+            token => Err(unexpected(&token)),
+        }
+    }
+
+    /// Redirections written after a compound command.
+    fn parse_trailing_redirects(&mut self) -> Result<Vec<Redirect>, ParseError> {
+        let mut redirects: Vec<Redirect> = Vec::new();
+        while matches!(self.peek()?, Token::IoNumber(_) | Token::Op(Operator::Redirect(_)) | Token::Op(Operator::HereDoc(_))) {
+            redirects.push(self.parse_redirect()?);
+        }
+        Ok(redirects)
+    }
+
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand, ParseError> {
+        let mut command: SimpleCommand = SimpleCommand::default();
+
+        loop {
+            match self.peek()? {
+                Token::Word(_) => {
+                    let word: Word = match self.next()? {
+                        Token::Word(word) => word,
+                        _ => unreachable!(),
+                    };
+                    // Assignments are only recognised before the command name.
+                    if command.words.is_empty() {
+                        if let Some(assignment) = as_assignment(&word) {
+                            command.assignments.push(assignment);
+                            continue;
+                        }
+                    }
+                    // Only declaration builtins take name=(...) arguments.
+                    let declaration: bool = command.words.first().and_then(Word::as_literal).is_some_and(|name| DECLARATION_BUILTINS.contains(&name));
+                    if !declaration && word.parts.iter().any(|part| matches!(part, WordPart::Array(_))) {
+                        return Err(ParseError::Syntax("unexpected token '('".to_owned()));
+                    }
+                    command.words.push(word);
+                }
+                Token::IoNumber(_) | Token::Op(Operator::Redirect(_)) | Token::Op(Operator::HereDoc(_)) => {
+                    command.redirects.push(self.parse_redirect()?);
+                }
+                _ => break,
+            }
+        }
+
+        if command.words.is_empty() && command.assignments.is_empty() && command.redirects.is_empty() {
+            let token: Token = self.next()?;
+            return Err(unexpected(&token));
+        }
+        Ok(command)
+    }
+
+    fn parse_redirect(&mut self) -> Result<Redirect, ParseError> {
+        let fd: Option<i32> = match self.peek()? {
+            Token::IoNumber(fd) => {
+                let fd: i32 = *fd;
+                self.next()?;
+                Some(fd)
+            }
+            _ => None,
+        };
+
+        let mut kind: RedirectKind = match self.next()? {
+            Token::Op(Operator::Redirect(kind)) => kind,
+            Token::Op(Operator::HereDoc(strip_tabs)) => return self.parse_here_doc(fd.unwrap_or(0), strip_tabs),
+            token => return Err(unexpected(&token)),
+        };
+
+        let target: Word = match self.next()? {
+            Token::Word(word) => word,
+            Token::Eof => return Err(ParseError::Syntax("expected a file name after redirection".to_owned())),
+            token => return Err(unexpected(&token)),
+        };
+
+        // Without a descriptor in front, >&file means the same as &>file.
+        if kind == RedirectKind::DupOutput && fd.is_none() {
+            if let Some(text) = target.as_literal() {
+                if text != "-" && text.parse::<i32>().is_err() {
+                    kind = RedirectKind::OutputBoth;
+                }
+            }
+        }
+
+        Ok(Redirect {
+            fd: fd.unwrap_or(kind.default_fd()),
+            kind,
+            target,
+        })
+    }
+
+    /// Reads the delimiter after << and then the body from the following lines.
+    /// The body is expanded like a double-quoted string unless any part of the delimiter was quoted.
+    fn parse_here_doc(&mut self, fd: i32, strip_tabs: bool) -> Result<Redirect, ParseError> {
+        let delimiter: Word = match self.next()? {
+            Token::Word(word) => word,
+            Token::Eof => return Err(ParseError::Syntax("expected a delimiter after '<<'".to_owned())),
+            token => return Err(unexpected(&token)),
+        };
+        let delimiter_text: String = delimiter.parts.iter().map(unquoted_text).collect();
+        let body: String = self.lexer.read_here_doc(&delimiter_text, strip_tabs)?;
+
+        let target: Word = if delimiter.is_quoted() {
+            Word::new(vec![WordPart::Quoted(body)])
+        } else {
+            Word::new(vec![WordPart::DoubleQuoted(Lexer::new(&body).read_here_doc_body()?)])
+        };
+
+        Ok(Redirect {
+            fd,
+            kind: RedirectKind::HereDoc,
+            target,
+        })
+    }
+}
+
+/// Builtins whose NAME=value arguments are assignments: not split or globbed, and possibly arrays.
+pub const DECLARATION_BUILTINS: &[&str] = &["local", "declare", "typeset", "export", "readonly"];
+
+/// Reserved words that close a compound command and so can't start a command.
+const CLOSING_KEYWORDS: &[&str] = &["then", "elif", "else", "fi", "do", "done", "esac", "}"];
+
+fn is_reserved_word(word: &str) -> bool {
+    CLOSING_KEYWORDS.contains(&word) || matches!(word, "if" | "while" | "until" | "for" | "case" | "in" | "function" | "{" | "!" | "[[" | "]]")
+}
+
+/// Whether the token is an unquoted word equal to one of the reserved words.
+fn is_keyword(token: &Token, keywords: &[&str]) -> bool {
+    match token {
+        Token::Word(word) => word.as_literal().is_some_and(|text| keywords.contains(&text)),
+        _ => false,
+    }
+}
+
+/// Splits the expression of `for ((init; condition; update))` on its semicolons.
+fn split_arithmetic(expression: Vec<WordPart>) -> Vec<Vec<WordPart>> {
+    let mut sections: Vec<Vec<WordPart>> = vec![Vec::new()];
+    for part in expression {
+        match part {
+            WordPart::Literal(text) => {
+                for (i, piece) in text.split(';').enumerate() {
+                    if i > 0 {
+                        sections.push(Vec::new());
+                    }
+                    if !piece.is_empty() {
+                        sections.last_mut().unwrap().push(WordPart::Literal(piece.to_owned()));
+                    }
+                }
+            }
+            part => sections.last_mut().unwrap().push(part),
+        }
+    }
+    sections
+}
+
+/// Splits NAME=value, NAME+=value or NAME[index]=value into an assignment if the word starts with a valid, unquoted name.
+pub fn as_assignment(word: &Word) -> Option<Assignment> {
+    let first: &str = match word.parts.first() {
+        Some(WordPart::Literal(text)) => text,
+        _ => return None,
+    };
+    let name_end: usize = first.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(first.len());
+    let name: &str = &first[..name_end];
+    if !is_valid_name(name) {
+        return None;
+    }
+
+    let mut rest: Vec<WordPart> = Vec::new();
+    if name_end < first.len() {
+        rest.push(WordPart::Literal(first[name_end..].to_owned()));
+    }
+    rest.extend_from_slice(&word.parts[1..]);
+    let index: Option<Word> = match split_subscript(&rest) {
+        Some((index, after)) => {
+            rest = after;
+            Some(index)
+        }
+        None => None,
+    };
+
+    let operator: &str = match rest.first() {
+        Some(WordPart::Literal(text)) => text,
+        _ => return None,
+    };
+    let (append, value): (bool, &str) = match operator.strip_prefix("+=") {
+        Some(value) => (true, value),
+        None => (false, operator.strip_prefix('=')?),
+    };
+    let mut parts: Vec<WordPart> = Vec::new();
+    if !value.is_empty() {
+        parts.push(WordPart::Literal(value.to_owned()));
+    }
+    parts.extend_from_slice(&rest[1..]);
+
+    Some(Assignment {
+        name: name.to_owned(),
+        index,
+        append,
+        value: Word::new(parts),
+    })
+}
+
+/// Splits parts starting with "[index]" at the ']' closing the subscript, which may contain expansions.
+/// Returns None unless they start with an unquoted '[' that is closed.
+pub fn split_subscript(parts: &[WordPart]) -> Option<(Word, Vec<WordPart>)> {
+    let first: &str = match parts.first() {
+        Some(WordPart::Literal(text)) => text.strip_prefix('[')?,
+        _ => return None,
+    };
+    let mut rest: Vec<WordPart> = Vec::new();
+    if !first.is_empty() {
+        rest.push(WordPart::Literal(first.to_owned()));
+    }
+    rest.extend_from_slice(&parts[1..]);
+
+    let mut index: Vec<WordPart> = Vec::new();
+    for (i, part) in rest.iter().enumerate() {
+        if let WordPart::Literal(text) = part {
+            if let Some(end) = text.find(']') {
+                if end > 0 {
+                    index.push(WordPart::Literal(text[..end].to_owned()));
+                }
+                let mut after: Vec<WordPart> = Vec::new();
+                if end + 1 < text.len() {
+                    after.push(WordPart::Literal(text[end + 1..].to_owned()));
+                }
+                after.extend_from_slice(&rest[i + 1..]);
+                return Some((Word::new(index), after));
+            }
+        }
+        index.push(part.clone());
+    }
+    None
+}
+
+/// The text of a word part with its quoting removed and nothing expanded.
+fn unquoted_text(part: &WordPart) -> String {
+    match part {
+        WordPart::Literal(text) | WordPart::Quoted(text) => text.clone(),
+        WordPart::DoubleQuoted(parts) => parts.iter().map(unquoted_text).collect(),
+        WordPart::Parameter(name) => format!("${}", name),
+        WordPart::CommandSubstitution(list) => format!("$({})", list),
+        WordPart::Arithmetic(_) | WordPart::ParameterExpansion(_) | WordPart::Array(_) => part.to_string(),
+    }
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars: std::str::Chars<'_> = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => chars.all(|c| c.is_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+pub fn unexpected(token: &Token) -> ParseError {
+    match token {
+        Token::Eof => ParseError::Incomplete("unexpected end of input".to_owned()),
+        Token::Newline => ParseError::Syntax("unexpected newline".to_owned()),
+        Token::Op(op) => ParseError::Syntax(format!("unexpected token '{}'", op)),
+        Token::IoNumber(n) => ParseError::Syntax(format!("unexpected token '{}'", n)),
+        Token::Word(_) => ParseError::Syntax("unexpected word".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simple(list: &List, index: usize) -> &SimpleCommand {
+        match &list.items[index].first.commands[0] {
+            Command::Simple(simple) => simple,
+            command => panic!("expected a simple command, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn quoted_operators_do_not_split_commands() {
+        let list: List = parse("echo \"a|b\" \\; x\\>y '&&'").unwrap();
+        assert_eq!(list.items.len(), 1);
+        let command: &SimpleCommand = simple(&list, 0);
+        assert_eq!(command.words.len(), 5);
+        assert!(command.redirects.is_empty());
+    }
+
+    #[test]
+    fn lists_and_pipelines() {
+        let list: List = parse("a | b | c; d\n\ne\n").unwrap();
+        assert_eq!(list.items.len(), 3);
+        assert_eq!(list.items[0].first.commands.len(), 3);
+        assert_eq!(parse("a |\nb").unwrap().items[0].first.commands.len(), 2);
+    }
+
+    #[test]
+    fn and_or_lists() {
+        let list: List = parse("a | b && ! c || d").unwrap();
+        let and_or: &AndOr = &list.items[0];
+        assert_eq!(and_or.first.commands.len(), 2);
+        assert_eq!(and_or.rest.len(), 2);
+        assert_eq!(and_or.rest[0].0, AndOrOp::And);
+        assert!(and_or.rest[0].1.negated);
+        assert_eq!(and_or.rest[1].0, AndOrOp::Or);
+        assert_eq!(and_or.to_string(), "a | b && ! c || d");
+        assert_eq!(parse("a &&\n\nb").unwrap().items[0].to_string(), "a && b");
+    }
+
+    #[test]
+    fn assignments_and_redirections() {
+        let list: List = parse("x=1 y=\"a b\" cmd arg 2>&1 >>out <in").unwrap();
+        let command: &SimpleCommand = simple(&list, 0);
+        assert_eq!(command.assignments.len(), 2);
+        assert_eq!(command.assignments[0].name, "x");
+        assert_eq!(command.assignments[1].value, Word::new(vec![WordPart::DoubleQuoted(vec![WordPart::Quoted("a b".to_owned())])]));
+        assert_eq!(command.words.len(), 2);
+        let redirects: Vec<(i32, RedirectKind)> = command.redirects.iter().map(|redirect| (redirect.fd, redirect.kind)).collect();
+        assert_eq!(redirects, [(2, RedirectKind::DupOutput), (1, RedirectKind::Append), (0, RedirectKind::Input)]);
+        // Only words in front of the command name are assignments, and only with a valid name.
+        assert!(simple(&parse("cmd x=1").unwrap(), 0).assignments.is_empty());
+        assert!(simple(&parse("1x=1").unwrap(), 0).assignments.is_empty());
+    }
+
+    #[test]
+    fn compound_commands() {
+        assert_eq!(parse("(( i++ )) >out").unwrap().to_string(), "(( i++ )) >out");
+        assert_eq!(parse("if a; then b; elif c; then d; else e; fi").unwrap().to_string(), "if a; then b; elif c; then d; else e; fi");
+        assert_eq!(parse("for i in 1 2\ndo\n echo $i\ndone").unwrap().to_string(), "for i in 1 2; do echo ${i}; done");
+        assert_eq!(parse("for ((i=0;i<2;i++)); do a; done").unwrap().to_string(), "for ((i=0; i<2; i++)); do a; done");
+        assert_eq!(parse("while a; do b; done").unwrap().to_string(), "while a; do b; done");
+        assert_eq!(parse("until a; do b; done > out").unwrap().to_string(), "until a; do b; done >out");
+        assert_eq!(parse("case $x in a|b) echo ab;; *) echo other;& esac").unwrap().to_string(), "case ${x} in a | b) echo ab ;; *) echo other ;& esac");
+        // Reserved words are only recognised as the first word of a command.
+        assert_eq!(parse("echo if then fi").unwrap().to_string(), "echo if then fi");
+    }
+
+    #[test]
+    fn function_definitions() {
+        assert_eq!(parse("f() { echo hi; }").unwrap().to_string(), "f () { echo hi; }");
+        assert_eq!(parse("function f {\n a\n b\n}").unwrap().to_string(), "f () { a; b; }");
+        assert_eq!(parse("function f() { a; } > out").unwrap().to_string(), "f () { a; } >out");
+        assert!(matches!(parse("f() {"), Err(ParseError::Incomplete(_))));
+    }
+
+    #[test]
+    fn subshells_and_brace_groups() {
+        assert_eq!(parse("(a; b) | c").unwrap().to_string(), "( a; b ) | c");
+        assert_eq!(parse("{ a; b; } >out").unwrap().to_string(), "{ a; b; } >out");
+        assert_eq!(parse("( (a) )").unwrap().to_string(), "( ( a ) )");
+        assert_eq!(parse("f() ( a )").unwrap().to_string(), "f () ( a )");
+        assert!(matches!(parse("(a"), Err(ParseError::Incomplete(_))));
+        assert!(matches!(parse("{ a;"), Err(ParseError::Incomplete(_))));
+        assert!(matches!(parse("()"), Err(ParseError::Syntax(_))));
+        assert!(matches!(parse("f() a"), Err(ParseError::Syntax(_))));
+    }
+
+    #[test]
+    fn background_lists() {
+        let list: List = parse("a && b & c; d &").unwrap();
+        assert_eq!(list.items.iter().map(|item| item.background).collect::<Vec<bool>>(), vec![true, false, true]);
+        assert_eq!(list.to_string(), "a && b & c; d &");
+        assert_eq!(parse("{ a &; }").unwrap().to_string(), "{ a &; }");
+        assert!(matches!(parse("& a"), Err(ParseError::Syntax(_))));
+    }
+
+    #[test]
+    fn unfinished_input_is_incomplete() {
+        for input in ["a |", "a &&", "a ||", "echo 'abc", "echo \"abc", "cat <<EOF\nbody", "if a; then", "while a; do b", "case x in", "for i in a"] {
+            assert!(matches!(parse(input), Err(ParseError::Incomplete(_))), "{:?} should be incomplete", input);
+        }
+    }
+
+    #[test]
+    fn malformed_input_is_a_syntax_error() {
+        for input in ["; a", "a ;;", "| a", "&& a", "a && && b", "a >", "a > ;", "fi", "if a; fi", "for 1 in a; do b; done", "done"] {
+            assert!(matches!(parse(input), Err(ParseError::Syntax(_))), "{:?} should be a syntax error", input);
+        }
+    }
+
+    #[test]
+    fn here_doc_lines_continue_unless_the_delimiter_is_quoted() {
+        let shown = |input: &str| -> Vec<String> { parse(input).unwrap().items.iter().map(|item| item.to_string()).collect() };
+        assert_eq!(shown("cat <<EOF\none \\\ntwo $x\nEOF\n"), ["cat <<<\"one two ${x}\n\""]);
+        assert_eq!(shown("cat <<'EOF'\none \\\ntwo $x\nEOF\n"), ["cat <<<'one \\\ntwo $x\n'"]);
+        assert_eq!(shown("cat <<-EOF; echo hi\n\tone\n\tEOF\necho after"), ["cat <<<\"one\n\"", "echo hi", "echo after"]);
+    }
+}
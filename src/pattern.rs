@@ -0,0 +1,487 @@
+// Shell pattern matching (*, ?, [...] and the extended ?(..), *(..), +(..), @(..), !(..) groups)
+// used by parameter expansion and pathname expansion.
+// A backslash makes the next character literal, which is how quoted parts of a pattern are passed in.
+
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+enum PatternItem {
+    Char(char),
+    /// ?
+    Any,
+    /// *
+    Star,
+    /// [...]
+    Class { negated: bool, members: Vec<ClassMember> },
+    /// One of ?(...), *(...), +(...), @(...) or !(...), keyed by its leading character.
+    Group { kind: char, alternatives: Vec<Vec<PatternItem>> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ClassMember {
+    Char(char),
+    Range(char, char),
+    /// [:alpha:] and friends
+    Named(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    items: Vec<PatternItem>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        Pattern { items: compile(&chars) }
+    }
+
+    /// Whether the pattern matches all of `text`.
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        match_items(&self.items, &text)
+    }
+
+    /// Whether the pattern contains anything other than literal characters.
+    pub fn has_wildcards(&self) -> bool {
+        self.items.iter().any(|item| !matches!(item, PatternItem::Char(_)))
+    }
+}
+
+fn compile(chars: &[char]) -> Vec<PatternItem> {
+    let mut items: Vec<PatternItem> = Vec::new();
+    let mut pos: usize = 0;
+
+    while pos < chars.len() {
+        let c: char = chars[pos];
+        pos += 1;
+        if matches!(c, '?' | '*' | '+' | '@' | '!') && chars.get(pos) == Some(&'(') {
+            if let Some((alternatives, end)) = parse_group(chars, pos + 1) {
+                items.push(PatternItem::Group { kind: c, alternatives });
+                pos = end;
+                continue;
+            }
+        }
+        match c {
+            '\\' if pos < chars.len() => {
+                items.push(PatternItem::Char(chars[pos]));
+                pos += 1;
+            }
+            '?' => items.push(PatternItem::Any),
+            // Consecutive stars match the same as one.
+            '*' if items.last() == Some(&PatternItem::Star) => {}
+            '*' => items.push(PatternItem::Star),
+            '[' => match parse_class(chars, pos) {
+                Some((item, end)) => {
+                    items.push(item);
+                    pos = end;
+                }
+                None => items.push(PatternItem::Char('[')),
+            },
+            _ => items.push(PatternItem::Char(c)),
+        }
+    }
+
+    items
+}
+
+/// Splits the inside of a group whose '(' is just before `start` on its top-level '|'s.
+/// Returns None when there is no closing ')', in which case the group is taken literally.
+fn parse_group(chars: &[char], start: usize) -> Option<(Vec<Vec<PatternItem>>, usize)> {
+    let mut alternatives: Vec<Vec<PatternItem>> = Vec::new();
+    let mut depth: usize = 0;
+    let mut alternative_start: usize = start;
+    let mut pos: usize = start;
+
+    loop {
+        match *chars.get(pos)? {
+            '\\' => pos += 1,
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            ')' => {
+                alternatives.push(compile(&chars[alternative_start..pos]));
+                return Some((alternatives, pos + 1));
+            }
+            '|' if depth == 0 => {
+                alternatives.push(compile(&chars[alternative_start..pos]));
+                alternative_start = pos + 1;
+            }
+            _ => {}
+        }
+        pos += 1;
+    }
+}
+
+/// Shorthand for matching a pattern once.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    Pattern::new(pattern).matches(text)
+}
+
+/// Escapes every character that is special in a pattern, so that the text only matches itself.
+pub fn escape(text: &str) -> String {
+    let mut escaped: String = String::new();
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\' | '(' | ')' | '|' | '!' | '@' | '+') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Parses a bracket expression whose '[' is just before `start`.
+/// Returns None when there is no closing ']', in which case the '[' is literal.
+fn parse_class(chars: &[char], start: usize) -> Option<(PatternItem, usize)> {
+    let mut pos: usize = start;
+    let negated: bool = matches!(chars.get(pos), Some('!') | Some('^'));
+    if negated {
+        pos += 1;
+    }
+
+    let mut members: Vec<ClassMember> = Vec::new();
+    let mut first: bool = true;
+    loop {
+        let c: char = *chars.get(pos)?;
+        // A ']' right at the start is a member, not the end.
+        if c == ']' && !first {
+            return Some((PatternItem::Class { negated, members }, pos + 1));
+        }
+        first = false;
+
+        if c == '[' && chars.get(pos + 1) == Some(&':') {
+            let rest: String = chars[pos + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                members.push(ClassMember::Named(rest[..end].to_owned()));
+                pos += 2 + rest[..end].chars().count() + 2;
+                continue;
+            }
+        }
+
+        let mut low: char = c;
+        pos += 1;
+        if low == '\\' {
+            low = *chars.get(pos)?;
+            pos += 1;
+        }
+        if chars.get(pos) == Some(&'-') && chars.get(pos + 1).is_some_and(|c| *c != ']') {
+            let mut high: char = chars[pos + 1];
+            pos += 2;
+            if high == '\\' {
+                high = *chars.get(pos)?;
+                pos += 1;
+            }
+            members.push(ClassMember::Range(low, high));
+        } else {
+            members.push(ClassMember::Char(low));
+        }
+    }
+}
+
+fn class_matches(negated: bool, members: &[ClassMember], c: char) -> bool {
+    let found: bool = members.iter().any(|member| match member {
+        ClassMember::Char(m) => *m == c,
+        ClassMember::Range(low, high) => *low <= c && c <= *high,
+        ClassMember::Named(name) => match name.as_str() {
+            "alnum" => c.is_alphanumeric(),
+            "alpha" => c.is_alphabetic(),
+            "blank" => c == ' ' || c == '\t',
+            "cntrl" => c.is_control(),
+            "digit" => c.is_ascii_digit(),
+            "graph" => c.is_ascii_graphic(),
+            "lower" => c.is_lowercase(),
+            "print" => c.is_ascii_graphic() || c == ' ',
+            "punct" => c.is_ascii_punctuation(),
+            "space" => c.is_whitespace(),
+            "upper" => c.is_uppercase(),
+            "xdigit" => c.is_ascii_hexdigit(),
+            _ => false,
+        },
+    });
+    found != negated
+}
+
+fn match_items(items: &[PatternItem], text: &[char]) -> bool {
+    match items.first() {
+        None => text.is_empty(),
+        Some(PatternItem::Star) => (0..=text.len()).any(|i| match_items(&items[1..], &text[i..])),
+        Some(PatternItem::Any) => !text.is_empty() && match_items(&items[1..], &text[1..]),
+        Some(PatternItem::Char(c)) => text.first() == Some(c) && match_items(&items[1..], &text[1..]),
+        Some(PatternItem::Class { negated, members }) => {
+            !text.is_empty() && class_matches(*negated, members, text[0]) && match_items(&items[1..], &text[1..])
+        }
+        Some(PatternItem::Group { kind, alternatives }) => (0..=text.len()).any(|i| {
+            group_matches(*kind, alternatives, &text[..i]) && match_items(&items[1..], &text[i..])
+        }),
+    }
+}
+
+fn group_matches(kind: char, alternatives: &[Vec<PatternItem>], text: &[char]) -> bool {
+    let any: bool = alternatives.iter().any(|alternative| match_items(alternative, text));
+    match kind {
+        '@' => any,
+        '?' => text.is_empty() || any,
+        '!' => !any,
+        '+' => repeats(alternatives, text),
+        _ => text.is_empty() || repeats(alternatives, text),
+    }
+}
+
+/// Whether `text` is one or more consecutive matches of the alternatives.
+fn repeats(alternatives: &[Vec<PatternItem>], text: &[char]) -> bool {
+    !text.is_empty()
+        && (1..=text.len()).any(|i| {
+            alternatives.iter().any(|alternative| match_items(alternative, &text[..i])) && (i == text.len() || repeats(alternatives, &text[i..]))
+        })
+}
+
+/// Whether a word needs pathname expansion. Escaped characters don't count.
+pub fn has_glob_chars(pattern: &str) -> bool {
+    Pattern::new(pattern).has_wildcards()
+}
+
+/// Expands a pattern into the sorted list of existing paths it matches.
+/// Names starting with '.' are only matched by a pattern component that starts with '.', unless `dotglob` is set.
+/// With `globstar`, a "**" component matches any number of directories.
+pub fn glob(pattern: &str, dotglob: bool, globstar: bool) -> Vec<String> {
+    let absolute: bool = pattern.starts_with('/');
+    let components: Vec<&str> = split_components(pattern.trim_start_matches('/'));
+    let mut paths: Vec<String> = vec![if absolute { "/".to_owned() } else { String::new() }];
+
+    for (i, component) in components.iter().enumerate() {
+        let last: bool = i + 1 == components.len();
+        let mut next: Vec<String> = Vec::new();
+
+        for path in &paths {
+            if component.is_empty() {
+                // A trailing or doubled '/' only keeps directories.
+                if Path::new(path).is_dir() {
+                    next.push(format!("{}/", path.trim_end_matches('/')));
+                }
+            } else if *component == "**" && globstar {
+                if !last {
+                    next.push(path.clone());
+                }
+                walk(path, dotglob, last, &mut next);
+            } else if !Pattern::new(component).has_wildcards() {
+                let joined: String = join(path, &unescape(component));
+                if fs::symlink_metadata(&joined).is_ok() {
+                    next.push(joined);
+                }
+            } else {
+                let pattern: Pattern = Pattern::new(component);
+                let show_hidden: bool = dotglob || component.starts_with('.') || component.starts_with("\\.");
+                for name in list_dir(path) {
+                    if name.starts_with('.') && !show_hidden {
+                        continue;
+                    }
+                    let joined: String = join(path, &name);
+                    if pattern.matches(&name) && (last || Path::new(&joined).is_dir()) {
+                        next.push(joined);
+                    }
+                }
+            }
+        }
+
+        paths = next;
+    }
+
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Splits a pattern on the '/'s that are not escaped.
+fn split_components(pattern: &str) -> Vec<&str> {
+    let mut components: Vec<&str> = Vec::new();
+    let mut start: usize = 0;
+    let mut escaped: bool = false;
+    for (i, c) in pattern.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '/' => {
+                components.push(&pattern[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    components.push(&pattern[start..]);
+    components
+}
+
+/// Adds every directory below `path` to `found`, and every other file too when `files` is set.
+fn walk(path: &str, dotglob: bool, files: bool, found: &mut Vec<String>) {
+    for name in list_dir(path) {
+        if name.starts_with('.') && !dotglob {
+            continue;
+        }
+        let joined: String = join(path, &name);
+        let is_dir: bool = fs::symlink_metadata(&joined).is_ok_and(|meta| meta.is_dir());
+        if is_dir || files {
+            found.push(joined.clone());
+        }
+        if is_dir {
+            walk(&joined, dotglob, files, found);
+        }
+    }
+}
+
+fn list_dir(path: &str) -> Vec<String> {
+    let dir: &str = if path.is_empty() { "." } else { path };
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.file_name().to_string_lossy().into_owned()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_owned()
+    } else if path.ends_with('/') {
+        format!("{}{}", path, name)
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+/// Removes the backslashes that quote characters in a pattern.
+pub fn unescape(pattern: &str) -> String {
+    let mut result: String = String::new();
+    let mut chars: std::str::Chars<'_> = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("a*c", "abbc"));
+        assert!(matches("a*c", "ac"));
+        assert!(!matches("a*c", "acb"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("main", "main.rs"));
+    }
+
+    #[test]
+    fn bracket_expressions() {
+        assert!(matches("[abc]", "b"));
+        assert!(!matches("[abc]", "d"));
+        assert!(matches("[a-c]x", "cx"));
+        assert!(matches("[!a-c]", "d"));
+        assert!(matches("[^a-c]", "d"));
+        assert!(!matches("[!a-c]", "a"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[[:digit:]][[:upper:]]", "1A"));
+        assert!(!matches("[[:alpha:]]", "1"));
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn escapes_are_literal() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("a\\?", "a?"));
+        assert!(!has_glob_chars("\\*\\?"));
+        assert!(has_glob_chars("a*"));
+        let text: &str = "a*b?[c]!(d)";
+        assert!(matches(&escape(text), text));
+        assert!(!matches(&escape(text), "aXb?[c]!(d)"));
+        assert_eq!(unescape(&escape(text)), text);
+    }
+
+    #[test]
+    fn extended_groups() {
+        assert!(matches("@(foo|bar).c", "bar.c"));
+        assert!(!matches("@(foo|bar).c", "foobar.c"));
+        assert!(matches("?(x)y", "y"));
+        assert!(matches("?(x)y", "xy"));
+        assert!(!matches("?(x)y", "xxy"));
+        assert!(matches("*(ab)c", "c"));
+        assert!(matches("*(ab)c", "ababc"));
+        assert!(!matches("+(ab)c", "c"));
+        assert!(matches("+(ab|x)c", "abxabc"));
+        assert!(matches("!(*.o)", "main.c"));
+        assert!(!matches("!(*.o)", "main.o"));
+        assert!(matches("a!(b)c", "axc"));
+        assert!(!matches("a!(b)c", "abc"));
+        assert!(matches("@(a|*(b))", "bbb"));
+        assert!(matches("@(a", "@(a"));
+    }
+
+    struct TempDir(String);
+
+    impl TempDir {
+        fn new(name: &str, files: &[&str]) -> Self {
+            let root: String = format!("{}/nash-{}-{}", std::env::temp_dir().display(), name, std::process::id());
+            let _ = fs::remove_dir_all(&root);
+            for file in files {
+                let path: String = format!("{}/{}", root, file);
+                if let Some(dir) = path.strip_suffix('/') {
+                    fs::create_dir_all(dir).unwrap();
+                } else {
+                    fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+                    fs::write(&path, "").unwrap();
+                }
+            }
+            TempDir(root)
+        }
+
+        fn glob(&self, pattern: &str, dotglob: bool, globstar: bool) -> Vec<String> {
+            let prefix: String = format!("{}/", self.0);
+            glob(&format!("{}{}", prefix, pattern), dotglob, globstar)
+                .iter()
+                .map(|path| path.strip_prefix(&prefix).unwrap().to_owned())
+                .collect()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn glob_skips_dotfiles_unless_asked() {
+        let dir: TempDir = TempDir::new("dotfiles", &["a.rs", "b.rs", ".hidden.rs", "c.txt"]);
+        assert_eq!(dir.glob("*.rs", false, false), ["a.rs", "b.rs"]);
+        assert_eq!(dir.glob(".*.rs", false, false), [".hidden.rs"]);
+        assert_eq!(dir.glob("*.rs", true, false), [".hidden.rs", "a.rs", "b.rs"]);
+        assert_eq!(dir.glob("!(*.rs)", false, false), ["c.txt"]);
+        assert!(dir.glob("*.md", false, false).is_empty());
+    }
+
+    #[test]
+    fn glob_directories() {
+        let dir: TempDir = TempDir::new("dirs", &["src/main.rs", "src/lib.rs", "docs/", "notes"]);
+        assert_eq!(dir.glob("*/", false, false), ["docs/", "src/"]);
+        assert_eq!(dir.glob("*/*.rs", false, false), ["src/lib.rs", "src/main.rs"]);
+        assert_eq!(dir.glob("src/main.rs", false, false), ["src/main.rs"]);
+    }
+
+    #[test]
+    fn globstar() {
+        let dir: TempDir = TempDir::new("globstar", &["top.rs", "a/mid.rs", "a/b/deep.rs", "a/b/deep.txt", "a/.hidden/x.rs"]);
+        assert_eq!(dir.glob("**/*.rs", false, true), ["a/b/deep.rs", "a/mid.rs", "top.rs"]);
+        assert_eq!(dir.glob("**", false, true), ["a", "a/b", "a/b/deep.rs", "a/b/deep.txt", "a/mid.rs", "top.rs"]);
+        // Like bash, "dir/**/" includes "dir/" itself.
+        assert_eq!(dir.glob("**/", false, true), ["", "a/", "a/b/"]);
+        assert_eq!(dir.glob("**/*.rs", true, true), ["a/.hidden/x.rs", "a/b/deep.rs", "a/mid.rs", "top.rs"]);
+        // Without globstar, ** is just a *.
+        assert_eq!(dir.glob("**/*.rs", false, false), ["a/mid.rs"]);
+    }
+}
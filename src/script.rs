@@ -2,8 +2,9 @@ use std::fs::File;
 use std::io::{BufReader, BufRead};
 use std::path::Path;
 use std::sync::atomic::Ordering;
-use libc;
 use crate::{eval, Config, ShellState, RECEIVED_SIGTSTP, JobControl};
+use crate::parser::{parse, ParseError};
+use crate::globals::ControlFlow;
 
 pub struct ScriptExecutor<'a> {
     state: &'a mut ShellState,
@@ -24,7 +25,31 @@ impl<'a> ScriptExecutor<'a> {
         }
     }
 
-    pub fn execute_script(&mut self, script_path: &Path) -> Result<(), std::io::Error> {
+    /// Runs every line of the script and returns the exit status of the last command.
+    fn run(&mut self, command: String) -> Result<i32, std::io::Error> {
+        if command.trim().is_empty() {
+            return Ok(self.state.last_status);
+        }
+
+        // Execute the command; its output has already been written
+        let status: i32 = eval(self.state, self.conf, self.job_control, command, true);
+
+        // Check for job control signals
+        if RECEIVED_SIGTSTP.load(Ordering::SeqCst) {
+            RECEIVED_SIGTSTP.store(false, Ordering::SeqCst);
+            if let Some(job) = self.job_control.get_current_job() {
+                println!("\n[{}] Stopped    {}", job.id, job.command);
+                self.job_control.stop_job(job.pgid)?;
+            }
+        }
+
+        // Clean up any completed jobs; a script doesn't announce them
+        self.job_control.reap_jobs();
+        self.job_control.cleanup_jobs();
+        Ok(status)
+    }
+
+    pub fn execute_script(&mut self, script_path: &Path) -> Result<i32, std::io::Error> {
         let file: File = File::open(script_path)?;
         let reader: BufReader<File> = BufReader::new(file);
         let mut lines: Vec<String> = reader.lines().collect::<Result<_, _>>()?;
@@ -35,57 +60,51 @@ impl<'a> ScriptExecutor<'a> {
                 lines.remove(0);
             }
         }
+
+        // A script runs without job control: its commands stay in its process group,
+        // so Ctrl-C and the like reach them and the script alike
+        self.job_control.monitor = false;
     
-        // Create a new process group for the script
-        unsafe {
-            let script_pgid: i32 = libc::getpid();
-            if libc::setpgid(0, script_pgid) == -1 {
-                eprintln!("Warning: Failed to create new process group for script");
-            }
-        }
-    
-        // Execute all commands in the script
+        // Execute all commands in the script. Lines are gathered until they form a complete
+        // command, so quotes and here-documents may span several lines.
+        let mut status: i32 = 0;
+        let mut pending: String = String::new();
         for line in lines {
-            let trimmed: &str = line.trim();
-            if !trimmed.is_empty() && !trimmed.starts_with('#') {
-                // Take terminal control before executing each command
-                unsafe {
-                    let script_pgid: i32 = libc::getpgrp();
-                    if libc::tcsetpgrp(libc::STDIN_FILENO, script_pgid) == -1 {
-                        eprintln!("Warning: Failed to take terminal control");
-                    }
-                }
-    
-                // Execute the command and handle output immediately
-                let output: String = eval(self.state, self.conf, self.job_control, line.to_string(), true).trim().to_string();
-                
-                // If there's any output, print it
-                if !output.is_empty() {
-                    println!("{}", output);
-                }
-    
-                // Check for job control signals
-                if RECEIVED_SIGTSTP.load(Ordering::SeqCst) {
-                    RECEIVED_SIGTSTP.store(false, Ordering::SeqCst);
-                    if let Some(job) = self.job_control.get_current_job() {
-                        println!("\n[{}] Stopped    {}", job.pid, job.command);
-                        self.job_control.stop_job(job.pid)?;
-                    }
-                }
-    
-                // Clean up any completed jobs
-                self.job_control.cleanup_jobs();
+            pending.push_str(&line);
+            pending.push('\n');
+            if let Err(ParseError::Incomplete(_)) = parse(&pending) {
+                continue;
             }
-        }
-    
-        // Return terminal control to the shell
-        unsafe {
-            let shell_pgid = libc::getpgrp();
-            if libc::tcsetpgrp(libc::STDIN_FILENO, shell_pgid) == -1 {
-                eprintln!("Warning: Failed to return terminal control to shell");
+            status = self.run(std::mem::take(&mut pending))?;
+            // A fatal expansion error ends the script
+            if let Some(ControlFlow::Exit(code)) = self.state.control_flow {
+                status = code;
+                break;
             }
         }
-    
-        Ok(())
+        if !pending.trim().is_empty() {
+            // Still incomplete at the end of the file; evaluating it reports the error.
+            status = self.run(pending)?;
+        }
+
+        Ok(status)
     }    
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_run_without_job_control() {
+        let path: std::path::PathBuf = std::env::temp_dir().join(format!("nash-test-{}-script", std::process::id()));
+        std::fs::write(&path, "#!/usr/bin/nash\nx=1\nif ((x)); then\n  y=2\nfi\n").unwrap();
+        let mut state: ShellState = ShellState::default();
+        let mut job_control: JobControl = JobControl::new();
+        let status: i32 = ScriptExecutor::new(&mut state, &mut Config::default(), &mut job_control).execute_script(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(status, 0);
+        assert_eq!(state.get_var("y").as_deref(), Some("2"));
+        assert!(!job_control.monitor);
+    }
+}